@@ -1,5 +1,7 @@
 ﻿using System;
 using System.Collections.Generic;
+using System.IO;
+using System.Linq;
 using UnityEngine;
 using UnityEngine.PlayerLoop;
 using MessagePack;
@@ -99,4 +101,35 @@ public class NodeReadFormat
     public Edge[] neighbours { get; set; }
     [Key(6)]
     public Edge[] bi_neighbours { get; set; }
-}
\ No newline at end of file
+}
+
+public static class GraphFileHeader
+{
+    private static readonly byte[] Magic = System.Text.Encoding.ASCII.GetBytes("OSMGRAPH");
+    public const uint SupportedVersion = 1;
+
+    public static void Skip(Stream stream)
+    {
+        /**
+            * Skips the header rust_osm writes before the MessagePack body (see graph_file.rs).
+            * Files written before the header existed are rewound and read as before.
+         */
+        var start = stream.Position;
+        var magic = new byte[Magic.Length];
+        if (stream.Read(magic, 0, magic.Length) != magic.Length || !magic.SequenceEqual(Magic))
+        {
+            stream.Position = start;
+            return;
+        }
+        using (var reader = new BinaryReader(stream, System.Text.Encoding.ASCII, true))
+        {
+            var version = reader.ReadUInt32();
+            if (version != SupportedVersion)
+            {
+                throw new InvalidDataException("Graph format version " + version + " is not supported, expected " + SupportedVersion);
+            }
+            var headerLength = reader.ReadUInt32();
+            stream.Seek(headerLength, SeekOrigin.Current);
+        }
+    }
+}
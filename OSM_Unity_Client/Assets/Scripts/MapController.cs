@@ -78,6 +78,7 @@ public class MapController : MonoBehaviour
         // Using stream and async deserialization
         using (var stream = File.OpenRead(mapFile))
         {
+            GraphFileHeader.Skip(stream);
             var deserialized = await MessagePack.MessagePackSerializer.DeserializeAsync<GraphReadFormat>(stream);
 
             var n = deserialized.nodes.Length;
@@ -115,6 +116,7 @@ public class MapController : MonoBehaviour
         // Using stream and async deserialization
         using (var stream = File.OpenRead(mapFile))
         {
+            GraphFileHeader.Skip(stream);
             var deserialized = MessagePack.MessagePackSerializer.Deserialize<GraphReadFormat>(stream);
 
             var n = deserialized.nodes.Length;
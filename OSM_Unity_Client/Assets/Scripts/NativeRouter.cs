@@ -0,0 +1,84 @@
+﻿using System;
+using System.Collections;
+using System.Runtime.InteropServices;
+
+/*
+    Runs the searches in the Rust library instead of in C#, through the C API in
+    rust_osm/include/rust_osm.h. Build it with `cargo build --release` in rust_osm and copy
+    target/release/librust_osm.so (rust_osm.dll on Windows, librust_osm.dylib on macOS) into
+    Assets/Plugins. The library loads its own copy of the graph file, with the same node ids.
+*/
+public class NativeRouter : IPathfindingAlgorithm, IDisposable
+{
+    public const int Dijkstra = 0;
+    public const int AStar = 1;
+    public const int Landmarks = 2;
+
+    [StructLayout(LayoutKind.Sequential)]
+    private struct OsmRoute
+    {
+        public IntPtr nodes;
+        public UIntPtr node_count;
+        public IntPtr coords;
+        public UIntPtr coord_count;
+        public float distance;
+        public UIntPtr visited;
+    }
+
+    [DllImport("rust_osm")] private static extern IntPtr osm_graph_load([MarshalAs(UnmanagedType.LPUTF8Str)] string path);
+    [DllImport("rust_osm")] private static extern void osm_graph_free(IntPtr graph);
+    [DllImport("rust_osm")] private static extern long osm_nearest_node(IntPtr graph, double lat, double lon);
+    [DllImport("rust_osm")] private static extern IntPtr osm_route_between_nodes(IntPtr graph, long start, long end, int algorithm);
+    [DllImport("rust_osm")] private static extern void osm_route_free(IntPtr route);
+    [DllImport("rust_osm")] private static extern IntPtr osm_last_error();
+
+    private Graph graph;
+    private IntPtr nativeGraph;
+    public int algorithm;
+
+    public NativeRouter(Graph graph, string path, int algorithm = Dijkstra)
+    {
+        this.graph = graph;
+        this.algorithm = algorithm;
+        nativeGraph = osm_graph_load(path);
+        if (nativeGraph == IntPtr.Zero) throw new Exception(LastError());
+    }
+
+    private static string LastError()
+    {
+        return Marshal.PtrToStringUTF8(osm_last_error());
+    }
+
+    public long NearestNode(double lat, double lon)
+    {
+        return osm_nearest_node(nativeGraph, lat, lon);
+    }
+
+    public PathResult FindShortestPath(long start, long end)
+    {
+        var stopwatch = System.Diagnostics.Stopwatch.StartNew();
+        var routePtr = osm_route_between_nodes(nativeGraph, start, end, algorithm);
+        stopwatch.Stop();
+        if (routePtr == IntPtr.Zero) return null;
+
+        var route = Marshal.PtrToStructure<OsmRoute>(routePtr);
+        var path = new long[(int)route.node_count];
+        Marshal.Copy(route.nodes, path, 0, path.Length);
+        osm_route_free(routePtr);
+        return new PathResult(start, end, route.distance, stopwatch.ElapsedMilliseconds, (int)route.visited, path);
+    }
+
+    // The native search cannot be drawn while it runs, so only the result is shown
+    public IEnumerator FindShortestPathWithVisual(long start, long end, int drawspeed)
+    {
+        var result = FindShortestPath(start, end);
+        if (result != null) result.DisplayAndDrawPath(graph);
+        yield break;
+    }
+
+    public void Dispose()
+    {
+        osm_graph_free(nativeGraph);
+        nativeGraph = IntPtr.Zero;
+    }
+}
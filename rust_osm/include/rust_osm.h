@@ -0,0 +1,64 @@
+/*
+   C API of rust_osm, implemented in src/ffi.rs.
+   Build the shared library with `cargo build --release`, which writes librust_osm.so
+   (rust_osm.dll on Windows, librust_osm.dylib on macOS) to target/release.
+
+   A graph is loaded once with osm_graph_load and queried until osm_graph_free. Routes are
+   allocated by the library and must be given back with osm_route_free. Functions returning
+   NULL or -1 leave a message for osm_last_error. A loaded graph is never modified, so it can
+   be queried from several threads at once.
+*/
+#ifndef RUST_OSM_H
+#define RUST_OSM_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define OSM_DIJKSTRA 0
+#define OSM_ASTAR 1
+#define OSM_LANDMARKS 2
+
+typedef struct OsmGraph OsmGraph;
+
+typedef struct OsmRoute {
+    /* Node ids along the route */
+    int64_t *nodes;
+    size_t node_count;
+    /* coord_count (lat, lon) pairs. One per node for routes between nodes, and the snapped
+       start and end around them for routes between coordinates */
+    double *coords;
+    size_t coord_count;
+    /* Meters */
+    float distance;
+    /* Nodes settled by the search */
+    size_t visited;
+} OsmRoute;
+
+/* Loads a .graph file, compressed or not. Returns NULL if it cannot be read */
+OsmGraph *osm_graph_load(const char *path);
+void osm_graph_free(OsmGraph *graph);
+size_t osm_graph_node_count(const OsmGraph *graph);
+
+/* The node closest to the coordinate, or -1 for an empty graph */
+int64_t osm_nearest_node(const OsmGraph *graph, double lat, double lon);
+
+/* Routes with one of the OSM_* algorithms. NULL if there is no route, or a node is unknown */
+OsmRoute *osm_route_between_nodes(const OsmGraph *graph, int64_t start, int64_t end,
+                                  int algorithm);
+/* Routes between coordinates snapped onto the closest roads. NULL if there is no route */
+OsmRoute *osm_route_between_coords(const OsmGraph *graph, double start_lat, double start_lon,
+                                   double end_lat, double end_lon, int algorithm);
+void osm_route_free(OsmRoute *route);
+
+/* The message of the last failed call on this thread, valid until the next failing call */
+const char *osm_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,310 @@
+use crate::preprocessor::preprocessor::FullGraph;
+use crate::preprocessor::routing::{Algorithm, Route, Router};
+use crate::Coord;
+
+use osmpbfreader::NodeId;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::ptr;
+
+/*
+   C API for using the Rust routing from other languages, declared in include/rust_osm.h.
+   A graph is loaded once with osm_graph_load and queried until osm_graph_free. Routes are
+   allocated by Rust and must be given back with osm_route_free. Functions returning NULL or
+   -1 leave a message for osm_last_error. That includes panics, which are caught at every
+   entry point since unwinding into C is undefined behaviour.
+   A loaded graph is never modified, so it can be queried from several threads at once.
+*/
+
+pub const OSM_DIJKSTRA: c_int = 0;
+pub const OSM_ASTAR: c_int = 1;
+pub const OSM_LANDMARKS: c_int = 2;
+
+/// A loaded graph, opaque to C
+pub struct OsmGraph {
+    router: Router,
+}
+
+/// A route returned to C. coords holds coord_count (lat, lon) pairs, one per node for routes
+/// between nodes, and the snapped start and end around them for routes between coordinates
+#[repr(C)]
+pub struct OsmRoute {
+    pub nodes: *mut i64,
+    pub node_count: usize,
+    pub coords: *mut f64,
+    pub coord_count: usize,
+    pub distance: f32,
+    pub visited: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_error(message: impl ToString) {
+    // Messages never contain NUL, but a broken one should not panic across the boundary
+    let message = CString::new(message.to_string()).unwrap_or_default();
+    LAST_ERROR.with(|error| *error.borrow_mut() = message);
+}
+
+/// Runs the body of an entry point, returning failed with an error if it panics
+fn guard<T>(failed: T, body: impl FnOnce() -> T) -> T {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(body)).unwrap_or_else(|_| {
+        set_error("internal error in rust_osm");
+        failed
+    })
+}
+
+/// The coordinate, if it is one
+fn coord(lat: f64, lon: f64) -> Option<Coord> {
+    if lat.is_finite() && lon.is_finite() {
+        Some(Coord { lat, lon })
+    } else {
+        set_error(format!("{},{} is not a coordinate", lat, lon));
+        None
+    }
+}
+
+fn algorithm(code: c_int) -> Option<Algorithm> {
+    match code {
+        OSM_DIJKSTRA => Some(Algorithm::Dijkstra),
+        OSM_ASTAR => Some(Algorithm::AStar),
+        OSM_LANDMARKS => Some(Algorithm::Landmarks),
+        _ => {
+            set_error(format!("unknown algorithm {}", code));
+            None
+        }
+    }
+}
+
+fn into_raw<T>(values: Vec<T>) -> (*mut T, usize) {
+    let len = values.len();
+    (Box::into_raw(values.into_boxed_slice()) as *mut T, len)
+}
+
+fn route_to_c(route: Route, coords: Vec<Coord>) -> *mut OsmRoute {
+    let (nodes, node_count) = into_raw(route.nodes.iter().map(|node| node.0).collect());
+    let (coords, len) = into_raw(coords.iter().flat_map(|c| [c.lat, c.lon]).collect());
+    Box::into_raw(Box::new(OsmRoute {
+        nodes,
+        node_count,
+        coords,
+        coord_count: len / 2,
+        distance: route.cost,
+        visited: route.visited,
+    }))
+}
+
+/// Loads a .graph file in any format FullGraph::load reads. Returns NULL if it cannot be read
+///
+/// # Safety
+/// path must be a NUL terminated string
+#[no_mangle]
+pub unsafe extern "C" fn osm_graph_load(path: *const c_char) -> *mut OsmGraph {
+    guard(ptr::null_mut(), || {
+        if path.is_null() {
+            set_error("path is NULL");
+            return ptr::null_mut();
+        }
+        let Ok(path) = CStr::from_ptr(path).to_str() else {
+            set_error("path is not UTF-8");
+            return ptr::null_mut();
+        };
+        match FullGraph::load(path) {
+            Ok(full_graph) => Box::into_raw(Box::new(OsmGraph {
+                router: Router::new(&full_graph),
+            })),
+            Err(e) => {
+                set_error(format!("could not read {}: {}", path, e));
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// # Safety
+/// graph must come from osm_graph_load and not be used afterwards. NULL is ignored
+#[no_mangle]
+pub unsafe extern "C" fn osm_graph_free(graph: *mut OsmGraph) {
+    guard((), || {
+        if !graph.is_null() {
+            drop(Box::from_raw(graph));
+        }
+    })
+}
+
+/// # Safety
+/// graph must come from osm_graph_load
+#[no_mangle]
+pub unsafe extern "C" fn osm_graph_node_count(graph: *const OsmGraph) -> usize {
+    guard(0, || (*graph).router.coords.len())
+}
+
+/// The node closest to the coordinate, or -1 for an empty graph
+///
+/// # Safety
+/// graph must come from osm_graph_load
+#[no_mangle]
+pub unsafe extern "C" fn osm_nearest_node(graph: *const OsmGraph, lat: f64, lon: f64) -> i64 {
+    guard(-1, || {
+        let Some(coord) = coord(lat, lon) else {
+            return -1;
+        };
+        match (*graph).router.nearest_node(coord) {
+            Some(node) => node.0,
+            None => {
+                set_error("the graph has no nodes");
+                -1
+            }
+        }
+    })
+}
+
+/// Routes between two node ids with one of the OSM_* algorithms. Returns NULL if either node
+/// is unknown or there is no route
+///
+/// # Safety
+/// graph must come from osm_graph_load
+#[no_mangle]
+pub unsafe extern "C" fn osm_route_between_nodes(
+    graph: *const OsmGraph,
+    start: i64,
+    end: i64,
+    algorithm_code: c_int,
+) -> *mut OsmRoute {
+    guard(ptr::null_mut(), || {
+        let router = &(*graph).router;
+        let Some(algorithm) = algorithm(algorithm_code) else {
+            return ptr::null_mut();
+        };
+        for node in [start, end] {
+            if !router.graph.contains_key(&NodeId(node)) {
+                set_error(format!("unknown node {}", node));
+                return ptr::null_mut();
+            }
+        }
+        match router.route_between_nodes(NodeId(start), NodeId(end), algorithm) {
+            Some(route) => {
+                let coords = route.nodes.iter().map(|node| router.coords[node]).collect();
+                route_to_c(route, coords)
+            }
+            None => {
+                set_error(format!("no route from {} to {}", start, end));
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Routes between two coordinates, snapped onto the closest roads. Returns NULL if there is
+/// no route
+///
+/// # Safety
+/// graph must come from osm_graph_load
+#[no_mangle]
+pub unsafe extern "C" fn osm_route_between_coords(
+    graph: *const OsmGraph,
+    start_lat: f64,
+    start_lon: f64,
+    end_lat: f64,
+    end_lon: f64,
+    algorithm_code: c_int,
+) -> *mut OsmRoute {
+    guard(ptr::null_mut(), || {
+        let Some(algorithm) = algorithm(algorithm_code) else {
+            return ptr::null_mut();
+        };
+        let (Some(start), Some(end)) = (coord(start_lat, start_lon), coord(end_lat, end_lon))
+        else {
+            return ptr::null_mut();
+        };
+        match (*graph).router.route_between_coords(start, end, algorithm) {
+            Some(snapped) => route_to_c(snapped.route, snapped.coords),
+            None => {
+                set_error("no route between the given coordinates");
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// # Safety
+/// route must come from one of the osm_route_* functions and not be used afterwards.
+/// NULL is ignored
+#[no_mangle]
+pub unsafe extern "C" fn osm_route_free(route: *mut OsmRoute) {
+    guard((), || {
+        if route.is_null() {
+            return;
+        }
+        let route = Box::from_raw(route);
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            route.nodes,
+            route.node_count,
+        )));
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            route.coords,
+            route.coord_count * 2,
+        )));
+    })
+}
+
+/// The message of the last failed call on this thread, valid until the next failing call
+#[no_mangle]
+pub extern "C" fn osm_last_error() -> *const c_char {
+    guard(ptr::null(), || {
+        LAST_ERROR.with(|error| error.borrow().as_ptr())
+    })
+}
+
+// TESTS
+#[test]
+fn routes_through_the_c_api() {
+    let full_graph = crate::preprocessor::preprocessor::grid_full_graph();
+    let file = tempfile::NamedTempFile::new().unwrap();
+    full_graph.write(&mut file.as_file()).unwrap();
+    let path = CString::new(file.path().to_str().unwrap()).unwrap();
+
+    unsafe {
+        let graph = osm_graph_load(path.as_ptr());
+        assert!(!graph.is_null());
+        assert_eq!(12, osm_graph_node_count(graph));
+        let start = osm_nearest_node(graph, 55.0, 10.0);
+        let end = osm_nearest_node(graph, 55.006, 10.009);
+        assert!(start >= 0 && end >= 0);
+
+        let route = osm_route_between_nodes(graph, start, end, OSM_LANDMARKS);
+        assert!(!route.is_null());
+        let nodes = std::slice::from_raw_parts((*route).nodes, (*route).node_count);
+        assert_eq!((start, end), (nodes[0], nodes[nodes.len() - 1]));
+        assert_eq!((*route).node_count, (*route).coord_count);
+        let dijkstra = osm_route_between_nodes(graph, start, end, OSM_DIJKSTRA);
+        assert!(((*route).distance - (*dijkstra).distance).abs() < 1e-2);
+        osm_route_free(route);
+        osm_route_free(dijkstra);
+
+        let route = osm_route_between_coords(graph, 55.0004, 10.0001, 55.006, 10.0089, OSM_ASTAR);
+        assert_eq!((*route).node_count + 2, (*route).coord_count);
+        osm_route_free(route);
+
+        assert!(osm_route_between_nodes(graph, start, end, 7).is_null());
+        let error = CStr::from_ptr(osm_last_error()).to_str().unwrap();
+        assert_eq!("unknown algorithm 7", error);
+        assert_eq!(-1, osm_nearest_node(graph, f64::NAN, 10.0));
+        let route = osm_route_between_coords(graph, 55.0, f64::INFINITY, 55.006, 10.0, OSM_ASTAR);
+        assert!(route.is_null());
+        let error = CStr::from_ptr(osm_last_error()).to_str().unwrap();
+        assert_eq!("55,inf is not a coordinate", error);
+        osm_graph_free(graph);
+    }
+
+    let missing = CString::new("/nonexistent/map.graph").unwrap();
+    assert!(unsafe { osm_graph_load(missing.as_ptr()) }.is_null());
+}
+
+#[test]
+fn panics_do_not_cross_the_c_api() {
+    assert_eq!(-1, guard(-1, || panic!("broken")));
+    let error = unsafe { CStr::from_ptr(osm_last_error()) };
+    assert_eq!("internal error in rust_osm", error.to_str().unwrap());
+}
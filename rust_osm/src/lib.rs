@@ -0,0 +1,8 @@
+pub mod ffi;
+pub mod preprocessor;
+pub mod server;
+
+pub use crate::preprocessor::coord::Coord;
+pub use crate::preprocessor::graph::Graph;
+
+pub use crate::preprocessor::projection::azimuthal_equidistant_projection;
@@ -4,50 +4,379 @@
 // the terms of the Do What The Fuck You Want To Public License,
 // Version 2, as published by Sam Hocevar. See the COPYING file for
 // more details.
-mod preprocessor;
+use rust_osm::preprocessor::external::MemoryBudget;
+use rust_osm::preprocessor::graph_file::Compression;
+use rust_osm::preprocessor::isochrone::Budget;
+use rust_osm::preprocessor::matrix::*;
+use rust_osm::preprocessor::origins::EdgeOrigins;
+use rust_osm::preprocessor::overlay::{read_edge_updates, Overlay};
+use rust_osm::preprocessor::road_store::RoadStore;
+use rust_osm::preprocessor::routing::Router;
+use rust_osm::preprocessor::time_dependent::*;
+use rust_osm::preprocessor::turn_graph::TurnCosts;
+use rust_osm::Graph;
 
-use crate::preprocessor::coord::*;
-use crate::preprocessor::graph::*;
-use crate::preprocessor::preprocessor::*;
+use osmpbfreader::NodeId;
+use rust_osm::preprocessor::preprocessor::*;
 
-use std::f64::consts::PI;
-
-fn azimuthal_equidistant_projection(coord: Coord, center: (f64, f64)) -> (f64, f64) {
-    let lat_rad = coord.lat * (PI / 180.0);
-    let lon_rad = coord.lon * (PI / 180.0);
-    let center_lat_rad = center.0 * (PI / 180.0);
-    let center_lon_rad = center.1 * (PI / 180.0);
-
-    let r = 6371000.0;
-
-    let delta_lon = lon_rad - center_lon_rad;
-    let central_angle = (center_lat_rad.sin() * lat_rad.sin()
-        + center_lat_rad.cos() * lat_rad.cos() * delta_lon.cos())
-    .acos();
-
-    let distance = r * central_angle;
-
-    let azimuth = delta_lon
-        .sin()
-        .atan2(center_lat_rad.cos() * lat_rad.tan() - center_lat_rad.sin() * delta_lon.cos());
-
-    let x = distance * azimuth.sin();
-    let y = distance * azimuth.cos();
-
-    (x, y)
+/// A node id, or the node closest to a lat,lon coordinate
+fn node_or_nearest(router: &Router, arg: &str) -> NodeId {
+    match arg.parse() {
+        Ok(id) => NodeId(id),
+        Err(_) => {
+            let coord = read_coordinates(arg).unwrap_or_else(|e| panic!("{}", e))[0];
+            router.nearest_node(coord).expect("the graph has no nodes")
+        }
+    }
 }
 
 fn main() {
     let time = std::time::Instant::now();
     let mut preprocessor = Preprocessor::new();
-    preprocessor.get_roads_and_nodes("src/test_data/denmark.osm.pbf");
+    // Several extracts can be given on the command line to merge them into one graph
+    let (flags, files): (Vec<String>, Vec<String>) =
+        std::env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    let mut temp_dir = None;
+    let mut format = OutputFormat::MessagePack;
+    let mut compression = Compression::None;
+    let mut spatial_index = false;
+    let mut turn_graph = false;
+    let mut geojson = None;
+    let mut dimacs = None;
+    let mut metis = None;
+    let mut address = "127.0.0.1:8080".to_owned();
+    let mut matrix_targets = None;
+    let mut output = None;
+    let mut node_ids = false;
+    let mut budget = None;
+    let mut hull_edge = 500.0;
+    let mut k = 5;
+    let mut origins = None;
+    let mut speeds = None;
+    let mut depart = 8.0 * 3600.0;
+    let mut updates = None;
+    let mut store = None;
+    for flag in &flags {
+        match flag.split_once('=') {
+            None if flag == "--single-pass" => preprocessor.ingestion = Ingestion::SinglePass,
+            // Stores the k-d tree order of the nodes so readers can skip building it
+            None if flag == "--spatial-index" => spatial_index = true,
+            None if flag == "--turn-graph" => turn_graph = true,
+            // Keeps roads and nodes on disk, sorting with at most this many megabytes at a time
+            Some(("--memory-budget", megabytes)) => {
+                let megabytes: usize = megabytes.parse().expect("--memory-budget takes megabytes");
+                preprocessor.ingestion = Ingestion::External(MemoryBudget::new(megabytes << 20));
+            }
+            Some(("--temp-dir", dir)) => temp_dir = Some(dir.into()),
+            Some(("--format", "msgpack")) => format = OutputFormat::MessagePack,
+            Some(("--format", "bincode")) => format = OutputFormat::Bincode,
+            Some(("--format", "json")) => format = OutputFormat::Json,
+            Some(("--format", "flat")) => format = OutputFormat::Flat,
+            Some(("--compress", "gzip")) => compression = Compression::Gzip,
+            Some(("--compress", "zstd")) => compression = Compression::Zstd,
+            Some(("--projection", projection)) => {
+                preprocessor.projection = projection.parse().unwrap_or_else(|e| panic!("{}", e));
+            }
+            // Projects around this point instead of the mean of all nodes
+            Some(("--center", center)) => {
+                let (lat, lon) = center.split_once(',').expect("--center takes lat,lon");
+                let parse = |v: &str| v.trim().parse::<f64>().expect("--center takes lat,lon");
+                preprocessor.custom_center = Some((parse(lat), parse(lon)));
+            }
+            // Also writes the graph as GeoJSON for inspecting it in QGIS
+            Some(("--geojson", path)) => geojson = Some(path.to_owned()),
+            // Writes <path>.gr and <path>.co for benchmarking against other routing codes
+            Some(("--dimacs", path)) => dimacs = Some(path.to_owned()),
+            Some(("--metis", path)) => metis = Some(path.to_owned()),
+            Some(("--address", value)) => address = value.to_owned(),
+            // Locations are node ids instead of lat,lon for the matrix mode
+            None if flag == "--nodes" => node_ids = true,
+            Some(("--targets", path)) => matrix_targets = Some(path.to_owned()),
+            Some(("--output", path)) => output = Some(path.to_owned()),
+            Some(("--meters", meters)) => {
+                budget = Some(Budget::Meters(meters.parse().expect("--meters takes meters")))
+            }
+            Some(("--minutes", minutes)) => {
+                let minutes: f32 = minutes.parse().expect("--minutes takes minutes");
+                budget = Some(Budget::Seconds(minutes * 60.0));
+            }
+            Some(("--k", value)) => k = value.parse().expect("--k takes a number of paths"),
+            // Isochrone boundaries longer than this many meters are dug into
+            Some(("--hull-edge", meters)) => {
+                hull_edge = meters.parse().expect("--hull-edge takes meters")
+            }
+            // Where the OSM ways and nodes of every edge are written, or read from in time-route
+            Some(("--origins", path)) => origins = Some(path.to_owned()),
+            Some(("--speeds", path)) => speeds = Some(path.to_owned()),
+            // Closures and cost changes by OSM id that serve applies to every route
+            Some(("--updates", path)) => updates = Some(path.to_owned()),
+            // Keeps the roads before minimizing, for update to apply OsmChange files to
+            Some(("--store", path)) => store = Some(path.to_owned()),
+            Some(("--depart", time)) => {
+                depart = parse_time_of_day(time).expect("--depart takes HH:MM")
+            }
+            _ => panic!("Unknown option {}", flag),
+        }
+    }
+    // Checked before preprocessing, which takes long, instead of when writing the graph
+    if format == OutputFormat::Flat && compression != Compression::None {
+        panic!("--format=flat is memory-mapped and cannot be used with --compress");
+    }
+    let dimacs_input = matches!(files.as_slice(), [gr] if gr.ends_with(".gr"));
+    if store.is_some() && (matches!(preprocessor.ingestion, Ingestion::External(_)) || dimacs_input) {
+        panic!("--store keeps the roads, which are not read with --memory-budget or from DIMACS");
+    }
+    if store.is_some() {
+        // For changes that turn other ways into roads
+        preprocessor.way_nodes = Some(std::collections::HashMap::new());
+    }
+    // rust_osm serve <graph file> answers routing requests over HTTP instead of preprocessing,
+    // with the edge updates in --updates=<file> when given --origins=<file> of the graph
+    if let [mode, graph] = files.as_slice() {
+        if mode == "serve" {
+            let full_graph = FullGraph::load(graph)
+                .unwrap_or_else(|e| panic!("Could not read {}: {}", graph, e));
+            println!("Time to load graph: {:?}", time.elapsed());
+            let mut server = rust_osm::server::Server::new(&full_graph);
+            if let Some(path) = updates {
+                let text = std::fs::read_to_string(&path)
+                    .unwrap_or_else(|e| panic!("Could not read {}: {}", path, e));
+                let updates = read_edge_updates(&text).unwrap_or_else(|e| panic!("{}", e));
+                let path = origins.expect("--updates takes --origins=<file> written with the graph");
+                let edge_origins = EdgeOrigins::load(&path)
+                    .unwrap_or_else(|e| panic!("Could not read {}: {}", path, e));
+                let (graph, coords) = (full_graph.graph(), full_graph.coords());
+                let overlay = Overlay::new(&graph, &coords, &edge_origins, &updates);
+                println!("Edges changed by the updates: {}", overlay.changed_edges());
+                if !overlay.unmatched.is_empty() {
+                    println!("Updates on no road of the graph: {:?}", overlay.unmatched);
+                }
+                server.set_overlay(Some(overlay));
+            }
+            server.serve(&address).unwrap();
+            return;
+        }
+    }
+    // rust_osm matrix <graph file> <locations> writes the distances between all locations, or
+    // from them to the --targets, as CSV or as JSON when --output ends in .json
+    if let [mode, graph, sources] = files.as_slice() {
+        if mode == "matrix" {
+            let full_graph = FullGraph::load(graph)
+                .unwrap_or_else(|e| panic!("Could not read {}: {}", graph, e));
+            let read = |path: &str| {
+                std::fs::read_to_string(path).unwrap_or_else(|e| panic!("Could not read {}: {}", path, e))
+            };
+            let sources = read(sources);
+            let targets = matrix_targets.map(|path| read(&path)).unwrap_or(sources.clone());
+            let router = Router::new(&full_graph);
+            let matrix = if node_ids {
+                let sources = read_node_ids(&sources).unwrap_or_else(|e| panic!("{}", e));
+                let targets = read_node_ids(&targets).unwrap_or_else(|e| panic!("{}", e));
+                Graph::distance_matrix(&router.graph, &sources, &targets)
+            } else {
+                let sources = read_coordinates(&sources).unwrap_or_else(|e| panic!("{}", e));
+                let targets = read_coordinates(&targets).unwrap_or_else(|e| panic!("{}", e));
+                router.distance_matrix(&sources, &targets)
+            };
+            match output {
+                Some(path) if path.ends_with(".json") => {
+                    let file = std::fs::File::create(path).unwrap();
+                    serde_json::to_writer(std::io::BufWriter::new(file), &matrix_to_json(&matrix)).unwrap();
+                }
+                Some(path) => {
+                    let mut file = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+                    write_matrix_csv(&matrix, &mut file).unwrap();
+                }
+                None => write_matrix_csv(&matrix, &mut std::io::stdout().lock()).unwrap(),
+            }
+            eprintln!("Time to compute matrix: {:?}", time.elapsed());
+            return;
+        }
+    }
+    // rust_osm isochrone <graph file> <lat,lon> --minutes=15 writes what can be reached from
+    // the coordinate as GeoJSON
+    if let [mode, graph, start] = files.as_slice() {
+        if mode == "isochrone" {
+            let full_graph = FullGraph::load(graph)
+                .unwrap_or_else(|e| panic!("Could not read {}: {}", graph, e));
+            let start = *read_coordinates(start)
+                .unwrap_or_else(|e| panic!("{}", e))
+                .first()
+                .expect("isochrone takes a start lat,lon");
+            let budget = budget.expect("isochrone takes --meters=<meters> or --minutes=<minutes>");
+            let isochrone = Router::new(&full_graph)
+                .isochrone(start, budget, hull_edge)
+                .expect("the graph has no roads");
+            eprintln!("Reachable nodes: {}", isochrone.reachable.len());
+            let geojson = isochrone.to_geojson();
+            match output {
+                Some(path) => {
+                    let file = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+                    serde_json::to_writer(file, &geojson).unwrap();
+                }
+                None => println!("{}", geojson),
+            }
+            eprintln!("Time to compute isochrone: {:?}", time.elapsed());
+            return;
+        }
+    }
+    // rust_osm k-shortest <graph file> <start> <end> --k=5 prints the k shortest simple paths,
+    // between node ids or the nodes closest to lat,lon coordinates
+    if let [mode, graph, start, end] = files.as_slice() {
+        if mode == "k-shortest" {
+            let full_graph = FullGraph::load(graph)
+                .unwrap_or_else(|e| panic!("Could not read {}: {}", graph, e));
+            let router = Router::new(&full_graph);
+            let node = |arg: &str| node_or_nearest(&router, arg);
+            let paths = Graph::k_shortest_paths(&router.graph, node(start), node(end), k);
+            for path in &paths {
+                let nodes: Vec<String> = path.nodes.iter().map(|node| node.0.to_string()).collect();
+                println!("{}\t{}", path.cost, nodes.join(","));
+            }
+            eprintln!("Paths found: {}, nodes visited: {}", paths.len(), paths.last().map_or(0, |p| p.visited));
+            eprintln!("Time to find paths: {:?}", time.elapsed());
+            return;
+        }
+    }
+    // rust_osm time-route <graph file> <start> <end> --origins=<file> --speeds=<csv> --depart=08:00
+    // prints the route arriving first, with the speeds of the ways at the time they are driven
+    if let [mode, graph, start, end] = files.as_slice() {
+        if mode == "time-route" {
+            let full_graph = FullGraph::load(graph)
+                .unwrap_or_else(|e| panic!("Could not read {}: {}", graph, e));
+            let router = Router::new(&full_graph);
+            let path = origins.expect("time-route takes --origins=<file> written with the graph");
+            let edge_origins = EdgeOrigins::load(&path)
+                .unwrap_or_else(|e| panic!("Could not read {}: {}", path, e));
+            let profiles = match speeds {
+                Some(path) => {
+                    let text = std::fs::read_to_string(&path)
+                        .unwrap_or_else(|e| panic!("Could not read {}: {}", path, e));
+                    read_speed_profiles(&text).unwrap_or_else(|e| panic!("{}", e))
+                }
+                None => SpeedProfiles::new(),
+            };
+            let td_graph = TimeDependentGraph::new(&router.graph, &router.coords, &edge_origins, &profiles);
+            let node = |arg: &str| node_or_nearest(&router, arg);
+            match td_graph.earliest_arrival_astar(node(start), node(end), depart) {
+                Some(route) => {
+                    let arrival = (depart + route.cost).round() as u32;
+                    let clock = format!("{:02}:{:02}", arrival / 3600 % 24, arrival / 60 % 60);
+                    let nodes: Vec<String> = route.nodes.iter().map(|node| node.0.to_string()).collect();
+                    println!("{:.1} minutes, arriving at {}", route.cost / 60.0, clock);
+                    println!("{}", nodes.join(","));
+                    eprintln!("Nodes visited: {}", route.visited);
+                }
+                None => println!("No route found"),
+            }
+            eprintln!("Time to find route: {:?}", time.elapsed());
+            return;
+        }
+    }
+    if let (Ingestion::External(budget), Some(dir)) = (&mut preprocessor.ingestion, temp_dir) {
+        budget.temp_dir = dir;
+    }
+    // rust_osm update <store> <change.osc>... applies OsmChange files to the roads written with
+    // --store=<file>, writes them back and builds the graph without reading the extract again
+    let update = files.first().is_some_and(|mode| mode == "update");
+    let report = if update {
+        let [_, path, changes @ ..] = files.as_slice() else {
+            panic!("update takes a store and OsmChange files");
+        };
+        let mut road_store =
+            RoadStore::load(path).unwrap_or_else(|e| panic!("Could not read {}: {}", path, e));
+        let mut report = IngestionReport::default();
+        for change in changes {
+            let change_report = road_store.apply_change(change);
+            println!(
+                "{}: {} ways changed, edges rebuilt at {} nodes and minimized again around {}",
+                change,
+                change_report.changed_ways.len(),
+                change_report.rebuilt_nodes,
+                change_report.reminimized_nodes
+            );
+            report.missing_nodes.extend(change_report.missing.missing_nodes);
+            report.ways_with_missing_nodes.extend(change_report.missing.ways_with_missing_nodes);
+        }
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+        road_store.write(&mut file).unwrap();
+        road_store.load_into(&mut preprocessor);
+        report
+    } else if files.is_empty() {
+        preprocessor.get_roads_and_nodes("src/test_data/denmark.osm.pbf")
+    } else if let [gr] = files.as_slice() {
+        // A DIMACS graph, with its coordinates in the .co file next to it
+        if let Some(name) = gr.strip_suffix(".gr") {
+            preprocessor
+                .get_graph_from_dimacs(gr, &format!("{}.co", name))
+                .unwrap_or_else(|e| panic!("Could not read {}: {}", gr, e));
+            IngestionReport::default()
+        } else {
+            preprocessor.get_roads_and_nodes(gr)
+        }
+    } else {
+        let files: Vec<&str> = files.iter().map(String::as_str).collect();
+        preprocessor.get_roads_and_nodes_from_files(&files)
+    };
+    if !report.is_complete() {
+        let ways = &report.ways_with_missing_nodes;
+        println!(
+            "{} missing nodes in {} ways, the first of them: {:?}",
+            report.missing_nodes.len(),
+            ways.len(),
+            &ways[..ways.len().min(10)]
+        );
+    }
     println!("Time to get roads and nodes: {:?}", time.elapsed());
+    if let (false, Some(path)) = (update, &store) {
+        let road_store = preprocessor.road_store();
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+        road_store.write(&mut file).unwrap();
+        road_store.load_into(&mut preprocessor);
+    }
+    preprocessor.record_origins = origins.is_some();
     let (graph, bi_graph, landmarks) = &preprocessor.build_graph();
+    if let (Some(path), Some(edge_origins)) = (&origins, &preprocessor.origins) {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+        edge_origins.write(&mut file).unwrap();
+        let untraced = &edge_origins.untraced;
+        if !untraced.is_empty() {
+            println!(
+                "{} edges have no recorded origin, the first of them: {:?}",
+                untraced.len(),
+                &untraced[..untraced.len().min(10)]
+            );
+        }
+    }
     println!("Size of graph after minimization: {}", graph.len());
     let projected_points = &preprocessor.project_nodes_to_2d();
     let time2 = std::time::Instant::now();
-    let full_graph = preprocessor.build_full_graph(graph, bi_graph, landmarks.to_vec(), &projected_points);
-    Preprocessor::write_graph(full_graph, "denmark1.graph");
+    let mut full_graph = preprocessor.build_full_graph(graph, bi_graph, landmarks.to_vec(), projected_points);
+    if spatial_index {
+        full_graph.add_spatial_index();
+    }
+    if let Some(path) = dimacs {
+        let create = |extension: &str| {
+            std::io::BufWriter::new(std::fs::File::create(format!("{}.{}", path, extension)).unwrap())
+        };
+        full_graph.write_dimacs(&mut create("gr"), &mut create("co")).unwrap();
+    }
+    if let Some(path) = metis {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+        full_graph.write_metis(&mut file).unwrap();
+    }
+    if let Some(path) = geojson {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+        full_graph.write_geojson(&mut file).unwrap();
+    }
+    Preprocessor::write_graph(full_graph, "denmark1.graph", format, compression)
+        .unwrap_or_else(|e| panic!("Could not write denmark1.graph: {}", e));
+    if turn_graph {
+        let turn_graph = preprocessor.build_turn_graph(graph, projected_points, TurnCosts::default());
+        println!("Size of turn graph: {}", turn_graph.nodes.len());
+        Preprocessor::write_graph(turn_graph, "denmark1.turns.graph", format, compression)
+            .unwrap_or_else(|e| panic!("Could not write denmark1.turns.graph: {}", e));
+    }
     println!("Time to write graph: {:?}", time2.elapsed());
     println!("Total time: {:?}", time.elapsed());
 }
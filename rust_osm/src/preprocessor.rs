@@ -1,4 +1,25 @@
-pub mod preprocessor;
+pub mod alternatives;
 pub mod coord;
+pub mod dimacs;
+pub mod edge;
+pub mod external;
+pub mod flat_graph;
+pub mod geojson;
 pub mod graph;
-pub mod edge;
\ No newline at end of file
+pub mod graph_file;
+pub mod graph_format;
+pub mod isochrone;
+pub mod k_shortest;
+pub mod matrix;
+pub mod origins;
+pub mod osrm;
+pub mod osm_xml;
+pub mod overlay;
+#[allow(clippy::module_inception)]
+pub mod preprocessor;
+pub mod projection;
+pub mod road_store;
+pub mod routing;
+pub mod spatial_index;
+pub mod time_dependent;
+pub mod turn_graph;
@@ -0,0 +1,297 @@
+use crate::preprocessor::edge::Edge;
+use crate::preprocessor::graph::Graph;
+use crate::preprocessor::routing::Route;
+
+use osmpbfreader::NodeId;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/*
+   Alternative routes with the plateau method.
+   A shortest path tree is grown from the start on the graph and one towards the end on the
+   bi_graph. A plateau is a chain of edges that lies on both trees: every node on it is
+   reached the shortest way from the start and continues the shortest way to the end. Each
+   plateau gives a route through a via node on it, start -> plateau -> end, and long plateaus
+   give routes that are locally optimal rather than detours. The optimal route is itself one
+   long plateau.
+   Candidates are taken cheapest first, and kept if they are within max_stretch of the optimum,
+   their plateau covers at least min_plateau of their cost, they do not revisit a node and
+   they share at most max_overlap of their cost with any route kept before.
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct AlternativeOptions {
+    /// How many routes to return at most, including the optimal one
+    pub max_routes: usize,
+    /// Largest cost allowed as a multiple of the optimal cost
+    pub max_stretch: f32,
+    /// Largest share of its cost a route may have in common with a route kept before
+    pub max_overlap: f32,
+    /// Smallest share of its cost the plateau of a route must cover
+    pub min_plateau: f32,
+}
+
+impl Default for AlternativeOptions {
+    fn default() -> AlternativeOptions {
+        AlternativeOptions {
+            max_routes: 3,
+            max_stretch: 1.25,
+            max_overlap: 0.6,
+            min_plateau: 0.2,
+        }
+    }
+}
+
+/// Cost and predecessor of every node at most max_cost away, and how many were settled.
+/// Without max_cost, it becomes stretch times the cost of target once that is settled
+fn shortest_path_tree(
+    graph: &HashMap<NodeId, Vec<Edge>>,
+    start: NodeId,
+    target: NodeId,
+    stretch: f32,
+    max_cost: Option<f32>,
+) -> (HashMap<NodeId, (f32, Option<NodeId>)>, usize) {
+    let mut tree: HashMap<NodeId, (f32, Option<NodeId>)> = HashMap::new();
+    let mut settled = HashSet::new();
+    let mut heap = BinaryHeap::new();
+    let mut max_cost = max_cost.unwrap_or(f32::INFINITY);
+    tree.insert(start, (0.0, None));
+    heap.push(Edge::new(start, 0.0));
+
+    while let Some(Edge { node, cost }) = heap.pop() {
+        if cost > max_cost {
+            break;
+        }
+        if !settled.insert(node) {
+            continue;
+        }
+        if node == target && max_cost.is_infinite() {
+            max_cost = cost * stretch;
+        }
+        for edge in graph.get(&node).into_iter().flatten() {
+            let next_cost = cost + edge.cost;
+            if tree
+                .get(&edge.node)
+                .is_none_or(|(best, _)| next_cost < *best)
+            {
+                tree.insert(edge.node, (next_cost, Some(node)));
+                heap.push(Edge::new(edge.node, next_cost));
+            }
+        }
+    }
+    // Nodes only reached, not settled, may not have their final cost
+    tree.retain(|node, _| settled.contains(node));
+    (tree, settled.len())
+}
+
+/// Follows the predecessors from node back to the root of the tree, node first
+fn walk_tree(tree: &HashMap<NodeId, (f32, Option<NodeId>)>, node: NodeId) -> Vec<NodeId> {
+    let mut nodes = vec![node];
+    while let Some(next) = tree[nodes.last().unwrap()].1 {
+        nodes.push(next);
+    }
+    nodes
+}
+
+/// The cost of every edge of a path, by its ends
+type EdgeCosts = HashMap<(NodeId, NodeId), f32>;
+
+fn edge_costs(graph: &HashMap<NodeId, Vec<Edge>>, nodes: &[NodeId]) -> EdgeCosts {
+    nodes
+        .windows(2)
+        .map(|pair| {
+            let cost = graph[&pair[0]]
+                .iter()
+                .filter(|edge| edge.node == pair[1])
+                .map(|edge| edge.cost)
+                .fold(f32::INFINITY, f32::min);
+            ((pair[0], pair[1]), cost)
+        })
+        .collect()
+}
+
+impl Graph {
+    /// Up to options.max_routes routes from start to end, the optimal one first. bi_graph is
+    /// the graph with every edge reversed
+    pub fn alternative_routes(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        bi_graph: &HashMap<NodeId, Vec<Edge>>,
+        start: NodeId,
+        end: NodeId,
+        options: AlternativeOptions,
+    ) -> Vec<Route> {
+        let (forward, forward_visited) =
+            shortest_path_tree(graph, start, end, options.max_stretch, None);
+        let Some(&(optimum, _)) = forward.get(&end) else {
+            return Vec::new();
+        };
+        let max_cost = optimum * options.max_stretch;
+        let (backward, backward_visited) =
+            shortest_path_tree(bi_graph, end, start, 1.0, Some(max_cost));
+        let visited = forward_visited + backward_visited;
+        if start == end {
+            let nodes = vec![start];
+            return vec![Route {
+                nodes,
+                cost: 0.0,
+                visited,
+            }];
+        }
+
+        // Plateaus as (route cost, plateau length, first node), from the edges on both trees
+        let on_plateau = |from: NodeId, to: NodeId| {
+            forward
+                .get(&to)
+                .is_some_and(|(_, previous)| *previous == Some(from))
+                && backward
+                    .get(&from)
+                    .is_some_and(|(_, next)| *next == Some(to))
+        };
+        let mut plateaus = Vec::new();
+        for (node, (_, previous)) in &forward {
+            let Some(&(_, next)) = backward.get(node) else {
+                continue;
+            };
+            let starts_plateau = previous.is_none_or(|previous| !on_plateau(previous, *node));
+            let continues = next.is_some_and(|next| on_plateau(*node, next));
+            if !starts_plateau || !continues {
+                continue;
+            }
+            let mut last = *node;
+            while let Some(next) = backward[&last].1.filter(|next| on_plateau(last, *next)) {
+                last = next;
+            }
+            let cost = forward[node].0 + backward[node].0;
+            let length = forward[&last].0 - forward[node].0;
+            plateaus.push((cost, length, *node));
+        }
+        plateaus.sort_by(|a, b| a.0.total_cmp(&b.0).then(b.1.total_cmp(&a.1)));
+
+        let mut routes: Vec<(Route, EdgeCosts)> = Vec::new();
+        for (cost, length, first) in plateaus {
+            if routes.len() >= options.max_routes || cost > max_cost {
+                break;
+            }
+            // The first candidate is an optimal route, which is kept however short its plateau
+            if !routes.is_empty() && length < options.min_plateau * cost {
+                continue;
+            }
+            // start -> first along the forward tree, then along the plateau and on to end
+            // along the backward tree
+            let mut nodes = walk_tree(&forward, first);
+            nodes.reverse();
+            nodes.extend(walk_tree(&backward, first).into_iter().skip(1));
+            let unique: HashSet<&NodeId> = nodes.iter().collect();
+            if unique.len() != nodes.len() {
+                continue;
+            }
+            let edges = edge_costs(graph, &nodes);
+            let overlaps = routes.iter().any(|(_, kept)| {
+                let shared: f32 = edges
+                    .iter()
+                    .filter(|(edge, _)| kept.contains_key(edge))
+                    .map(|(_, cost)| cost)
+                    .sum();
+                shared > options.max_overlap * cost
+            });
+            if !overlaps {
+                let route = Route {
+                    nodes,
+                    cost,
+                    visited,
+                };
+                routes.push((route, edges));
+            }
+        }
+        routes.into_iter().map(|(route, _)| route).collect()
+    }
+}
+
+// TESTS
+#[cfg(test)]
+fn ladder() -> HashMap<NodeId, Vec<Edge>> {
+    // Two parallel roads from 0 to 5, 0-1-2-5 costing 30 and 0-3-4-5 costing 33, with a rung
+    // 1-3 that only makes detours. All roads go both ways
+    let roads = [
+        (0, 1, 10.0),
+        (1, 2, 10.0),
+        (2, 5, 10.0),
+        (0, 3, 11.0),
+        (3, 4, 11.0),
+        (4, 5, 11.0),
+        (1, 3, 2.0),
+    ];
+    let mut graph: HashMap<NodeId, Vec<Edge>> = HashMap::new();
+    for (a, b, cost) in roads {
+        graph
+            .entry(NodeId(a))
+            .or_default()
+            .push(Edge::new(NodeId(b), cost));
+        graph
+            .entry(NodeId(b))
+            .or_default()
+            .push(Edge::new(NodeId(a), cost));
+    }
+    graph
+}
+
+#[test]
+fn finds_the_parallel_road() {
+    let graph = ladder();
+    let bi_graph = Graph::get_bidirectional_graph(&graph);
+    let routes = Graph::alternative_routes(
+        &graph,
+        &bi_graph,
+        NodeId(0),
+        NodeId(5),
+        AlternativeOptions::default(),
+    );
+    assert_eq!(2, routes.len());
+    let ids = |route: &Route| route.nodes.iter().map(|node| node.0).collect::<Vec<_>>();
+    assert_eq!(vec![0, 1, 2, 5], ids(&routes[0]));
+    assert_eq!(30.0, routes[0].cost);
+    assert_eq!(vec![0, 3, 4, 5], ids(&routes[1]));
+    assert_eq!(33.0, routes[1].cost);
+
+    // The parallel road is more than 5% longer
+    let strict = AlternativeOptions {
+        max_stretch: 1.05,
+        ..AlternativeOptions::default()
+    };
+    assert_eq!(
+        1,
+        Graph::alternative_routes(&graph, &bi_graph, NodeId(0), NodeId(5), strict).len()
+    );
+    let missing = Graph::alternative_routes(&graph, &bi_graph, NodeId(0), NodeId(42), strict);
+    assert!(missing.is_empty());
+}
+
+#[test]
+fn alternatives_on_the_grid_are_valid_paths() {
+    let full_graph = crate::preprocessor::preprocessor::grid_full_graph();
+    let (graph, bi_graph) = (full_graph.graph(), full_graph.bi_graph());
+    let options = AlternativeOptions {
+        max_routes: 4,
+        max_stretch: 1.5,
+        max_overlap: 0.8,
+        min_plateau: 0.1,
+    };
+    let nodes: Vec<NodeId> = graph.keys().copied().collect();
+    for start in &nodes {
+        for end in &nodes {
+            let routes = Graph::alternative_routes(&graph, &bi_graph, *start, *end, options);
+            let Some(optimal) = Graph::shortest_path(&graph, *start, *end) else {
+                assert!(routes.is_empty());
+                continue;
+            };
+            assert!((routes[0].cost - optimal.cost).abs() < 1e-2);
+            for route in &routes {
+                assert_eq!(
+                    (*start, *end),
+                    (route.nodes[0], *route.nodes.last().unwrap())
+                );
+                assert!(route.cost <= optimal.cost * options.max_stretch + 1e-2);
+                let cost: f32 = edge_costs(&graph, &route.nodes).values().sum();
+                assert!((cost - route.cost).abs() < 1e-2);
+            }
+        }
+    }
+}
@@ -1,12 +1,17 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Coord {
     pub lat: f64,
     pub lon: f64,
 }
 
 impl Coord {
+    /// Whether lat and lon are finite and within the valid latitudes and longitudes
+    pub fn is_valid(&self) -> bool {
+        (-90.0..=90.0).contains(&self.lat) && (-180.0..=180.0).contains(&self.lon)
+    }
+
     pub fn distance_to(&self, end: Coord) -> f32 {
         let r: f64 = 6_378_100.0;
 
@@ -21,4 +26,15 @@ impl Coord {
 
         (r * c) as f32
     }
+
+    /// Initial direction of the great circle towards end, in degrees clockwise from north
+    pub fn bearing_to(&self, end: Coord) -> f64 {
+        let dlon: f64 = (end.lon - self.lon).to_radians();
+        let lat1: f64 = (self.lat).to_radians();
+        let lat2: f64 = (end.lat).to_radians();
+
+        let y = dlon.sin() * lat2.cos();
+        let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+        (y.atan2(x).to_degrees() + 360.0) % 360.0
+    }
 }
@@ -0,0 +1,362 @@
+use crate::preprocessor::coord::Coord;
+use crate::preprocessor::edge::Edge;
+use crate::preprocessor::graph::Graph;
+use crate::preprocessor::graph_file::GraphFileError;
+use crate::preprocessor::preprocessor::{source_file_name, FullGraph, Preprocessor};
+
+use osmpbfreader::NodeId;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+
+/*
+   Text formats used by routing and partitioning benchmarks.
+
+   DIMACS shortest path challenge (.gr and .co):
+   c <comment>
+   p sp <nodes> <arcs>             one arc line per directed edge follows
+   a <from> <to> <weight>          weights are integers, we write costs rounded to whole meters
+   p aux sp co <nodes>
+   v <id> <lon> <lat>              coordinates in millionths of a degree
+
+   METIS (.graph for partitioners, not to be confused with our own .graph files):
+   <nodes> <edges> 001             edges counts undirected edges, 001 means edges have weights
+   <neighbour> <weight> ...        one line per node, listing every neighbour in either direction
+
+   Both number nodes from 1, so node ids are replaced by their position in sorted order.
+   After rewrite_ids this is simply id + 1.
+*/
+fn dimacs_ids(graph: &HashMap<NodeId, Vec<Edge>>) -> BTreeMap<NodeId, usize> {
+    let mut ids: Vec<NodeId> = graph.keys().copied().collect();
+    ids.sort();
+    ids.into_iter().zip(1..).collect()
+}
+
+fn dimacs_weight(cost: f32) -> u64 {
+    cost.round() as u64
+}
+
+impl Graph {
+    pub fn write_dimacs<W: Write>(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        let ids = dimacs_ids(graph);
+        let arcs: usize = graph.values().map(Vec::len).sum();
+        writeln!(
+            writer,
+            "c road graph written by rust_osm, weights in meters"
+        )?;
+        writeln!(writer, "p sp {} {}", ids.len(), arcs)?;
+        for (node, id) in &ids {
+            for edge in &graph[node] {
+                let weight = dimacs_weight(edge.cost);
+                writeln!(writer, "a {} {} {}", id, ids[&edge.node], weight)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_dimacs_coordinates<W: Write>(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        nodes: &HashMap<NodeId, Coord>,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        let ids = dimacs_ids(graph);
+        writeln!(writer, "c coordinates written by rust_osm")?;
+        writeln!(writer, "p aux sp co {}", ids.len())?;
+        for (node, id) in &ids {
+            let coord = &nodes[node];
+            let lon = (coord.lon * 1e6).round() as i64;
+            let lat = (coord.lat * 1e6).round() as i64;
+            writeln!(writer, "v {} {} {}", id, lon, lat)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_metis<W: Write>(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        let ids = dimacs_ids(graph);
+        // METIS wants an undirected graph without loops or parallel edges, keep the cheapest
+        let mut neighbours: Vec<BTreeMap<usize, u64>> = vec![BTreeMap::new(); ids.len() + 1];
+        for (node, edges) in graph {
+            let from = ids[node];
+            for edge in edges {
+                let to = ids[&edge.node];
+                if from == to {
+                    continue;
+                }
+                // METIS does not allow zero weights
+                let weight = dimacs_weight(edge.cost).max(1);
+                for (a, b) in [(from, to), (to, from)] {
+                    let current = neighbours[a].entry(b).or_insert(weight);
+                    *current = (*current).min(weight);
+                }
+            }
+        }
+        let edges: usize = neighbours.iter().map(BTreeMap::len).sum::<usize>() / 2;
+        writeln!(writer, "{} {} 001", ids.len(), edges)?;
+        for adjacent in &neighbours[1..] {
+            let line: Vec<String> = adjacent
+                .iter()
+                .map(|(node, weight)| format!("{} {}", node, weight))
+                .collect();
+            writeln!(writer, "{}", line.join(" "))?;
+        }
+        Ok(())
+    }
+
+    /// Reads a DIMACS .gr file, keeping the node numbers from the file as ids
+    pub fn read_dimacs<R: BufRead>(
+        reader: R,
+    ) -> Result<HashMap<NodeId, Vec<Edge>>, GraphFileError> {
+        let mut graph: HashMap<NodeId, Vec<Edge>> = HashMap::new();
+        let mut node_count: Option<i64> = None;
+        for_each_line(reader, |kind, fields| match kind {
+            "p" => {
+                let nodes: i64 = parse_field(fields.get(1))?;
+                for id in 1..=nodes {
+                    graph.insert(NodeId(id), Vec::new());
+                }
+                node_count = Some(nodes);
+                Ok(())
+            }
+            "a" => {
+                let nodes = node_count.ok_or("arc before the problem line")?;
+                let from: i64 = parse_field(fields.first())?;
+                let to: i64 = parse_field(fields.get(1))?;
+                let cost: f32 = parse_field(fields.get(2))?;
+                if !(1..=nodes).contains(&from) || !(1..=nodes).contains(&to) {
+                    return Err(format!(
+                        "arc {} {} is not between nodes 1..={}",
+                        from, to, nodes
+                    ));
+                }
+                // Every search assumes costs that are finite and not negative
+                if !cost.is_finite() || cost < 0.0 {
+                    return Err(format!("invalid arc weight {}", cost));
+                }
+                graph
+                    .entry(NodeId(from))
+                    .or_default()
+                    .push(Edge::new(NodeId(to), cost));
+                Ok(())
+            }
+            _ => Err(format!("unknown line type {}", kind)),
+        })?;
+        Ok(graph)
+    }
+
+    /// Reads a DIMACS .co file into coordinates for the nodes of read_dimacs
+    pub fn read_dimacs_coordinates<R: BufRead>(
+        reader: R,
+    ) -> Result<HashMap<NodeId, Coord>, GraphFileError> {
+        let mut nodes = HashMap::new();
+        for_each_line(reader, |kind, fields| match kind {
+            "p" => Ok(()),
+            "v" => {
+                let id = NodeId(parse_field(fields.first())?);
+                let lon: f64 = parse_field(fields.get(1))?;
+                let lat: f64 = parse_field(fields.get(2))?;
+                nodes.insert(
+                    id,
+                    Coord {
+                        lat: lat / 1e6,
+                        lon: lon / 1e6,
+                    },
+                );
+                Ok(())
+            }
+            _ => Err(format!("unknown line type {}", kind)),
+        })?;
+        Ok(nodes)
+    }
+}
+
+fn for_each_line<R: BufRead>(
+    reader: R,
+    mut f: impl FnMut(&str, &[&str]) -> Result<(), String>,
+) -> Result<(), GraphFileError> {
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            None | Some("c") => continue,
+            Some(kind) => {
+                let fields: Vec<&str> = fields.collect();
+                f(kind, &fields)
+                    .map_err(|e| GraphFileError::Decode(format!("line {}: {}", number + 1, e)))?
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_field<T: std::str::FromStr>(field: Option<&&str>) -> Result<T, String> {
+    let field = field.ok_or("missing field")?;
+    field
+        .parse()
+        .map_err(|_| format!("invalid number {}", field))
+}
+
+impl FullGraph {
+    pub fn write_dimacs<W: Write>(&self, gr: &mut W, co: &mut W) -> std::io::Result<()> {
+        let graph = self.graph();
+        Graph::write_dimacs(&graph, gr)?;
+        Graph::write_dimacs_coordinates(&graph, &self.coords(), co)
+    }
+
+    pub fn write_metis<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        Graph::write_metis(&self.graph(), writer)
+    }
+}
+
+impl Preprocessor {
+    /// Uses a DIMACS graph and its coordinates as input instead of OSM files,
+    /// build_graph then minimizes it and picks landmarks as usual
+    pub fn get_graph_from_dimacs(&mut self, gr: &str, co: &str) -> Result<(), GraphFileError> {
+        let open = |filename: &str| -> Result<_, GraphFileError> {
+            Ok(std::io::BufReader::new(std::fs::File::open(filename)?))
+        };
+        let graph = Graph::read_dimacs(open(gr)?)?;
+        let nodes = Graph::read_dimacs_coordinates(open(co)?)?;
+        if let Some(node) = graph.keys().find(|node| !nodes.contains_key(node)) {
+            return Err(GraphFileError::Decode(format!(
+                "node {} has no coordinates in {}",
+                node.0, co
+            )));
+        }
+        self.source_files = vec![source_file_name(gr), source_file_name(co)];
+        self.nodes = nodes;
+        self.compressed_graph = Some(graph);
+        Ok(())
+    }
+}
+
+// TESTS
+#[cfg(test)]
+const TRIANGLE_GR: &str = "c triangle with a oneway edge
+p sp 3 5
+a 1 2 10
+a 2 1 10
+a 2 3 20
+a 3 2 20
+a 3 1 5
+";
+
+#[cfg(test)]
+const TRIANGLE_CO: &str = "p aux sp co 3
+v 1 10000000 55000000
+v 2 10001000 55000000
+v 3 10000000 55001000
+";
+
+#[test]
+fn reads_dimacs_graph_and_coordinates() {
+    let graph = Graph::read_dimacs(TRIANGLE_GR.as_bytes()).unwrap();
+    assert_eq!(3, graph.len());
+    let edge = graph[&NodeId(3)][1];
+    assert_eq!((NodeId(1), 5.0), (edge.node, edge.cost));
+    let nodes = Graph::read_dimacs_coordinates(TRIANGLE_CO.as_bytes()).unwrap();
+    assert_eq!(55.001, nodes[&NodeId(3)].lat);
+    assert_eq!(10.001, nodes[&NodeId(2)].lon);
+}
+
+#[test]
+fn dimacs_round_trips() {
+    let graph = Graph::read_dimacs(TRIANGLE_GR.as_bytes()).unwrap();
+    let nodes = Graph::read_dimacs_coordinates(TRIANGLE_CO.as_bytes()).unwrap();
+    let mut gr = Vec::new();
+    let mut co = Vec::new();
+    Graph::write_dimacs(&graph, &mut gr).unwrap();
+    Graph::write_dimacs_coordinates(&graph, &nodes, &mut co).unwrap();
+    let arcs = |graph: &HashMap<NodeId, Vec<Edge>>| {
+        let mut arcs: Vec<(NodeId, NodeId, f32)> = graph
+            .iter()
+            .flat_map(|(node, edges)| edges.iter().map(|edge| (*node, edge.node, edge.cost)))
+            .collect();
+        arcs.sort_by_key(|(from, to, _)| (*from, *to));
+        arcs
+    };
+    assert_eq!(
+        arcs(&graph),
+        arcs(&Graph::read_dimacs(gr.as_slice()).unwrap())
+    );
+    let coords = |nodes: HashMap<NodeId, Coord>| {
+        let mut coords: Vec<(NodeId, f64, f64)> = nodes
+            .into_iter()
+            .map(|(node, coord)| (node, coord.lat, coord.lon))
+            .collect();
+        coords.sort_by_key(|(node, _, _)| *node);
+        coords
+    };
+    let read_back = Graph::read_dimacs_coordinates(co.as_slice()).unwrap();
+    assert_eq!(coords(nodes), coords(read_back));
+}
+
+#[test]
+fn metis_lists_undirected_neighbours() {
+    let graph = Graph::read_dimacs(TRIANGLE_GR.as_bytes()).unwrap();
+    let mut metis = Vec::new();
+    Graph::write_metis(&graph, &mut metis).unwrap();
+    // 3 -> 1 is oneway but METIS still lists it from both ends
+    assert_eq!(
+        "3 3 001\n2 10 3 5\n1 10 3 20\n1 5 2 20\n",
+        String::from_utf8(metis).unwrap()
+    );
+}
+
+#[test]
+fn rejects_malformed_dimacs() {
+    let result = Graph::read_dimacs("p sp 2 1\na 1 x 3\n".as_bytes());
+    assert!(matches!(result, Err(GraphFileError::Decode(e)) if e.starts_with("line 2")));
+}
+
+#[test]
+fn rejects_dimacs_arcs_that_cannot_be_searched() {
+    for arc in [
+        "a 1 2 -3",
+        "a 1 2 nan",
+        "a 1 2 inf",
+        "a 0 2 3",
+        "a 1 3 3",
+        "a -1 2 3",
+    ] {
+        let text = format!("c comment\np sp 2 1\n{}\n", arc);
+        let result = Graph::read_dimacs(text.as_bytes());
+        assert!(
+            matches!(&result, Err(GraphFileError::Decode(e)) if e.starts_with("line 3")),
+            "{} gave {:?}",
+            arc,
+            result
+        );
+    }
+    let result = Graph::read_dimacs("a 1 2 3\np sp 2 1\n".as_bytes());
+    assert!(matches!(result, Err(GraphFileError::Decode(e)) if e.starts_with("line 1")));
+    assert!(Graph::read_dimacs("p sp 2 1\na 1 2 0\n".as_bytes()).is_ok());
+}
+
+#[test]
+fn preprocesses_dimacs_input() {
+    let dir = tempfile::tempdir().unwrap();
+    let gr = dir.path().join("triangle.gr");
+    let co = dir.path().join("triangle.co");
+    std::fs::write(&gr, TRIANGLE_GR).unwrap();
+    std::fs::write(&co, TRIANGLE_CO).unwrap();
+    let mut preprocessor = Preprocessor::new();
+    preprocessor
+        .get_graph_from_dimacs(gr.to_str().unwrap(), co.to_str().unwrap())
+        .unwrap();
+    let (graph, bi_graph, landmarks) = preprocessor.build_graph();
+    let projected_points = preprocessor.project_nodes_to_2d();
+    let full_graph = preprocessor.build_full_graph(&graph, &bi_graph, landmarks, &projected_points);
+    assert_eq!(
+        full_graph.header.node_count as usize,
+        full_graph.nodes.len()
+    );
+    assert_eq!(
+        vec!["triangle.gr", "triangle.co"],
+        full_graph.header.source_files
+    );
+}
@@ -1,9 +1,9 @@
 use osmpbfreader::NodeId;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::hash::{Hash, Hasher};
 
-#[derive(Clone, Debug, Serialize, Copy)]
+#[derive(Clone, Debug, Serialize, Deserialize, Copy)]
 pub struct Edge {
     pub node: NodeId,
     pub cost: f32,
@@ -26,7 +26,7 @@ impl Ord for Edge {
 
 impl PartialOrd for Edge {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        other.cost.partial_cmp(&self.cost)
+        Some(self.cmp(other))
     }
 }
 
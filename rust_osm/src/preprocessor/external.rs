@@ -0,0 +1,662 @@
+use crate::preprocessor::coord::Coord;
+use crate::preprocessor::edge::Edge;
+use crate::preprocessor::preprocessor::*;
+
+use memmap2::Mmap;
+use osmpbfreader::{NodeId, WayId};
+use rayon::slice::ParallelSliceMut;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+
+/*
+   Disk-backed preprocessing for inputs that do not fit in memory.
+
+   Instead of holding every road and every referenced node in memory, the roads are spooled to a
+   temporary file and the referenced node ids and node coordinates are sorted externally into
+   memory-mapped arrays. The roads are then streamed once more to emit edges between junctions
+   (nodes used by more than one road, or road ends), which is the same contraction
+   Graph::minimize_graph does for intermediate nodes. Only this much smaller graph and the
+   coordinates of its nodes are loaded into memory before the usual minimization and landmarks.
+*/
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MemoryBudget {
+    /// Approximate number of bytes each external sort may buffer before spilling a sorted run
+    pub bytes: usize,
+    pub temp_dir: PathBuf,
+}
+
+impl MemoryBudget {
+    pub fn new(bytes: usize) -> Self {
+        MemoryBudget {
+            bytes,
+            temp_dir: std::env::temp_dir(),
+        }
+    }
+
+    fn temp_file(&self) -> File {
+        tempfile::tempfile_in(&self.temp_dir).unwrap_or_else(|e| {
+            panic!(
+                "Could not create temporary file in {:?}: {}",
+                self.temp_dir, e
+            )
+        })
+    }
+}
+
+/// Fixed size record that can be written to and read from temporary files
+pub trait Record: Copy + Ord + Send {
+    const SIZE: usize;
+    fn write_to(&self, buf: &mut [u8]);
+    fn read_from(buf: &[u8]) -> Self;
+}
+
+fn read_i64(buf: &[u8]) -> i64 {
+    i64::from_le_bytes(buf[..8].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes(buf[..4].try_into().unwrap())
+}
+
+impl Record for NodeId {
+    const SIZE: usize = 8;
+    fn write_to(&self, buf: &mut [u8]) {
+        buf[..8].copy_from_slice(&self.0.to_le_bytes());
+    }
+    fn read_from(buf: &[u8]) -> Self {
+        NodeId(read_i64(buf))
+    }
+}
+
+/// Node coordinates in decimicro degrees, as stored in the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NodeRecord {
+    pub id: NodeId,
+    pub decimicro_lat: i32,
+    pub decimicro_lon: i32,
+}
+
+impl NodeRecord {
+    pub fn coord(&self) -> Coord {
+        // Same conversion as osmpbfreader::Node::lat/lon, so coordinates match the in-memory path
+        Coord {
+            lat: self.decimicro_lat as f64 * 1e-7,
+            lon: self.decimicro_lon as f64 * 1e-7,
+        }
+    }
+}
+
+impl Record for NodeRecord {
+    const SIZE: usize = 16;
+    fn write_to(&self, buf: &mut [u8]) {
+        buf[..8].copy_from_slice(&self.id.0.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.decimicro_lat.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.decimicro_lon.to_le_bytes());
+    }
+    fn read_from(buf: &[u8]) -> Self {
+        NodeRecord {
+            id: NodeId(read_i64(buf)),
+            decimicro_lat: read_u32(&buf[8..]) as i32,
+            decimicro_lon: read_u32(&buf[12..]) as i32,
+        }
+    }
+}
+
+/// How many times a node is used by the roads, counting road ends twice
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NodeUse {
+    pub id: NodeId,
+    pub uses: u32,
+}
+
+impl Record for NodeUse {
+    const SIZE: usize = 12;
+    fn write_to(&self, buf: &mut [u8]) {
+        buf[..8].copy_from_slice(&self.id.0.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.uses.to_le_bytes());
+    }
+    fn read_from(buf: &[u8]) -> Self {
+        NodeUse {
+            id: NodeId(read_i64(buf)),
+            uses: read_u32(&buf[8..]),
+        }
+    }
+}
+
+/// Edge between two junctions. The cost is kept as bits so edges sort by (from, to, cost),
+/// which works since the costs are never negative
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EdgeRecord {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub cost_bits: u32,
+}
+
+impl EdgeRecord {
+    pub fn new(from: NodeId, to: NodeId, cost: f32) -> Self {
+        EdgeRecord {
+            from,
+            to,
+            cost_bits: cost.to_bits(),
+        }
+    }
+}
+
+impl Record for EdgeRecord {
+    const SIZE: usize = 20;
+    fn write_to(&self, buf: &mut [u8]) {
+        buf[..8].copy_from_slice(&self.from.0.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.to.0.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.cost_bits.to_le_bytes());
+    }
+    fn read_from(buf: &[u8]) -> Self {
+        EdgeRecord {
+            from: NodeId(read_i64(buf)),
+            to: NodeId(read_i64(&buf[8..])),
+            cost_bits: read_u32(&buf[16..]),
+        }
+    }
+}
+
+fn write_records<T: Record>(file: File, records: impl IntoIterator<Item = T>) -> File {
+    let mut writer = BufWriter::new(file);
+    let mut buf = vec![0u8; T::SIZE];
+    for record in records {
+        record.write_to(&mut buf);
+        writer.write_all(&buf).unwrap();
+    }
+    let mut file = writer.into_inner().unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file
+}
+
+/// How many runs are merged at once, which bounds the number of open temporary files
+const MERGE_FAN_IN: usize = 64;
+
+/// Sorts more records than fit in the memory budget by spilling sorted runs to temporary files
+/// and merging them when iterated. Every MERGE_FAN_IN runs of the same size are merged into one
+/// bigger run as they are spilled
+pub struct ExternalSorter<T: Record> {
+    buffer: Vec<T>,
+    capacity: usize,
+    /// The runs with how many merges made them, in the order they were made
+    runs: Vec<(File, u32)>,
+    budget: MemoryBudget,
+}
+
+impl<T: Record> ExternalSorter<T> {
+    pub fn new(budget: &MemoryBudget) -> Self {
+        let capacity = (budget.bytes / std::mem::size_of::<T>().max(1)).max(1);
+        ExternalSorter {
+            buffer: Vec::new(),
+            capacity,
+            runs: Vec::new(),
+            budget: budget.clone(),
+        }
+    }
+
+    pub fn push(&mut self, record: T) {
+        self.buffer.push(record);
+        if self.buffer.len() >= self.capacity {
+            self.spill();
+        }
+    }
+
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn spill(&mut self) {
+        self.buffer.par_sort_unstable();
+        let file = write_records(self.budget.temp_file(), self.buffer.iter().copied());
+        self.runs.push((file, 0));
+        self.buffer.clear();
+        while self.runs.len() >= MERGE_FAN_IN {
+            let last = self.runs.len() - MERGE_FAN_IN;
+            let level = self.runs[last].1;
+            if self.runs[last..].iter().any(|(_, other)| *other != level) {
+                break;
+            }
+            let runs = self.runs.drain(last..).map(|(file, _)| file).collect();
+            let file = write_records(self.budget.temp_file(), merge_runs::<T>(runs));
+            self.runs.push((file, level + 1));
+        }
+    }
+
+    pub fn into_sorted_iter(mut self) -> SortedIter<T> {
+        if self.runs.is_empty() {
+            self.buffer.par_sort_unstable();
+            return SortedIter::Memory(self.buffer.into_iter());
+        }
+        if !self.buffer.is_empty() {
+            self.spill();
+        }
+        merge_runs(self.runs.into_iter().map(|(file, _)| file).collect())
+    }
+}
+
+fn merge_runs<T: Record>(runs: Vec<File>) -> SortedIter<T> {
+    let mut readers: Vec<BufReader<File>> = runs.into_iter().map(BufReader::new).collect();
+    let mut heap = BinaryHeap::new();
+    for (run, reader) in readers.iter_mut().enumerate() {
+        if let Some(record) = read_record(reader) {
+            heap.push(Reverse((record, run)));
+        }
+    }
+    SortedIter::Merge { readers, heap }
+}
+
+fn read_record<T: Record>(reader: &mut BufReader<File>) -> Option<T> {
+    let mut buf = [0u8; 32];
+    reader.read_exact(&mut buf[..T::SIZE]).ok()?;
+    Some(T::read_from(&buf[..T::SIZE]))
+}
+
+pub enum SortedIter<T: Record> {
+    Memory(std::vec::IntoIter<T>),
+    Merge {
+        readers: Vec<BufReader<File>>,
+        heap: BinaryHeap<Reverse<(T, usize)>>,
+    },
+}
+
+impl<T: Record> Iterator for SortedIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match self {
+            SortedIter::Memory(it) => it.next(),
+            SortedIter::Merge { readers, heap } => {
+                let Reverse((record, run)) = heap.pop()?;
+                if let Some(next) = read_record(&mut readers[run]) {
+                    heap.push(Reverse((next, run)));
+                }
+                Some(record)
+            }
+        }
+    }
+}
+
+/// Sorted records in a memory-mapped temporary file, looked up with binary search
+pub struct DiskArray<T: Record> {
+    mmap: Option<Mmap>,
+    len: usize,
+    _record: std::marker::PhantomData<T>,
+}
+
+impl<T: Record> DiskArray<T> {
+    pub fn from_sorted<I: Iterator<Item = T>>(records: I, budget: &MemoryBudget) -> Self {
+        let mut writer = BufWriter::new(budget.temp_file());
+        let mut buf = vec![0u8; T::SIZE];
+        let mut len = 0;
+        for record in records {
+            record.write_to(&mut buf);
+            writer.write_all(&buf).unwrap();
+            len += 1;
+        }
+        let file = writer.into_inner().unwrap();
+        // Mapping an empty file is an error on some platforms
+        let mmap = (len > 0).then(|| unsafe { Mmap::map(&file).unwrap() });
+        DiskArray {
+            mmap,
+            len,
+            _record: std::marker::PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> T {
+        let bytes = &self.mmap.as_ref().unwrap()[index * T::SIZE..(index + 1) * T::SIZE];
+        T::read_from(bytes)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        (0..self.len).map(|i| self.get(i))
+    }
+
+    pub fn find<K: Ord>(&self, key: &K, key_of: impl Fn(&T) -> K) -> Option<T> {
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let mid = (low + high) / 2;
+            let record = self.get(mid);
+            match key_of(&record).cmp(key) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(record),
+            }
+        }
+        None
+    }
+}
+
+/// Roads written sequentially to a temporary file as
+/// way id, direction, number of nodes and the node ids
+struct RoadSpool {
+    writer: BufWriter<File>,
+}
+
+impl RoadSpool {
+    fn new(budget: &MemoryBudget) -> Self {
+        RoadSpool {
+            writer: BufWriter::new(budget.temp_file()),
+        }
+    }
+
+    fn push(&mut self, road: &Road) {
+        self.writer.write_all(&road.id.0.to_le_bytes()).unwrap();
+        let twoway = (road.direction == CarDirection::Twoway) as u8;
+        self.writer.write_all(&[twoway]).unwrap();
+        self.writer
+            .write_all(&(road.node_refs.len() as u32).to_le_bytes())
+            .unwrap();
+        for node in &road.node_refs {
+            self.writer.write_all(&node.0.to_le_bytes()).unwrap();
+        }
+    }
+
+    fn into_roads(self) -> impl Iterator<Item = Road> {
+        let mut file = self.writer.into_inner().unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader = BufReader::new(file);
+        std::iter::from_fn(move || {
+            let mut header = [0u8; 13];
+            reader.read_exact(&mut header).ok()?;
+            let len = read_u32(&header[9..]) as usize;
+            let mut refs = vec![0u8; len * 8];
+            reader.read_exact(&mut refs).unwrap();
+            Some(Road {
+                id: WayId(read_i64(&header)),
+                node_refs: refs
+                    .chunks(8)
+                    .map(|chunk| NodeId(read_i64(chunk)))
+                    .collect(),
+                direction: if header[8] == 1 {
+                    CarDirection::Twoway
+                } else {
+                    CarDirection::Forward
+                },
+            })
+        })
+    }
+}
+
+impl Preprocessor {
+    pub fn get_compressed_graph_external(
+        &mut self,
+        filenames: &[&str],
+        seen_ways: &mut HashSet<WayId>,
+        budget: &MemoryBudget,
+    ) -> IngestionReport {
+        let time = std::time::Instant::now();
+        let blacklist = create_blacklist();
+        let mut spool = RoadSpool::new(budget);
+        let mut refs: ExternalSorter<NodeId> = ExternalSorter::new(budget);
+        for filename in filenames {
+            Preprocessor::read_objects(filename, |obj| {
+                if let osmpbfreader::OsmObj::Way(way) = obj {
+                    if seen_ways.contains(&way.id) {
+                        return ControlFlow::Continue(());
+                    }
+                    if let Some(road) = self.to_road(&blacklist, way) {
+                        seen_ways.insert(road.id);
+                        for node in &road.node_refs {
+                            refs.push(*node);
+                        }
+                        // Road ends are always junctions, so they count twice
+                        if let (Some(first), Some(last)) =
+                            (road.node_refs.first(), road.node_refs.last())
+                        {
+                            refs.push(*first);
+                            refs.push(*last);
+                        }
+                        spool.push(&road);
+                    }
+                }
+                ControlFlow::Continue(())
+            });
+        }
+        let runs = refs.runs();
+        let uses = DiskArray::from_sorted(count_uses(refs.into_sorted_iter()), budget);
+        println!(
+            "Spooled roads using {} nodes ({} sorted runs) in {:?}",
+            uses.len(),
+            runs,
+            time.elapsed()
+        );
+
+        // Every node is sorted and then merged with the sorted uses, instead of looking up the
+        // uses of each node as it is read
+        let mut node_sorter: ExternalSorter<NodeRecord> = ExternalSorter::new(budget);
+        for filename in filenames {
+            Preprocessor::read_objects(filename, |obj| {
+                if let osmpbfreader::OsmObj::Node(node) = obj {
+                    node_sorter.push(NodeRecord {
+                        id: node.id,
+                        decimicro_lat: node.decimicro_lat,
+                        decimicro_lon: node.decimicro_lon,
+                    });
+                }
+                ControlFlow::Continue(())
+            });
+        }
+        let mut last = None;
+        let mut used = uses.iter().peekable();
+        let nodes = DiskArray::from_sorted(
+            node_sorter.into_sorted_iter().filter(|node| {
+                if last.replace(node.id) == Some(node.id) {
+                    return false;
+                }
+                while used.next_if(|u| u.id < node.id).is_some() {}
+                used.peek().is_some_and(|u| u.id == node.id)
+            }),
+            budget,
+        );
+
+        let (edges, report) = compress_roads(spool.into_roads(), &uses, &nodes, budget);
+        let mut graph: HashMap<NodeId, Vec<Edge>> = HashMap::new();
+        let mut previous: Option<(NodeId, NodeId)> = None;
+        for edge in edges.into_sorted_iter() {
+            // Edges are sorted by cost within (from, to), so the first one is the cheapest.
+            // Closed roads with a single junction give self loops, which build_graph drops as well
+            if previous == Some((edge.from, edge.to)) || edge.from == edge.to {
+                continue;
+            }
+            previous = Some((edge.from, edge.to));
+            graph.entry(edge.to).or_default();
+            graph
+                .entry(edge.from)
+                .or_default()
+                .push(Edge::new(edge.to, f32::from_bits(edge.cost_bits)));
+        }
+        for node in nodes.iter() {
+            if graph.contains_key(&node.id) {
+                self.nodes.insert(node.id, node.coord());
+            }
+        }
+        println!(
+            "Compressed graph has {} nodes, built in {:?}",
+            graph.len(),
+            time.elapsed()
+        );
+        self.compressed_graph = Some(graph);
+        if !report.is_complete() {
+            println!(
+                "{} referenced nodes were missing from the input, affecting {} ways",
+                report.missing_nodes.len(),
+                report.ways_with_missing_nodes.len()
+            );
+        }
+        report
+    }
+}
+
+fn count_uses(sorted_refs: impl Iterator<Item = NodeId>) -> impl Iterator<Item = NodeUse> {
+    let mut sorted_refs = sorted_refs.peekable();
+    std::iter::from_fn(move || {
+        let id = sorted_refs.next()?;
+        let mut uses = 1;
+        while sorted_refs.next_if_eq(&id).is_some() {
+            uses += 1;
+        }
+        Some(NodeUse { id, uses })
+    })
+}
+
+fn compress_roads(
+    roads: impl Iterator<Item = Road>,
+    uses: &DiskArray<NodeUse>,
+    nodes: &DiskArray<NodeRecord>,
+    budget: &MemoryBudget,
+) -> (ExternalSorter<EdgeRecord>, IngestionReport) {
+    /*
+       Walks every road and emits one edge per stretch between junctions, summing the
+       segment distances. Roads are split at missing nodes like Preprocessor::remove_missing_nodes,
+       which makes the nodes next to the gap road ends as well. Every part ends an edge at both
+       of its ends, so they need not be remembered as junctions.
+    */
+    let mut edges = ExternalSorter::new(budget);
+    let mut report = IngestionReport::default();
+    let is_junction = |node: &NodeId| uses.find(node, |u| u.id).is_some_and(|u| u.uses >= 2);
+    for road in roads {
+        let coords: Vec<Option<Coord>> = road
+            .node_refs
+            .iter()
+            .map(|node| nodes.find(node, |n| n.id).map(|n| n.coord()))
+            .collect();
+        if coords.iter().any(Option::is_none) {
+            report.ways_with_missing_nodes.push(road.id);
+        }
+        let mut start = 0;
+        while start < road.node_refs.len() {
+            if coords[start].is_none() {
+                report.missing_nodes.insert(road.node_refs[start]);
+                start += 1;
+                continue;
+            }
+            let end = (start..road.node_refs.len())
+                .find(|i| coords[*i].is_none())
+                .unwrap_or(road.node_refs.len());
+            let part = &road.node_refs[start..end];
+            if part.len() >= 2 {
+                let mut from = 0;
+                let mut cost = 0.0;
+                for i in 1..part.len() {
+                    cost += coords[start + i - 1]
+                        .unwrap()
+                        .distance_to(coords[start + i].unwrap());
+                    if i == part.len() - 1 || is_junction(&part[i]) {
+                        edges.push(EdgeRecord::new(part[from], part[i], cost));
+                        if road.direction == CarDirection::Twoway {
+                            edges.push(EdgeRecord::new(part[i], part[from], cost));
+                        }
+                        from = i;
+                        cost = 0.0;
+                    }
+                }
+            }
+            start = end;
+        }
+    }
+    (edges, report)
+}
+
+// TESTS
+#[cfg(test)]
+fn sorted_edges(graph: &HashMap<NodeId, Vec<Edge>>) -> Vec<(NodeId, Vec<(NodeId, f32)>)> {
+    let mut nodes: Vec<_> = graph
+        .iter()
+        .map(|(node, edges)| {
+            (
+                *node,
+                edges.iter().map(|edge| (edge.node, edge.cost)).collect(),
+            )
+        })
+        .collect();
+    nodes.sort_by_key(|(node, _)| *node);
+    nodes
+}
+
+#[test]
+fn external_sort_merges_spilled_runs() {
+    let budget = MemoryBudget::new(64);
+    let mut sorter = ExternalSorter::new(&budget);
+    let ids: Vec<i64> = (0..1000).map(|i| (i * 7919) % 1000).collect();
+    for id in &ids {
+        sorter.push(NodeId(*id));
+    }
+    // 125 runs were spilled, of which the first MERGE_FAN_IN were merged into one
+    assert_eq!(125 - MERGE_FAN_IN + 1, sorter.runs());
+    let sorted: Vec<i64> = sorter.into_sorted_iter().map(|id| id.0).collect();
+    let mut expected = ids.clone();
+    expected.sort();
+    assert_eq!(expected, sorted);
+}
+
+#[test]
+fn disk_array_finds_records() {
+    let budget = MemoryBudget::new(1024);
+    let records = (0..100).map(|i| NodeUse {
+        id: NodeId(i * 2),
+        uses: i as u32,
+    });
+    let array = DiskArray::from_sorted(records, &budget);
+    assert_eq!(100, array.len());
+    assert_eq!(Some(21), array.find(&NodeId(42), |u| u.id).map(|u| u.uses));
+    assert!(array.find(&NodeId(43), |u| u.id).is_none());
+    let empty: DiskArray<NodeUse> = DiskArray::from_sorted(std::iter::empty(), &budget);
+    assert!(empty.find(&NodeId(0), |u| u.id).is_none());
+}
+
+#[test]
+fn external_matches_in_memory_preprocessing() {
+    for filename in [
+        "src/test_data/minimize_example.osm.testpbf",
+        "src/test_data/ribe_slice.osm.testpbf",
+        "src/test_data/andorra.osm.testpbf",
+        "src/test_data/missing_node.osm",
+    ] {
+        let mut in_memory = Preprocessor::new();
+        in_memory.get_roads_and_nodes(filename);
+        let (graph, _, _) = in_memory.build_graph();
+
+        let read_external = || {
+            let mut external = Preprocessor::new();
+            // A tiny budget forces every sort to spill to several runs
+            external.ingestion = Ingestion::External(MemoryBudget::new(4096));
+            let external_report = external.get_roads_and_nodes(filename);
+            assert!(external.roads.is_empty());
+            assert_eq!(
+                filename.ends_with("missing_node.osm"),
+                !external_report.is_complete()
+            );
+            sorted_edges(&external.build_graph().0)
+        };
+        let external_graph = read_external();
+        assert_eq!(external_graph, read_external(), "{}", filename);
+
+        // The same edges, whose costs add up the same distances, but grouped differently
+        let graph = sorted_edges(&graph);
+        assert_eq!(graph.len(), external_graph.len(), "{}", filename);
+        for ((node, edges), (external_node, external_edges)) in graph.iter().zip(&external_graph) {
+            assert_eq!(node, external_node, "{}", filename);
+            let targets: Vec<NodeId> = edges.iter().map(|(to, _)| *to).collect();
+            let external_targets: Vec<NodeId> = external_edges.iter().map(|(to, _)| *to).collect();
+            assert_eq!(targets, external_targets, "{}", filename);
+            for ((_, cost), (_, external_cost)) in edges.iter().zip(external_edges) {
+                assert!((cost - external_cost).abs() <= cost * 1e-6, "{}", filename);
+            }
+        }
+    }
+}
@@ -0,0 +1,451 @@
+use crate::preprocessor::coord::Coord;
+use crate::preprocessor::graph_file::{GraphFileError, GraphHeader};
+use crate::preprocessor::preprocessor::FullGraph;
+
+use memmap2::Mmap;
+use std::io::Write;
+use std::ops::Range;
+
+/*
+   Flat graph format that can be memory-mapped and used without parsing.
+
+   magic       8 bytes  "OSMGFLAT"
+   version     u32      little endian
+   header_len  u32      little endian
+   header      header_len bytes of MessagePack encoding a GraphHeader, padded to 8 bytes
+   counts      [u64; 4] nodes, edges, bi_edges, landmarks
+   then each array below, every one starting at a multiple of 8 bytes:
+   coords              [f64; 2 * nodes]          lat, lon
+   points              [f32; 2 * nodes]          projected x, y
+   offsets             [u64; nodes + 1]          edges of node i are offsets[i]..offsets[i + 1]
+   targets             [u32; edges]
+   weights             [f32; edges]
+   bi_offsets          [u64; nodes + 1]
+   bi_targets          [u32; bi_edges]
+   bi_weights          [f32; bi_edges]
+   landmark_nodes      [u32; landmarks]
+   landmark_distances  [f32; landmarks * nodes]  row per landmark
+   landmark_bi_distances [f32; landmarks * nodes]
+
+   All numbers are little endian, and node ids are the rewritten ids 0..nodes.
+*/
+pub const FLAT_MAGIC: &[u8; 8] = b"OSMGFLAT";
+pub const FLAT_VERSION: u32 = 1;
+
+struct AlignedWriter<'a, W: Write> {
+    writer: &'a mut W,
+    position: usize,
+}
+
+impl<'a, W: Write> AlignedWriter<'a, W> {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.position += bytes.len();
+        self.writer.write_all(bytes)
+    }
+
+    fn pad(&mut self) -> std::io::Result<()> {
+        let padding = (8 - self.position % 8) % 8;
+        self.write(&[0u8; 8][..padding])
+    }
+
+    fn array<T: bytemuck::Pod>(&mut self, values: &[T]) -> std::io::Result<()> {
+        self.write(bytemuck::cast_slice(values))?;
+        self.pad()
+    }
+}
+
+impl FullGraph {
+    pub fn write_flat<W: Write>(&self, writer: &mut W) -> Result<(), GraphFileError> {
+        let n = self.nodes.len();
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.node_id.0 != index as i64 {
+                return Err(GraphFileError::Encode(format!(
+                    "node ids must be 0..{} in order, found {} at {}",
+                    n, node.node_id.0, index
+                )));
+            }
+        }
+        for landmark in &self.landmarks {
+            if landmark.distances.len() != n || landmark.bi_distances.len() != n {
+                return Err(GraphFileError::Encode(format!(
+                    "landmark {} must have {} distances each way, found {} and {}",
+                    landmark.node_id.0,
+                    n,
+                    landmark.distances.len(),
+                    landmark.bi_distances.len()
+                )));
+            }
+        }
+        let header = rmp_serde::to_vec(&self.header).unwrap();
+        let mut out = AlignedWriter {
+            writer,
+            position: 0,
+        };
+        out.write(FLAT_MAGIC)?;
+        out.write(&FLAT_VERSION.to_le_bytes())?;
+        out.write(&(header.len() as u32).to_le_bytes())?;
+        out.write(&header)?;
+        out.pad()?;
+
+        let neighbours = |bi: bool| {
+            let mut offsets: Vec<u64> = vec![0];
+            let mut targets: Vec<u32> = Vec::new();
+            let mut weights: Vec<f32> = Vec::new();
+            for node in &self.nodes {
+                let edges = if bi {
+                    &node.bi_neighbours
+                } else {
+                    &node.neighbours
+                };
+                targets.extend(edges.iter().map(|(target, _)| target.0 as u32));
+                weights.extend(edges.iter().map(|(_, cost)| *cost));
+                offsets.push(targets.len() as u64);
+            }
+            (offsets, targets, weights)
+        };
+        let (offsets, targets, weights) = neighbours(false);
+        let (bi_offsets, bi_targets, bi_weights) = neighbours(true);
+
+        let counts = [
+            n as u64,
+            targets.len() as u64,
+            bi_targets.len() as u64,
+            self.landmarks.len() as u64,
+        ];
+        out.array(&counts)?;
+        let coords: Vec<f64> = self
+            .nodes
+            .iter()
+            .flat_map(|node| [node.lat, node.lon])
+            .collect();
+        out.array(&coords)?;
+        let points: Vec<f32> = self
+            .nodes
+            .iter()
+            .flat_map(|node| [node.x, node.y])
+            .collect();
+        out.array(&points)?;
+        out.array(&offsets)?;
+        out.array(&targets)?;
+        out.array(&weights)?;
+        out.array(&bi_offsets)?;
+        out.array(&bi_targets)?;
+        out.array(&bi_weights)?;
+        let landmark_nodes: Vec<u32> = self.landmarks.iter().map(|l| l.node_id.0 as u32).collect();
+        out.array(&landmark_nodes)?;
+        for landmark in &self.landmarks {
+            out.write(bytemuck::cast_slice(&landmark.distances))?;
+        }
+        out.pad()?;
+        for landmark in &self.landmarks {
+            out.write(bytemuck::cast_slice(&landmark.bi_distances))?;
+        }
+        out.pad()?;
+        Ok(())
+    }
+}
+
+/// A flat graph file mapped into memory. The accessors return slices straight into the mapping
+pub struct MappedGraph {
+    mmap: Mmap,
+    pub header: GraphHeader,
+    node_count: usize,
+    landmark_count: usize,
+    coords: Range<usize>,
+    points: Range<usize>,
+    offsets: Range<usize>,
+    targets: Range<usize>,
+    weights: Range<usize>,
+    bi_offsets: Range<usize>,
+    bi_targets: Range<usize>,
+    bi_weights: Range<usize>,
+    landmark_nodes: Range<usize>,
+    landmark_distances: Range<usize>,
+    landmark_bi_distances: Range<usize>,
+}
+
+fn aligned(position: usize) -> usize {
+    position.div_ceil(8) * 8
+}
+
+impl MappedGraph {
+    pub fn open(filename: &str) -> Result<MappedGraph, GraphFileError> {
+        let file = std::fs::File::open(filename)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        MappedGraph::from_mmap(mmap)
+    }
+
+    fn from_mmap(mmap: Mmap) -> Result<MappedGraph, GraphFileError> {
+        let truncated = || GraphFileError::Decode("flat graph file is truncated".to_owned());
+        let corrupt = |what: &str| GraphFileError::Decode(format!("flat graph file has {}", what));
+        if mmap.len() < 16 || &mmap[..8] != FLAT_MAGIC {
+            return Err(GraphFileError::NotAGraphFile);
+        }
+        let version = u32::from_le_bytes(mmap[8..12].try_into().unwrap());
+        if version != FLAT_VERSION {
+            return Err(GraphFileError::UnsupportedVersion(version));
+        }
+        let header_len = u32::from_le_bytes(mmap[12..16].try_into().unwrap()) as usize;
+        let header_end = 16usize.checked_add(header_len).ok_or_else(truncated)?;
+        let header_bytes = mmap.get(16..header_end).ok_or_else(truncated)?;
+        let header: GraphHeader = rmp_serde::from_slice(header_bytes)
+            .map_err(|e| GraphFileError::Decode(e.to_string()))?;
+
+        let mut position = aligned(header_end);
+        let counts: &[u64] =
+            bytemuck::try_cast_slice(mmap.get(position..position + 32).ok_or_else(truncated)?)
+                .map_err(|e| GraphFileError::Decode(e.to_string()))?;
+        let mut sizes = [0; 4];
+        for (size, count) in sizes.iter_mut().zip(counts) {
+            *size = usize::try_from(*count).map_err(|_| truncated())?;
+        }
+        let [n, edges, bi_edges, landmarks] = sizes;
+        position += 32;
+        // The length in bytes of every section, checked so that corrupt counts cannot overflow
+        let lengths = (|| {
+            let offsets = n.checked_add(1)?.checked_mul(8)?;
+            let distances = landmarks.checked_mul(n)?.checked_mul(4)?;
+            Some([
+                n.checked_mul(16)?,
+                n.checked_mul(8)?,
+                offsets,
+                edges.checked_mul(4)?,
+                edges.checked_mul(4)?,
+                offsets,
+                bi_edges.checked_mul(4)?,
+                bi_edges.checked_mul(4)?,
+                landmarks.checked_mul(4)?,
+                distances,
+                distances,
+            ])
+        })()
+        .ok_or_else(truncated)?;
+        let mut sections = Vec::with_capacity(lengths.len());
+        for length in lengths {
+            let end = position.checked_add(length);
+            let end = end.filter(|end| *end <= mmap.len()).ok_or_else(truncated)?;
+            sections.push(position..end);
+            position = aligned(end);
+        }
+        let [coords, points, offsets, targets, weights, bi_offsets, bi_targets, bi_weights, landmark_nodes, landmark_distances, landmark_bi_distances]: [Range<usize>; 11] =
+            sections.try_into().unwrap();
+        let graph = MappedGraph {
+            header,
+            node_count: n,
+            landmark_count: landmarks,
+            coords,
+            points,
+            offsets,
+            targets,
+            weights,
+            bi_offsets,
+            bi_targets,
+            bi_weights,
+            landmark_nodes,
+            landmark_distances,
+            landmark_bi_distances,
+            mmap,
+        };
+
+        // Offsets and node ids are used as indices, so they must stay within their sections
+        for (offsets, edge_count) in [(&graph.offsets, edges), (&graph.bi_offsets, bi_edges)] {
+            let offsets: &[u64] = graph.slice(offsets);
+            let ordered = offsets.windows(2).all(|pair| pair[0] <= pair[1]);
+            if offsets[0] != 0 || offsets[n] != edge_count as u64 || !ordered {
+                return Err(corrupt("edge offsets out of order or out of range"));
+            }
+        }
+        let nodes = [&graph.targets, &graph.bi_targets, &graph.landmark_nodes];
+        for section in nodes {
+            let ids: &[u32] = graph.slice(section);
+            if ids.iter().any(|id| *id as usize >= n) {
+                return Err(corrupt("node ids out of range"));
+            }
+        }
+        Ok(graph)
+    }
+
+    fn slice<T: bytemuck::Pod>(&self, range: &Range<usize>) -> &[T] {
+        // Every section starts at a multiple of 8 from the page aligned mapping
+        bytemuck::cast_slice(&self.mmap[range.clone()])
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.node_count
+    }
+
+    pub fn landmark_count(&self) -> usize {
+        self.landmark_count
+    }
+
+    pub fn coord(&self, node: usize) -> Coord {
+        let coords: &[f64] = self.slice(&self.coords);
+        Coord {
+            lat: coords[2 * node],
+            lon: coords[2 * node + 1],
+        }
+    }
+
+    pub fn point(&self, node: usize) -> (f32, f32) {
+        let points: &[f32] = self.slice(&self.points);
+        (points[2 * node], points[2 * node + 1])
+    }
+
+    /// Targets and costs of the edges leaving the node
+    pub fn neighbours(&self, node: usize) -> (&[u32], &[f32]) {
+        self.csr(node, &self.offsets, &self.targets, &self.weights)
+    }
+
+    /// Targets and costs of the edges entering the node
+    pub fn bi_neighbours(&self, node: usize) -> (&[u32], &[f32]) {
+        self.csr(node, &self.bi_offsets, &self.bi_targets, &self.bi_weights)
+    }
+
+    fn csr(
+        &self,
+        node: usize,
+        offsets: &Range<usize>,
+        targets: &Range<usize>,
+        weights: &Range<usize>,
+    ) -> (&[u32], &[f32]) {
+        let offsets: &[u64] = self.slice(offsets);
+        let edges = offsets[node] as usize..offsets[node + 1] as usize;
+        let targets: &[u32] = self.slice(targets);
+        let weights: &[f32] = self.slice(weights);
+        (&targets[edges.clone()], &weights[edges])
+    }
+
+    pub fn landmark_nodes(&self) -> &[u32] {
+        self.slice(&self.landmark_nodes)
+    }
+
+    pub fn landmark_distances(&self, landmark: usize) -> &[f32] {
+        let distances: &[f32] = self.slice(&self.landmark_distances);
+        &distances[landmark * self.node_count..(landmark + 1) * self.node_count]
+    }
+
+    pub fn landmark_bi_distances(&self, landmark: usize) -> &[f32] {
+        let distances: &[f32] = self.slice(&self.landmark_bi_distances);
+        &distances[landmark * self.node_count..(landmark + 1) * self.node_count]
+    }
+}
+
+// TESTS
+#[cfg(test)]
+fn mapped(full_graph: &FullGraph) -> MappedGraph {
+    use std::io::Seek;
+    let mut file = tempfile::tempfile().unwrap();
+    let mut writer = std::io::BufWriter::new(&mut file);
+    full_graph.write_flat(&mut writer).unwrap();
+    drop(writer);
+    file.rewind().unwrap();
+    MappedGraph::from_mmap(unsafe { Mmap::map(&file).unwrap() }).unwrap()
+}
+
+#[test]
+fn flat_graph_matches_full_graph() {
+    let full_graph = crate::preprocessor::preprocessor::grid_full_graph();
+    let flat = mapped(&full_graph);
+
+    assert_eq!(full_graph.header, flat.header);
+    assert_eq!(full_graph.nodes.len(), flat.node_count());
+    for (index, node) in full_graph.nodes.iter().enumerate() {
+        assert_eq!(
+            (node.lat, node.lon),
+            (flat.coord(index).lat, flat.coord(index).lon)
+        );
+        assert_eq!((node.x, node.y), flat.point(index));
+        let (targets, weights) = flat.neighbours(index);
+        let neighbours: Vec<(i64, f32)> = node.neighbours.iter().map(|(n, c)| (n.0, *c)).collect();
+        let flat_neighbours: Vec<(i64, f32)> = targets
+            .iter()
+            .zip(weights)
+            .map(|(t, w)| (*t as i64, *w))
+            .collect();
+        assert_eq!(neighbours, flat_neighbours);
+        assert_eq!(node.bi_neighbours.len(), flat.bi_neighbours(index).0.len());
+    }
+    assert_eq!(full_graph.landmarks.len(), flat.landmark_count());
+    for (index, landmark) in full_graph.landmarks.iter().enumerate() {
+        assert_eq!(landmark.node_id.0 as u32, flat.landmark_nodes()[index]);
+        assert_eq!(landmark.distances, flat.landmark_distances(index));
+        assert_eq!(landmark.bi_distances, flat.landmark_bi_distances(index));
+    }
+}
+
+#[test]
+fn flat_graph_rejects_message_pack_files() {
+    use std::io::Seek;
+    let full_graph = FullGraph {
+        header: GraphHeader::default(),
+        nodes: Vec::new(),
+        landmarks: Vec::new(),
+        spatial_index: None,
+    };
+    let mut file = tempfile::tempfile().unwrap();
+    full_graph.write(&mut file).unwrap();
+    file.rewind().unwrap();
+    let mmap = unsafe { Mmap::map(&file).unwrap() };
+    assert!(matches!(
+        MappedGraph::from_mmap(mmap),
+        Err(GraphFileError::NotAGraphFile)
+    ));
+}
+
+#[test]
+fn flat_graph_rejects_truncated_and_corrupt_files() {
+    use std::io::{Seek, Write};
+    let full_graph = crate::preprocessor::preprocessor::grid_full_graph();
+    let mut bytes = Vec::new();
+    full_graph.write_flat(&mut bytes).unwrap();
+    let open = |bytes: &[u8]| {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(bytes).unwrap();
+        file.rewind().unwrap();
+        MappedGraph::from_mmap(unsafe { Mmap::map(&file).unwrap() })
+    };
+    assert!(open(&bytes).is_ok());
+    for length in [16, 20, bytes.len() / 2, bytes.len() - 4] {
+        assert!(matches!(
+            open(&bytes[..length]),
+            Err(GraphFileError::Decode(_))
+        ));
+    }
+
+    // The counts follow the header, at the first multiple of 8
+    let header_len = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as usize;
+    let counts = aligned(16 + header_len);
+    let mut huge = bytes.clone();
+    huge[counts + 8..counts + 16].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(matches!(open(&huge), Err(GraphFileError::Decode(_))));
+    // The first edge offset, after the coordinates and points of every node
+    let n = full_graph.nodes.len();
+    let offsets = aligned(aligned(counts + 32 + 16 * n) + 8 * n);
+    let mut scrambled = bytes.clone();
+    scrambled[offsets..offsets + 8].copy_from_slice(&1000u64.to_le_bytes());
+    assert!(matches!(open(&scrambled), Err(GraphFileError::Decode(_))));
+}
+
+#[test]
+fn flat_graph_rejects_landmarks_without_a_distance_per_node() {
+    use crate::preprocessor::preprocessor::Landmark;
+    let landmark = crate::preprocessor::preprocessor::grid_full_graph().landmarks[0].clone();
+    let short = Landmark {
+        distances: landmark.distances[1..].to_vec(),
+        ..landmark.clone()
+    };
+    let long = Landmark {
+        bi_distances: [landmark.bi_distances.clone(), vec![0.0]].concat(),
+        ..landmark
+    };
+    for landmark in [short, long] {
+        let mut full_graph = crate::preprocessor::preprocessor::grid_full_graph();
+        full_graph.landmarks = vec![landmark];
+        let mut bytes = Vec::new();
+        assert!(matches!(
+            full_graph.write_flat(&mut bytes),
+            Err(GraphFileError::Encode(_))
+        ));
+        assert!(bytes.is_empty());
+    }
+}
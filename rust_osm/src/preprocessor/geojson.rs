@@ -0,0 +1,121 @@
+use crate::preprocessor::graph_file::GraphFileError;
+use crate::preprocessor::preprocessor::FullGraph;
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::Write;
+
+/*
+   Exports a FullGraph as a GeoJSON FeatureCollection for QGIS or geojson.io.
+   Every feature has a "kind" property, one of:
+   "node"      Point with the node id and projected x, y
+   "edge"      LineString from node to node with its cost. Roads in both directions with the
+               same cost are written once with "oneway": false
+   "landmark"  Point with the landmark index and node id
+   The minimized graph does not keep the nodes between junctions, so edges are straight lines.
+*/
+impl FullGraph {
+    pub fn to_geojson(&self) -> Value {
+        let positions: HashMap<i64, [f64; 2]> = self
+            .nodes
+            .iter()
+            .map(|node| (node.node_id.0, [node.lon, node.lat]))
+            .collect();
+        let mut features = Vec::new();
+        for node in &self.nodes {
+            features.push(json!({
+                "type": "Feature",
+                "geometry": { "type": "Point", "coordinates": positions[&node.node_id.0] },
+                "properties": { "kind": "node", "id": node.node_id.0, "x": node.x, "y": node.y },
+            }));
+        }
+        for node in &self.nodes {
+            let from = node.node_id.0;
+            for (to, cost) in &node.neighbours {
+                let to = to.0;
+                let reverse = self.reverse_cost(to, from);
+                if reverse == Some(*cost) && to < from {
+                    // Written when visiting the other end
+                    continue;
+                }
+                features.push(json!({
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "LineString",
+                        "coordinates": [positions[&from], positions[&to]],
+                    },
+                    "properties": {
+                        "kind": "edge",
+                        "from": from,
+                        "to": to,
+                        "cost": cost,
+                        "oneway": reverse != Some(*cost),
+                    },
+                }));
+            }
+        }
+        for (index, landmark) in self.landmarks.iter().enumerate() {
+            features.push(json!({
+                "type": "Feature",
+                "geometry": { "type": "Point", "coordinates": positions[&landmark.node_id.0] },
+                "properties": { "kind": "landmark", "index": index, "id": landmark.node_id.0 },
+            }));
+        }
+        json!({ "type": "FeatureCollection", "features": features })
+    }
+
+    fn reverse_cost(&self, from: i64, to: i64) -> Option<f32> {
+        // Node ids are the indices after rewrite_ids, otherwise search for the node
+        let node = match self.nodes.get(from as usize) {
+            Some(node) if node.node_id.0 == from => node,
+            _ => self.nodes.iter().find(|node| node.node_id.0 == from)?,
+        };
+        node.neighbours
+            .iter()
+            .find(|(n, _)| n.0 == to)
+            .map(|(_, cost)| *cost)
+    }
+
+    pub fn write_geojson<W: Write>(&self, writer: &mut W) -> Result<(), GraphFileError> {
+        serde_json::to_writer(writer, &self.to_geojson())
+            .map_err(|e| GraphFileError::Encode(e.to_string()))
+    }
+}
+
+// TESTS
+#[test]
+fn geojson_has_nodes_edges_and_landmarks() {
+    let full_graph = crate::preprocessor::preprocessor::grid_full_graph();
+
+    let geojson = full_graph.to_geojson();
+    assert_eq!("FeatureCollection", geojson["type"]);
+    let features = geojson["features"].as_array().unwrap();
+    let of_kind = |kind: &str| {
+        features
+            .iter()
+            .filter(|f| f["properties"]["kind"] == kind)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(full_graph.nodes.len(), of_kind("node").len());
+    assert_eq!(full_graph.landmarks.len(), of_kind("landmark").len());
+
+    // Two-way edges are written once, so counting them twice gives every directed edge
+    let edges = of_kind("edge");
+    let directed: u64 = edges
+        .iter()
+        .map(|e| {
+            if e["properties"]["oneway"] == true {
+                1
+            } else {
+                2
+            }
+        })
+        .sum();
+    assert_eq!(full_graph.edge_count(), directed);
+    // Row 1 of the grid is a oneway street
+    assert!(edges.iter().any(|e| e["properties"]["oneway"] == true));
+
+    let node = &full_graph.nodes[0];
+    let point = &of_kind("node")[0]["geometry"]["coordinates"];
+    assert_eq!(json!([node.lon, node.lat]), *point);
+}
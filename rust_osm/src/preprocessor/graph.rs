@@ -19,7 +19,7 @@ impl Graph {
             for edge in edges {
                 bi_graph
                     .entry(edge.node)
-                    .or_insert_with(Vec::new)
+                    .or_default()
                     .push(Edge::new(*node, edge.cost));
             }
         }
@@ -45,6 +45,7 @@ impl Graph {
                 intermediate_nodes.push(*node_id);
             }
         }
+        intermediate_nodes.sort_unstable();
         intermediate_nodes
     }
 
@@ -56,7 +57,7 @@ impl Graph {
             edges.iter().for_each(|edge| {
                 nodes_pointing_to_node
                     .entry(edge.node)
-                    .or_insert_with(Vec::new)
+                    .or_default()
                     .push(*node_id);
             });
         });
@@ -132,15 +133,15 @@ impl Graph {
                 .unwrap_or(&Vec::new())
                 .clone();
             if edges.len() == 1 {
-                if pointing.len() == 0 {
+                if pointing.is_empty() {
                     start_nodes.push(*node);
                 } else if pointing.len() == 1
                     && nodes_pointing_to_node.get(node).unwrap()[0] == edges[0].node
                 {
                     two_way_end_nodes.push(*node);
                 }
-            } else if pointing.len() == 0 {
-                if edges.len() == 0 {
+            } else if pointing.is_empty() {
+                if edges.is_empty() {
                     dead_nodes.push(*node);
                 } else {
                     end_nodes.push(*node);
@@ -153,31 +154,31 @@ impl Graph {
     pub fn fix_end_nodes(
         graph: &mut HashMap<NodeId, Vec<Edge>>,
         nodes_pointing_to_node: &mut HashMap<NodeId, Vec<NodeId>>,
-        start_nodes: &Vec<NodeId>,
-        end_nodes: &Vec<NodeId>,
-        two_way_end_nodes: &Vec<NodeId>,
-        dead_nodes: &Vec<NodeId>,
+        start_nodes: &[NodeId],
+        end_nodes: &[NodeId],
+        two_way_end_nodes: &[NodeId],
+        dead_nodes: &[NodeId],
     ) {
         for node in start_nodes {
-            let edges = graph.get_mut(&node).unwrap();
+            let edges = graph.get_mut(node).unwrap();
             nodes_pointing_to_node
                 .get_mut(&edges[0].node)
                 .unwrap()
                 .clear();
-            graph.remove(&node);
+            graph.remove(node);
         }
         for node in end_nodes {
-            let pred_nodes = nodes_pointing_to_node.get(&node);
+            let pred_nodes = nodes_pointing_to_node.get(node);
             if let Some(p) = pred_nodes {
                 let pred = p[0];
                 let edges = graph.get_mut(&pred).unwrap();
                 edges.retain(|x| x.node != *node);
             }
-            graph.remove(&node);
+            graph.remove(node);
         }
 
         for node in two_way_end_nodes {
-            let pred_edges = nodes_pointing_to_node.get(&node).unwrap();
+            let pred_edges = nodes_pointing_to_node.get(node).unwrap();
             if !pred_edges.is_empty() {
                 let edges = graph.get_mut(&pred_edges[0]);
                 if let Some(edges) = edges {
@@ -188,10 +189,10 @@ impl Graph {
                     edges.retain(|x| x.node != *node);
                 }
             }
-            graph.remove(&node);
+            graph.remove(node);
         }
         for node in dead_nodes {
-            graph.remove(&node);
+            graph.remove(node);
         }
     }
 
@@ -213,10 +214,10 @@ impl Graph {
             let (mut end_nodes, mut start_nodes, mut two_way_end_nodes, mut dead_nodes) =
                 Self::find_end_nodes(graph, &nodes_pointing_to_node);
             fn can_remove_ends(
-                end_nodes: &Vec<NodeId>,
-                start_nodes: &Vec<NodeId>,
-                two_way_end_nodes: &Vec<NodeId>,
-                dead_nodes: &Vec<NodeId>,
+                end_nodes: &[NodeId],
+                start_nodes: &[NodeId],
+                two_way_end_nodes: &[NodeId],
+                dead_nodes: &[NodeId],
             ) -> bool {
                 !end_nodes.is_empty()
                     || !start_nodes.is_empty()
@@ -257,10 +258,11 @@ impl Graph {
         Self::remove_duplicate_edges(graph);
     }
 
-    fn remove_duplicate_edges(graph: &mut HashMap<NodeId, Vec<Edge>>) {
+    pub(crate) fn remove_duplicate_edges(graph: &mut HashMap<NodeId, Vec<Edge>>) {
         for (node, edges) in graph.iter_mut() {
             edges.retain(|x| x.node != *node);
-            edges.sort_unstable_by_key(|e| e.node.0);
+            // The cheapest of parallel edges is kept, whatever order they were added in
+            edges.sort_unstable_by(|a, b| a.node.cmp(&b.node).then(a.cost.total_cmp(&b.cost)));
             edges.dedup_by(|a, b| a.node == b.node);
         }
     }
@@ -339,11 +341,11 @@ impl Graph {
         */
         let mut landmarks = Vec::new();
         let mut it = graph.iter();
-        for i in 0..n {
+        for _ in 0..n {
             let node = it.next().unwrap();
-            let node_id = node.0.clone();
-            let distances = Graph::dijkstra_all(&graph, node_id);
-            let bi_distances = Graph::dijkstra_all(&bi_graph, node_id);
+            let node_id = *node.0;
+            let distances = Graph::dijkstra_all(graph, node_id);
+            let bi_distances = Graph::dijkstra_all(bi_graph, node_id);
             landmarks.push(Landmark {
                 node_id,
                 distances,
@@ -359,6 +361,9 @@ impl Graph {
         n: u32,
     ) -> Vec<Landmark> {
         let mut landmarks = Vec::new();
+        if graph.is_empty() {
+            return landmarks;
+        }
 
         // Select an initial random node
         let mut current = *graph.keys().next().unwrap();
@@ -447,7 +452,7 @@ impl Graph {
             .iter()
             .map(|(k, v)| (*k, *v))
             .collect::<Vec<(NodeId, f32)>>();
-        distances.sort_by(|a, b| a.0.cmp(&b.0));
+        distances.sort_by_key(|a| a.0);
         let distances: Vec<f32> = distances.iter().map(|x| x.1).collect();
 
         distances
@@ -455,6 +460,7 @@ impl Graph {
 }
 
 // TESTS
+#[cfg(test)]
 fn initialize(filename: &str) -> Preprocessor {
     let mut preprocessor = Preprocessor::new();
     preprocessor.get_roads_and_nodes(filename);
@@ -557,7 +563,7 @@ fn two_way_roads_simple() {
 #[test]
 fn one_way_cycle() {
     let mut graph: HashMap<NodeId, Vec<Edge>> = HashMap::new();
-    let _node_ids = vec![NodeId(1), NodeId(2), NodeId(3), NodeId(4), NodeId(5)];
+    let _node_ids = [NodeId(1), NodeId(2), NodeId(3), NodeId(4), NodeId(5)];
     graph.insert(NodeId(1), vec![Edge::new(NodeId(2), 1.0)]);
     graph.insert(
         NodeId(2),
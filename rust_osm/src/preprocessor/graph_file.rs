@@ -0,0 +1,418 @@
+use crate::preprocessor::coord::Coord;
+use crate::preprocessor::edge::Edge;
+use crate::preprocessor::preprocessor::FullGraph;
+
+use osmpbfreader::NodeId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, Read, Write};
+
+/*
+   Layout of a .graph file:
+   magic       8 bytes  "OSMGRAPH"
+   version     u32      little endian, FORMAT_VERSION when written
+   header_len  u32      little endian, length of the header below
+   header      header_len bytes of MessagePack encoding a GraphHeader
+   body        MessagePack encoding a FullGraph
+
+   Graphs written before the header was added only contain the body, FullGraph::load still reads them.
+
+   The whole file may be compressed with gzip or zstd, open_graph_file recognises both by their
+   magic numbers. The Unity client only reads uncompressed files.
+*/
+pub const MAGIC: &[u8; 8] = b"OSMGRAPH";
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct GraphHeader {
+    pub source_files: Vec<String>,
+    /// Seconds since the unix epoch when the graph was written
+    pub created: u64,
+    /// Which roads were kept, currently always "car"
+    pub profile: String,
+    /// What edge costs measure, currently always "distance" in meters
+    pub metric: String,
+    pub projection: String,
+    /// (lat, lon) the x and y coordinates are projected around
+    pub projection_center: (f64, f64),
+    pub node_count: u64,
+    pub edge_count: u64,
+    pub landmark_count: u64,
+}
+
+#[derive(Debug)]
+pub enum GraphFileError {
+    Io(std::io::Error),
+    NotAGraphFile,
+    UnsupportedVersion(u32),
+    Decode(String),
+    Encode(String),
+}
+
+impl fmt::Display for GraphFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GraphFileError::Io(e) => write!(f, "could not read graph file: {}", e),
+            GraphFileError::NotAGraphFile => {
+                write!(f, "not a graph file, the magic number is missing")
+            }
+            GraphFileError::UnsupportedVersion(version) => write!(
+                f,
+                "graph format version {} is not supported, expected {}",
+                version, FORMAT_VERSION
+            ),
+            GraphFileError::Decode(e) => write!(f, "could not decode graph file: {}", e),
+            GraphFileError::Encode(e) => write!(f, "could not encode graph file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for GraphFileError {}
+
+impl From<std::io::Error> for GraphFileError {
+    fn from(e: std::io::Error) -> Self {
+        GraphFileError::Io(e)
+    }
+}
+
+impl GraphHeader {
+    pub fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let header = rmp_serde::to_vec(self).unwrap();
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&(header.len() as u32).to_le_bytes())?;
+        writer.write_all(&header)
+    }
+
+    /// Reads and validates the header, leaving the reader at the start of the body
+    pub fn read<R: Read>(reader: &mut R) -> Result<GraphHeader, GraphFileError> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => GraphFileError::NotAGraphFile,
+            _ => GraphFileError::Io(e),
+        })?;
+        if &magic != MAGIC {
+            return Err(GraphFileError::NotAGraphFile);
+        }
+        GraphHeader::read_after_magic(reader)
+    }
+
+    fn read_after_magic<R: Read>(reader: &mut R) -> Result<GraphHeader, GraphFileError> {
+        let mut word = [0u8; 4];
+        reader.read_exact(&mut word)?;
+        let version = u32::from_le_bytes(word);
+        if version != FORMAT_VERSION {
+            return Err(GraphFileError::UnsupportedVersion(version));
+        }
+        reader.read_exact(&mut word)?;
+        let len = u32::from_le_bytes(word) as u64;
+        // Read through take, so a corrupt length allocates no more than the file holds
+        let mut header = Vec::new();
+        reader.by_ref().take(len).read_to_end(&mut header)?;
+        if header.len() as u64 != len {
+            return Err(GraphFileError::Decode(format!(
+                "the header should be {} bytes but the file ends after {}",
+                len,
+                header.len()
+            )));
+        }
+        rmp_serde::from_slice(&header).map_err(|e| GraphFileError::Decode(e.to_string()))
+    }
+
+    pub fn read_file(filename: &str) -> Result<GraphHeader, GraphFileError> {
+        GraphHeader::read(&mut open_graph_file(filename)?)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// Buffered writer for a graph file, compressing on the fly. Call finish when done so the
+/// compressed stream is terminated and the buffer flushed
+pub enum GraphWriter {
+    Plain(std::io::BufWriter<std::fs::File>),
+    Gzip(flate2::write::GzEncoder<std::io::BufWriter<std::fs::File>>),
+    Zstd(zstd::Encoder<'static, std::io::BufWriter<std::fs::File>>),
+}
+
+impl GraphWriter {
+    pub fn create(filename: &str, compression: Compression) -> std::io::Result<GraphWriter> {
+        let file = std::io::BufWriter::new(std::fs::File::create(filename)?);
+        Ok(match compression {
+            Compression::None => GraphWriter::Plain(file),
+            Compression::Gzip => GraphWriter::Gzip(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            )),
+            Compression::Zstd => GraphWriter::Zstd(zstd::Encoder::new(file, 0)?),
+        })
+    }
+
+    pub fn finish(self) -> std::io::Result<()> {
+        let mut file = match self {
+            GraphWriter::Plain(file) => file,
+            GraphWriter::Gzip(encoder) => encoder.finish()?,
+            GraphWriter::Zstd(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for GraphWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            GraphWriter::Plain(file) => file.write(buf),
+            GraphWriter::Gzip(encoder) => encoder.write(buf),
+            GraphWriter::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            GraphWriter::Plain(file) => file.flush(),
+            GraphWriter::Gzip(encoder) => encoder.flush(),
+            GraphWriter::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Opens a graph file for reading, decompressing it if it starts with a gzip or zstd magic number
+pub fn open_graph_file(filename: &str) -> std::io::Result<Box<dyn BufRead>> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(filename)?);
+    let start = file.fill_buf()?;
+    Ok(if start.starts_with(GZIP_MAGIC) {
+        Box::new(std::io::BufReader::new(flate2::read::MultiGzDecoder::new(
+            file,
+        )))
+    } else if start.starts_with(ZSTD_MAGIC) {
+        Box::new(std::io::BufReader::new(zstd::Decoder::with_buffer(file)?))
+    } else {
+        Box::new(file)
+    })
+}
+
+impl FullGraph {
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), GraphFileError> {
+        self.header.write(writer)?;
+        rmp_serde::encode::write(writer, self).map_err(|e| GraphFileError::Encode(e.to_string()))
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<FullGraph, GraphFileError> {
+        // Without the magic number this is a graph from before the header, and the bytes
+        // read so far are the start of the body
+        let mut magic = Vec::with_capacity(MAGIC.len());
+        reader.take(MAGIC.len() as u64).read_to_end(&mut magic)?;
+        let (header, body) = if magic == MAGIC {
+            (
+                Some(GraphHeader::read_after_magic(reader)?),
+                rmp_serde::from_read(reader),
+            )
+        } else {
+            (None, rmp_serde::from_read(magic.as_slice().chain(reader)))
+        };
+        let mut full_graph: FullGraph = body.map_err(|e| GraphFileError::Decode(e.to_string()))?;
+        full_graph.header = header.unwrap_or_else(|| GraphHeader {
+            node_count: full_graph.nodes.len() as u64,
+            edge_count: full_graph.edge_count(),
+            landmark_count: full_graph.landmarks.len() as u64,
+            ..GraphHeader::default()
+        });
+        Ok(full_graph)
+    }
+
+    pub fn load(filename: &str) -> Result<FullGraph, GraphFileError> {
+        let mut full_graph = FullGraph::read(&mut open_graph_file(filename)?)?;
+        full_graph.check_spatial_index();
+        Ok(full_graph)
+    }
+
+    pub fn edge_count(&self) -> u64 {
+        self.nodes
+            .iter()
+            .map(|node| node.neighbours.len() as u64)
+            .sum()
+    }
+
+    /// The minimized graph the file was written from
+    pub fn graph(&self) -> HashMap<NodeId, Vec<Edge>> {
+        self.nodes
+            .iter()
+            .map(|node| {
+                let edges = node.neighbours.iter().map(|(n, cost)| Edge::new(*n, *cost));
+                (node.node_id, edges.collect())
+            })
+            .collect()
+    }
+
+    /// The reversed graph, as made by Graph::get_bidirectional_graph
+    pub fn bi_graph(&self) -> HashMap<NodeId, Vec<Edge>> {
+        self.nodes
+            .iter()
+            .map(|node| {
+                let edges = node
+                    .bi_neighbours
+                    .iter()
+                    .map(|(n, cost)| Edge::new(*n, *cost));
+                (node.node_id, edges.collect())
+            })
+            .collect()
+    }
+
+    pub fn coords(&self) -> HashMap<NodeId, Coord> {
+        self.nodes
+            .iter()
+            .map(|node| {
+                let coord = Coord {
+                    lat: node.lat,
+                    lon: node.lon,
+                };
+                (node.node_id, coord)
+            })
+            .collect()
+    }
+
+    pub fn projected_points(&self) -> HashMap<NodeId, (f32, f32)> {
+        self.nodes
+            .iter()
+            .map(|node| (node.node_id, (node.x, node.y)))
+            .collect()
+    }
+}
+
+// TESTS
+#[cfg(test)]
+fn example_header() -> GraphHeader {
+    GraphHeader {
+        source_files: vec!["andorra.osm.pbf".to_owned()],
+        created: 1_700_000_000,
+        profile: "car".to_owned(),
+        metric: "distance".to_owned(),
+        projection: "azimuthal_equidistant".to_owned(),
+        projection_center: (42.5, 1.5),
+        node_count: 3,
+        edge_count: 4,
+        landmark_count: 1,
+    }
+}
+
+#[test]
+fn header_round_trips() {
+    let mut buf = Vec::new();
+    example_header().write(&mut buf).unwrap();
+    buf.extend_from_slice(b"body");
+    let mut reader = buf.as_slice();
+    assert_eq!(example_header(), GraphHeader::read(&mut reader).unwrap());
+    assert_eq!(b"body", reader);
+}
+
+#[test]
+fn rejects_files_without_magic() {
+    // a graph written before the header was added starts directly with the MessagePack body
+    let mut reader: &[u8] = &[0x92, 0x90, 0x90];
+    assert!(matches!(
+        GraphHeader::read(&mut reader),
+        Err(GraphFileError::NotAGraphFile)
+    ));
+}
+
+#[test]
+fn rejects_newer_versions() {
+    let mut buf = Vec::new();
+    example_header().write(&mut buf).unwrap();
+    buf[8..12].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+    assert!(matches!(
+        GraphHeader::read(&mut buf.as_slice()),
+        Err(GraphFileError::UnsupportedVersion(v)) if v == FORMAT_VERSION + 1
+    ));
+}
+
+#[test]
+fn rejects_header_lengths_past_the_end() {
+    let mut buf = Vec::new();
+    example_header().write(&mut buf).unwrap();
+    buf[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(matches!(
+        GraphHeader::read(&mut buf.as_slice()),
+        Err(GraphFileError::Decode(_))
+    ));
+}
+
+#[cfg(test)]
+use crate::preprocessor::preprocessor::grid_full_graph;
+
+#[test]
+fn full_graph_round_trips() {
+    let full_graph = grid_full_graph();
+    let mut buf = Vec::new();
+    full_graph.write(&mut buf).unwrap();
+    let loaded = FullGraph::read(&mut std::io::Cursor::new(buf)).unwrap();
+    assert_eq!(full_graph.header, loaded.header);
+    assert_eq!(full_graph.nodes, loaded.nodes);
+    assert_eq!(full_graph.landmarks, loaded.landmarks);
+}
+
+#[test]
+fn loaded_graph_rebuilds_graph_and_bi_graph() {
+    let full_graph = grid_full_graph();
+    let mut buf = Vec::new();
+    full_graph.write(&mut buf).unwrap();
+    let loaded = FullGraph::read(&mut std::io::Cursor::new(buf)).unwrap();
+    let graph = loaded.graph();
+    assert_eq!(loaded.nodes.len(), graph.len());
+    assert_eq!(loaded.edge_count(), loaded.header.edge_count);
+    // The stored landmark distances are what dijkstra gives on the rebuilt graphs
+    let landmark = &loaded.landmarks[0];
+    assert_eq!(
+        landmark.distances,
+        crate::Graph::dijkstra_all(&graph, landmark.node_id)
+    );
+    assert_eq!(
+        landmark.bi_distances,
+        crate::Graph::dijkstra_all(&loaded.bi_graph(), landmark.node_id)
+    );
+}
+
+#[test]
+fn reads_graphs_without_header() {
+    let full_graph = grid_full_graph();
+    let buf = rmp_serde::to_vec(&full_graph).unwrap();
+    let loaded = FullGraph::read(&mut std::io::Cursor::new(buf)).unwrap();
+    assert_eq!(full_graph.nodes, loaded.nodes);
+    assert_eq!(full_graph.header.node_count, loaded.header.node_count);
+    assert_eq!(full_graph.header.edge_count, loaded.header.edge_count);
+    assert!(loaded.header.source_files.is_empty());
+}
+
+#[test]
+fn reads_compressed_graphs() {
+    let full_graph = grid_full_graph();
+    let dir = tempfile::tempdir().unwrap();
+    let plain = dir.path().join("plain.graph");
+    let plain = plain.to_str().unwrap();
+    let mut writer = GraphWriter::create(plain, Compression::None).unwrap();
+    full_graph.write(&mut writer).unwrap();
+    writer.finish().unwrap();
+    for compression in [Compression::Gzip, Compression::Zstd] {
+        let path = dir.path().join(format!("{:?}.graph", compression));
+        let path = path.to_str().unwrap();
+        let mut writer = GraphWriter::create(path, compression).unwrap();
+        full_graph.write(&mut writer).unwrap();
+        writer.finish().unwrap();
+        let size = |path: &str| std::fs::metadata(path).unwrap().len();
+        assert!(size(path) < size(plain));
+
+        let loaded = FullGraph::load(path).unwrap();
+        assert_eq!(full_graph.header, loaded.header);
+        assert_eq!(full_graph.nodes, loaded.nodes);
+        assert_eq!(full_graph.landmarks, loaded.landmarks);
+        assert_eq!(full_graph.header, GraphHeader::read_file(path).unwrap());
+    }
+}
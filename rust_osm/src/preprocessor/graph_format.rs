@@ -0,0 +1,198 @@
+use crate::preprocessor::graph_file::{open_graph_file, GraphFileError, GraphHeader};
+use crate::preprocessor::preprocessor::{FullGraph, Landmark, NodeWriteFormat};
+
+use bincode::Options;
+use osmpbfreader::NodeId;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// A serialization backend for FullGraph, each with a reader for what it writes
+pub trait GraphFormat {
+    fn write<W: Write>(full_graph: &FullGraph, writer: &mut W) -> Result<(), GraphFileError>;
+    fn read<R: Read>(reader: &mut R) -> Result<FullGraph, GraphFileError>;
+}
+
+/// The format the Unity client reads, with the header described in graph_file.rs
+pub struct MessagePackFormat;
+
+/// Compact and fast to decode in Rust, but not readable from the Unity client
+pub struct BincodeFormat;
+
+/// Readable by anything, but several times larger than the binary formats
+pub struct JsonFormat;
+
+/*
+   Bincode and JSON files have no separate header in front, the header is simply
+   the first field of the document instead. Bincode files start with BINCODE_MAGIC
+   so that other files are rejected before bincode tries to allocate lengths read from them.
+*/
+pub const BINCODE_MAGIC: &[u8; 8] = b"OSMGBINC";
+
+/// Bincode files larger than this are rejected, so a corrupt length cannot allocate more
+pub const BINCODE_LIMIT: u64 = 1 << 32;
+
+/// The fixed-width little-endian encoding of bincode::serialize, but with BINCODE_LIMIT
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(BINCODE_LIMIT)
+}
+
+#[derive(Serialize)]
+struct GraphDocumentRef<'a> {
+    header: &'a GraphHeader,
+    nodes: &'a [NodeWriteFormat],
+    landmarks: &'a [Landmark],
+    spatial_index: &'a Option<Vec<NodeId>>,
+}
+
+#[derive(Deserialize)]
+struct GraphDocument {
+    header: GraphHeader,
+    nodes: Vec<NodeWriteFormat>,
+    landmarks: Vec<Landmark>,
+    #[serde(default)]
+    spatial_index: Option<Vec<NodeId>>,
+}
+
+impl<'a> GraphDocumentRef<'a> {
+    fn new(full_graph: &'a FullGraph) -> Self {
+        GraphDocumentRef {
+            header: &full_graph.header,
+            nodes: &full_graph.nodes,
+            landmarks: &full_graph.landmarks,
+            spatial_index: &full_graph.spatial_index,
+        }
+    }
+}
+
+impl From<GraphDocument> for FullGraph {
+    fn from(document: GraphDocument) -> Self {
+        FullGraph {
+            header: document.header,
+            nodes: document.nodes,
+            landmarks: document.landmarks,
+            spatial_index: document.spatial_index,
+        }
+    }
+}
+
+fn decode_error<E: std::fmt::Display>(e: E) -> GraphFileError {
+    GraphFileError::Decode(e.to_string())
+}
+
+fn encode_error<E: std::fmt::Display>(e: E) -> GraphFileError {
+    GraphFileError::Encode(e.to_string())
+}
+
+impl GraphFormat for MessagePackFormat {
+    fn write<W: Write>(full_graph: &FullGraph, writer: &mut W) -> Result<(), GraphFileError> {
+        full_graph.write(writer)
+    }
+
+    fn read<R: Read>(reader: &mut R) -> Result<FullGraph, GraphFileError> {
+        FullGraph::read(reader)
+    }
+}
+
+impl GraphFormat for BincodeFormat {
+    fn write<W: Write>(full_graph: &FullGraph, writer: &mut W) -> Result<(), GraphFileError> {
+        writer.write_all(BINCODE_MAGIC)?;
+        bincode_options()
+            .serialize_into(writer, &GraphDocumentRef::new(full_graph))
+            .map_err(encode_error)
+    }
+
+    fn read<R: Read>(reader: &mut R) -> Result<FullGraph, GraphFileError> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != BINCODE_MAGIC {
+            return Err(GraphFileError::NotAGraphFile);
+        }
+        let document: GraphDocument = bincode_options()
+            .deserialize_from(reader)
+            .map_err(decode_error)?;
+        Ok(document.into())
+    }
+}
+
+impl GraphFormat for JsonFormat {
+    fn write<W: Write>(full_graph: &FullGraph, writer: &mut W) -> Result<(), GraphFileError> {
+        serde_json::to_writer(writer, &GraphDocumentRef::new(full_graph)).map_err(encode_error)
+    }
+
+    fn read<R: Read>(reader: &mut R) -> Result<FullGraph, GraphFileError> {
+        let document: GraphDocument = serde_json::from_reader(reader).map_err(decode_error)?;
+        Ok(document.into())
+    }
+}
+
+impl FullGraph {
+    pub fn load_as<F: GraphFormat>(filename: &str) -> Result<FullGraph, GraphFileError> {
+        let mut full_graph = F::read(&mut open_graph_file(filename)?)?;
+        full_graph.check_spatial_index();
+        Ok(full_graph)
+    }
+}
+
+// TESTS
+#[cfg(test)]
+fn round_trip<F: GraphFormat>() {
+    let full_graph = crate::preprocessor::preprocessor::grid_full_graph();
+
+    let mut buf = Vec::new();
+    F::write(&full_graph, &mut buf).unwrap();
+    let loaded = F::read(&mut std::io::Cursor::new(buf)).unwrap();
+    assert_eq!(full_graph.header, loaded.header);
+    assert_eq!(full_graph.nodes, loaded.nodes);
+    assert_eq!(full_graph.landmarks, loaded.landmarks);
+}
+
+#[test]
+fn message_pack_round_trips() {
+    round_trip::<MessagePackFormat>();
+}
+
+#[test]
+fn bincode_round_trips() {
+    round_trip::<BincodeFormat>();
+}
+
+#[test]
+fn json_round_trips() {
+    round_trip::<JsonFormat>();
+}
+
+#[test]
+fn json_is_rejected_as_bincode() {
+    let mut buf = Vec::new();
+    let empty = FullGraph {
+        header: GraphHeader::default(),
+        nodes: Vec::new(),
+        landmarks: Vec::new(),
+        spatial_index: None,
+    };
+    JsonFormat::write(&empty, &mut buf).unwrap();
+    assert!(matches!(
+        BincodeFormat::read(&mut std::io::Cursor::new(buf)),
+        Err(GraphFileError::NotAGraphFile)
+    ));
+}
+
+#[test]
+fn bincode_rejects_lengths_past_the_limit() {
+    let mut full_graph = crate::preprocessor::preprocessor::grid_full_graph();
+    full_graph.header.source_files = vec!["grid.osm".to_string()];
+    let mut buf = Vec::new();
+    BincodeFormat::write(&full_graph, &mut buf).unwrap();
+    // The same bytes still read back with the options the writer used
+    assert!(BincodeFormat::read(&mut std::io::Cursor::new(buf.clone())).is_ok());
+
+    // After the magic comes the u64 count of source files and then the first one's length
+    buf[16..24].copy_from_slice(&(BINCODE_LIMIT + 1).to_le_bytes());
+    assert!(matches!(
+        BincodeFormat::read(&mut std::io::Cursor::new(buf)),
+        Err(GraphFileError::Decode(_))
+    ));
+}
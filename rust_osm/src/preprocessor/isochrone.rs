@@ -0,0 +1,398 @@
+use crate::preprocessor::coord::Coord;
+use crate::preprocessor::edge::Edge;
+use crate::preprocessor::graph::Graph;
+use crate::preprocessor::osrm::ASSUMED_SPEED;
+use crate::preprocessor::routing::{Router, START};
+use crate::preprocessor::spatial_index::KdTree;
+
+use osmpbfreader::NodeId;
+use serde_json::{json, Value};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/*
+   Isochrones: everything reachable from a start within a distance or time budget.
+   The search is Dijkstra stopped at the first node beyond the budget, and the area is the
+   concave hull of the reached nodes. The hull starts as the convex hull and edges longer
+   than max_edge are dug inward through the point closest to them, as long as the polygon
+   stays simple and no point ends up outside it (Park and Oh, 2012). Before that the points
+   are thinned to one per max_edge / 4 square, which keeps hulls of large areas fast and
+   moves the boundary by at most that much.
+*/
+
+/// How far the search may go. Edge costs are distances, so times assume ASSUMED_SPEED
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Budget {
+    Meters(f32),
+    Seconds(f32),
+}
+
+impl Budget {
+    pub fn meters(self) -> f32 {
+        match self {
+            Budget::Meters(meters) => meters,
+            Budget::Seconds(seconds) => seconds * ASSUMED_SPEED as f32,
+        }
+    }
+}
+
+pub struct Isochrone {
+    pub start: Coord,
+    pub budget: Budget,
+    /// Every reachable node with its distance from the start, closest first
+    pub reachable: Vec<(NodeId, f32)>,
+    /// The boundary counterclockwise, without repeating the first coordinate
+    pub hull: Vec<Coord>,
+}
+
+impl Graph {
+    /// Dijkstra from start, also following the edges in extra, returning every node at most
+    /// max_cost away in the order they were settled. Stops at the first node beyond max_cost.
+    /// Edges cost what cost gives for them and are not followed when that is infinite
+    pub fn dijkstra_within(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        extra: &HashMap<NodeId, Vec<Edge>>,
+        start: NodeId,
+        max_cost: f32,
+        cost: &dyn Fn(NodeId, &Edge) -> f32,
+    ) -> Vec<(NodeId, f32)> {
+        let mut distances: HashMap<NodeId, f32> = HashMap::new();
+        let mut settled = HashSet::new();
+        let mut reached = Vec::new();
+        let mut heap = BinaryHeap::new();
+        distances.insert(start, 0.0);
+        heap.push(Edge::new(start, 0.0));
+
+        while let Some(Edge {
+            node,
+            cost: node_cost,
+        }) = heap.pop()
+        {
+            if node_cost > max_cost {
+                break;
+            }
+            if !settled.insert(node) {
+                continue;
+            }
+            reached.push((node, node_cost));
+            let edges = graph.get(&node).into_iter().flatten();
+            for edge in edges.chain(extra.get(&node).into_iter().flatten()) {
+                let next_cost = node_cost + cost(node, edge);
+                if next_cost.is_infinite() {
+                    continue;
+                }
+                if distances
+                    .get(&edge.node)
+                    .is_none_or(|best| next_cost < *best)
+                {
+                    distances.insert(edge.node, next_cost);
+                    heap.push(Edge::new(edge.node, next_cost));
+                }
+            }
+        }
+        reached
+    }
+}
+
+impl Router {
+    /// Everything reachable from the start, snapped onto the closest road, within the budget
+    /// and with the costs of the overlay. max_edge is the longest boundary edge in meters the hull is not dug into further
+    pub fn isochrone(&self, start: Coord, budget: Budget, max_edge: f64) -> Option<Isochrone> {
+        let snapped = self.snap_with_overlay(start)?;
+        let mut extra: HashMap<NodeId, Vec<Edge>> = HashMap::new();
+        for hit in &snapped.hits {
+            let remaining = hit.cost * (1.0 - hit.fraction as f32);
+            extra
+                .entry(START)
+                .or_default()
+                .push(Edge::new(hit.to, remaining));
+        }
+        let cost = |from, edge: &Edge| self.edge_cost(from, edge);
+        let mut reachable =
+            Graph::dijkstra_within(&self.graph, &extra, START, budget.meters(), &cost);
+        reachable.retain(|(node, _)| *node != START);
+
+        let mut coords = vec![snapped.coord];
+        coords.extend(reachable.iter().map(|(node, _)| self.coords[node]));
+        let points: Vec<(f64, f64)> = coords.iter().map(|coord| self.project(*coord)).collect();
+        let hull = concave_hull(&points, max_edge)
+            .into_iter()
+            .map(|index| coords[index])
+            .collect();
+        Some(Isochrone {
+            start: snapped.coord,
+            budget,
+            reachable,
+            hull,
+        })
+    }
+}
+
+impl Isochrone {
+    /// A FeatureCollection with the hull as a Polygon, whose properties list the reachable
+    /// node ids, and the start as a Point. The polygon is degenerate when fewer than three
+    /// distinct nodes are reachable
+    pub fn to_geojson(&self) -> Value {
+        let mut ring: Vec<[f64; 2]> = self.hull.iter().map(|c| [c.lon, c.lat]).collect();
+        if let Some(first) = ring.first() {
+            ring.push(*first);
+        }
+        let nodes: Vec<i64> = self.reachable.iter().map(|(node, _)| node.0).collect();
+        let (meters, seconds) = match self.budget {
+            Budget::Meters(meters) => (meters, None),
+            Budget::Seconds(seconds) => (self.budget.meters(), Some(seconds)),
+        };
+        json!({
+            "type": "FeatureCollection",
+            "features": [
+                {
+                    "type": "Feature",
+                    "geometry": { "type": "Polygon", "coordinates": [ring] },
+                    "properties": {
+                        "kind": "isochrone",
+                        "meters": meters,
+                        "seconds": seconds,
+                        "nodes": nodes,
+                    },
+                },
+                {
+                    "type": "Feature",
+                    "geometry": { "type": "Point", "coordinates": [self.start.lon, self.start.lat] },
+                    "properties": { "kind": "start" },
+                },
+            ],
+        })
+    }
+}
+
+fn cross(o: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0)
+}
+
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+fn segment_distance(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length2 = dx * dx + dy * dy;
+    if length2 == 0.0 {
+        return distance(p, a);
+    }
+    let t = (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length2).clamp(0.0, 1.0);
+    distance(p, (a.0 + t * dx, a.1 + t * dy))
+}
+
+/// Whether the segments cross at a point inside both of them
+fn segments_cross(a: (f64, f64), b: (f64, f64), c: (f64, f64), d: (f64, f64)) -> bool {
+    let (d1, d2) = (cross(c, d, a), cross(c, d, b));
+    let (d3, d4) = (cross(a, b, c), cross(a, b, d));
+    d1 * d2 < 0.0 && d3 * d4 < 0.0
+}
+
+/// Indices of the convex hull counterclockwise, without collinear points (Andrew's monotone
+/// chain)
+fn convex_hull(points: &[(f64, f64)], mut indices: Vec<usize>) -> Vec<usize> {
+    indices.sort_by(|a, b| {
+        let (a, b) = (points[*a], points[*b]);
+        a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1))
+    });
+    indices.dedup_by(|a, b| points[*a] == points[*b]);
+    if indices.len() < 3 {
+        return indices;
+    }
+    let mut hull: Vec<usize> = Vec::with_capacity(2 * indices.len());
+    for pass in [indices.clone(), indices.into_iter().rev().collect()] {
+        let lower = hull.len();
+        for index in pass {
+            while hull.len() >= lower + 2
+                && cross(
+                    points[hull[hull.len() - 2]],
+                    points[hull[hull.len() - 1]],
+                    points[index],
+                ) <= 0.0
+            {
+                hull.pop();
+            }
+            hull.push(index);
+        }
+        // The last point of each chain is the first of the other
+        hull.pop();
+    }
+    hull
+}
+
+/// Indices of the points on a concave hull around them, counterclockwise. Hull edges longer
+/// than max_edge are dug into while a point allows it
+pub fn concave_hull(points: &[(f64, f64)], max_edge: f64) -> Vec<usize> {
+    let cell = max_edge / 4.0;
+    let mut cells = HashSet::new();
+    let candidates: Vec<usize> = (0..points.len())
+        .filter(|index| {
+            let (x, y) = points[*index];
+            !cell.is_finite()
+                || cell <= 0.0
+                || cells.insert(((x / cell).floor() as i64, (y / cell).floor() as i64))
+        })
+        .collect();
+    let tree = KdTree::new(candidates.iter().map(|i| (*i, points[*i])).collect());
+    let mut hull = convex_hull(points, candidates);
+    if hull.len() < 3 {
+        return hull;
+    }
+    let mut on_hull: HashSet<usize> = hull.iter().copied().collect();
+
+    let mut i = 0;
+    while i < hull.len() {
+        let (a, b) = (hull[i], hull[(i + 1) % hull.len()]);
+        let (pa, pb) = (points[a], points[b]);
+        let length = distance(pa, pb);
+        if length <= max_edge {
+            i += 1;
+            continue;
+        }
+        // Both new edges must be shorter than the one replaced, so the point is within
+        // length of the middle, and so is every point that could fall outside the new hull
+        let middle = ((pa.0 + pb.0) / 2.0, (pa.1 + pb.1) / 2.0);
+        let nearby: Vec<usize> = tree
+            .within(middle, length)
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        let mut options: Vec<(f64, usize)> = nearby
+            .iter()
+            .filter(|p| !on_hull.contains(p))
+            .map(|p| (segment_distance(points[*p], pa, pb), *p))
+            .filter(|(_, p)| {
+                let pp = points[*p];
+                cross(pa, pb, pp) > 0.0 && distance(pa, pp) < length && distance(pp, pb) < length
+            })
+            .collect();
+        options.sort_by(|x, y| x.0.total_cmp(&y.0));
+        let valid = |p: usize| {
+            let pp = points[p];
+            // Nothing may end up in the triangle cut off from the hull, or on the edge removed
+            let outside = nearby.iter().any(|q| {
+                let q = points[*q];
+                q != pa
+                    && q != pb
+                    && q != pp
+                    && cross(pa, pp, q) < 0.0
+                    && cross(pp, pb, q) < 0.0
+                    && cross(pb, pa, q) <= 0.0
+            });
+            let crosses = (0..hull.len()).any(|j| {
+                let (c, d) = (points[hull[j]], points[hull[(j + 1) % hull.len()]]);
+                segments_cross(pa, pp, c, d) || segments_cross(pp, pb, c, d)
+            });
+            !outside && !crosses
+        };
+        match options.into_iter().map(|(_, p)| p).find(|p| valid(*p)) {
+            Some(p) => {
+                hull.insert(i + 1, p);
+                on_hull.insert(p);
+            }
+            None => i += 1,
+        }
+    }
+    hull
+}
+
+// TESTS
+#[test]
+fn hull_digs_into_a_concave_shape() {
+    // An L shape: the square [0, 100]^2 without [50, 100]^2, sampled every 10 meters
+    let mut points = Vec::new();
+    for x in 0..=10 {
+        for y in 0..=10 {
+            if x <= 5 || y <= 5 {
+                points.push((x as f64 * 10.0, y as f64 * 10.0));
+            }
+        }
+    }
+    let convex = concave_hull(&points, f64::INFINITY);
+    assert_eq!(5, convex.len());
+    let hull = concave_hull(&points, 15.0);
+    let ring: Vec<(f64, f64)> = hull.iter().map(|index| points[*index]).collect();
+    // Every point is inside or on the hull
+    for point in &points {
+        assert!(winding_contains(&ring, *point), "{:?}", point);
+    }
+    // Counterclockwise, so the signed area is positive. It is close to the 7500 m^2 of the L,
+    // the convex hull has 8750 m^2
+    let area: f64 = (0..ring.len())
+        .map(|i| cross((0.0, 0.0), ring[i], ring[(i + 1) % ring.len()]))
+        .sum::<f64>()
+        / 2.0;
+    assert!((area - 7500.0).abs() < 100.0, "{}", area);
+}
+
+#[cfg(test)]
+fn winding_contains(ring: &[(f64, f64)], point: (f64, f64)) -> bool {
+    // Points on an edge count as inside
+    let on_edge = (0..ring.len())
+        .any(|i| segment_distance(point, ring[i], ring[(i + 1) % ring.len()]) < 1e-9);
+    let mut inside = false;
+    for i in 0..ring.len() {
+        let (a, b) = (ring[i], ring[(i + 1) % ring.len()]);
+        if (a.1 > point.1) != (b.1 > point.1)
+            && point.0 < a.0 + (point.1 - a.1) * (b.0 - a.0) / (b.1 - a.1)
+        {
+            inside = !inside;
+        }
+    }
+    on_edge || inside
+}
+
+#[test]
+fn isochrone_stops_at_the_budget() {
+    let router = crate::preprocessor::routing::grid_router();
+    let start = Coord {
+        lat: 55.0021,
+        lon: 10.0042,
+    };
+
+    let everything = router
+        .isochrone(start, Budget::Meters(f32::MAX), 50.0)
+        .unwrap();
+    let small = router
+        .isochrone(start, Budget::Meters(300.0), 50.0)
+        .unwrap();
+    assert!(!small.reachable.is_empty());
+    assert!(small.reachable.len() < everything.reachable.len());
+    assert!(small
+        .reachable
+        .iter()
+        .all(|(_, distance)| *distance <= 300.0));
+    let distances: Vec<f32> = small.reachable.iter().map(|(_, d)| *d).collect();
+    assert!(distances.windows(2).all(|pair| pair[0] <= pair[1]));
+
+    // Every reachable node is within the routed distance from the start
+    for (node, distance) in &small.reachable {
+        let route = router
+            .route_between_coords(
+                start,
+                router.coords[node],
+                crate::preprocessor::routing::Algorithm::Dijkstra,
+            )
+            .unwrap();
+        assert!((route.route.cost - distance).abs() < 1e-2);
+    }
+
+    let minutes = router
+        .isochrone(start, Budget::Seconds(20.0), 50.0)
+        .unwrap();
+    assert_eq!(20.0 * ASSUMED_SPEED as f32, minutes.budget.meters());
+    let geojson = everything.to_geojson();
+    let ring = geojson["features"][0]["geometry"]["coordinates"][0]
+        .as_array()
+        .unwrap();
+    assert!(ring.len() >= 4);
+    assert_eq!(ring[0], ring[ring.len() - 1]);
+    assert_eq!(
+        everything.reachable.len(),
+        geojson["features"][0]["properties"]["nodes"]
+            .as_array()
+            .unwrap()
+            .len()
+    );
+}
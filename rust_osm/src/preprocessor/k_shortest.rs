@@ -0,0 +1,177 @@
+use crate::preprocessor::edge::Edge;
+use crate::preprocessor::graph::Graph;
+use crate::preprocessor::routing::Route;
+
+use ordered_float::OrderedFloat;
+use osmpbfreader::NodeId;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/*
+   The k shortest loopless paths with Yen's algorithm.
+   Every path after the first branches off a path already found: for each node of the last
+   path found, the spur node, the path is kept up to it and Dijkstra finds the rest while
+   avoiding the nodes before the spur and the next edge of every found path that shares
+   the same beginning. The cheapest of all candidates found so far is the next path.
+   Parallel edges between the same two nodes are treated as one, with the lowest cost.
+*/
+
+/// Dijkstra from start to end without visiting the banned nodes or using the banned edges
+fn restricted_shortest_path(
+    graph: &HashMap<NodeId, Vec<Edge>>,
+    start: NodeId,
+    end: NodeId,
+    banned_nodes: &HashSet<NodeId>,
+    banned_edges: &HashSet<(NodeId, NodeId)>,
+) -> Option<Route> {
+    Graph::shortest_path_with_costs(
+        graph,
+        &HashMap::new(),
+        start,
+        end,
+        &|_| 0.0,
+        &|from, edge| {
+            if banned_nodes.contains(&edge.node) || banned_edges.contains(&(from, edge.node)) {
+                f32::INFINITY
+            } else {
+                edge.cost
+            }
+        },
+    )
+}
+
+fn edge_cost(graph: &HashMap<NodeId, Vec<Edge>>, from: NodeId, to: NodeId) -> f32 {
+    graph[&from]
+        .iter()
+        .filter(|edge| edge.node == to)
+        .map(|edge| edge.cost)
+        .fold(f32::INFINITY, f32::min)
+}
+
+impl Graph {
+    /// The k shortest paths from start to end without repeated nodes, cheapest first. Fewer
+    /// are returned when there are no more. visited counts the nodes settled by every search
+    /// that found a path, up to that path
+    pub fn k_shortest_paths(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        start: NodeId,
+        end: NodeId,
+        k: usize,
+    ) -> Vec<Route> {
+        let no_nodes = HashSet::new();
+        let no_edges = HashSet::new();
+        let Some(first) =
+            restricted_shortest_path(graph, start, end, &no_nodes, &no_edges).filter(|_| k > 0)
+        else {
+            return Vec::new();
+        };
+        let mut visited = first.visited;
+        let mut found = vec![first];
+        // Candidates ordered by cost, then by the fewest nodes
+        let mut candidates = BinaryHeap::new();
+        let mut seen: HashSet<Vec<NodeId>> = HashSet::new();
+        seen.insert(found[0].nodes.clone());
+
+        while found.len() < k {
+            let last = found.last().unwrap().nodes.clone();
+            let mut root_cost = 0.0;
+            for i in 0..last.len() - 1 {
+                let (spur, root) = (last[i], &last[..=i]);
+                let banned_edges: HashSet<(NodeId, NodeId)> = found
+                    .iter()
+                    .filter(|route| route.nodes.len() > i + 1 && route.nodes[..=i] == *root)
+                    .map(|route| (route.nodes[i], route.nodes[i + 1]))
+                    .collect();
+                let banned_nodes: HashSet<NodeId> = root[..i].iter().copied().collect();
+                let spur_path =
+                    restricted_shortest_path(graph, spur, end, &banned_nodes, &banned_edges);
+                if let Some(spur_path) = spur_path {
+                    visited += spur_path.visited;
+                    let mut nodes = root[..i].to_vec();
+                    nodes.extend(spur_path.nodes);
+                    if seen.insert(nodes.clone()) {
+                        let cost = root_cost + spur_path.cost;
+                        candidates.push(Reverse((OrderedFloat(cost), nodes.len(), nodes)));
+                    }
+                }
+                root_cost += edge_cost(graph, last[i], last[i + 1]);
+            }
+            let Some(Reverse((OrderedFloat(cost), _, nodes))) = candidates.pop() else {
+                break;
+            };
+            found.push(Route {
+                nodes,
+                cost,
+                visited,
+            });
+        }
+        found
+    }
+}
+
+// TESTS
+#[test]
+fn finds_the_k_shortest_paths_in_order() {
+    // The example graph from Wikipedia's article on Yen's algorithm, C=0 D=1 E=2 F=3 G=4 H=5
+    let edges = [
+        (0, 1, 3.0),
+        (0, 2, 2.0),
+        (1, 3, 4.0),
+        (2, 1, 1.0),
+        (2, 3, 2.0),
+        (2, 4, 3.0),
+        (3, 4, 2.0),
+        (3, 5, 1.0),
+        (4, 5, 2.0),
+    ];
+    let mut graph: HashMap<NodeId, Vec<Edge>> = HashMap::new();
+    for (from, to, cost) in edges {
+        graph
+            .entry(NodeId(from))
+            .or_default()
+            .push(Edge::new(NodeId(to), cost));
+    }
+    let paths = Graph::k_shortest_paths(&graph, NodeId(0), NodeId(5), 3);
+    let ids: Vec<Vec<i64>> = paths
+        .iter()
+        .map(|route| route.nodes.iter().map(|node| node.0).collect())
+        .collect();
+    assert_eq!(
+        vec![vec![0, 2, 3, 5], vec![0, 2, 4, 5], vec![0, 1, 3, 5]],
+        ids
+    );
+    let costs: Vec<f32> = paths.iter().map(|route| route.cost).collect();
+    assert_eq!(vec![5.0, 7.0, 8.0], costs);
+
+    // There are 7 simple paths from C to H in total
+    assert_eq!(
+        7,
+        Graph::k_shortest_paths(&graph, NodeId(0), NodeId(5), 20).len()
+    );
+    assert!(Graph::k_shortest_paths(&graph, NodeId(5), NodeId(0), 3).is_empty());
+    assert!(Graph::k_shortest_paths(&graph, NodeId(0), NodeId(5), 0).is_empty());
+}
+
+#[test]
+fn k_shortest_paths_on_the_grid_are_simple_and_sorted() {
+    let graph = crate::preprocessor::preprocessor::grid_full_graph().graph();
+    let (start, end) = (NodeId(0), NodeId(graph.len() as i64 - 1));
+    let paths = Graph::k_shortest_paths(&graph, start, end, 10);
+    let optimal = Graph::shortest_path(&graph, start, end).unwrap();
+    assert!((paths[0].cost - optimal.cost).abs() < 1e-3);
+    assert!(paths.len() > 1);
+    for pair in paths.windows(2) {
+        assert!(pair[0].cost <= pair[1].cost + 1e-3);
+        assert_ne!(pair[0].nodes, pair[1].nodes);
+    }
+    for route in &paths {
+        let unique: HashSet<&NodeId> = route.nodes.iter().collect();
+        assert_eq!(route.nodes.len(), unique.len());
+        let cost: f32 = route
+            .nodes
+            .windows(2)
+            .map(|pair| edge_cost(&graph, pair[0], pair[1]))
+            .sum();
+        assert!((cost - route.cost).abs() < 1e-2);
+    }
+}
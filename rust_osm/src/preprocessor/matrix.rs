@@ -0,0 +1,312 @@
+use crate::preprocessor::coord::Coord;
+use crate::preprocessor::edge::Edge;
+use crate::preprocessor::graph::Graph;
+use crate::preprocessor::routing::{Router, Snapped, START};
+
+use osmpbfreader::NodeId;
+use rayon::prelude::*;
+use serde_json::{json, Value};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io::Write;
+
+/*
+   Many-to-many distance tables. Each source runs its own Dijkstra, stopped as soon as every
+   target is settled, and the sources are spread over threads with rayon. Row i holds the
+   distances from source i to every target, f32::MAX where a target cannot be reached, like
+   Graph::dijkstra_all.
+*/
+pub type DistanceMatrix = Vec<Vec<f32>>;
+
+/// Virtual id of the j-th snapped target, below START and END
+fn target_id(j: usize) -> NodeId {
+    NodeId(-3 - j as i64)
+}
+
+impl Graph {
+    /// Dijkstra from start, also following the edges in extra and start_edges out of start,
+    /// until every target is settled. Edges cost what cost gives for them, see
+    /// shortest_path_with_costs, and edges with an infinite cost are not followed
+    pub fn dijkstra_to_targets(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        extra: &HashMap<NodeId, Vec<Edge>>,
+        start: NodeId,
+        start_edges: &[Edge],
+        targets: &[NodeId],
+        cost: &dyn Fn(NodeId, &Edge) -> f32,
+    ) -> Vec<f32> {
+        let mut remaining: HashSet<NodeId> = targets.iter().copied().collect();
+        let mut distances: HashMap<NodeId, f32> = HashMap::new();
+        let mut settled = HashSet::new();
+        let mut heap = BinaryHeap::new();
+        distances.insert(start, 0.0);
+        heap.push(Edge::new(start, 0.0));
+
+        while let Some(Edge {
+            node,
+            cost: node_cost,
+        }) = heap.pop()
+        {
+            if remaining.is_empty() {
+                break;
+            }
+            if !settled.insert(node) {
+                continue;
+            }
+            remaining.remove(&node);
+            let edges = graph.get(&node).into_iter().flatten();
+            let edges = edges.chain(extra.get(&node).into_iter().flatten());
+            let from_start = if node == start { start_edges } else { &[] };
+            for edge in edges.chain(from_start) {
+                let next_cost = node_cost + cost(node, edge);
+                if next_cost.is_infinite() {
+                    continue;
+                }
+                if distances
+                    .get(&edge.node)
+                    .is_none_or(|best| next_cost < *best)
+                {
+                    distances.insert(edge.node, next_cost);
+                    heap.push(Edge::new(edge.node, next_cost));
+                }
+            }
+        }
+        targets
+            .iter()
+            .map(|target| match settled.contains(target) {
+                true => distances[target],
+                false => f32::MAX,
+            })
+            .collect()
+    }
+
+    /// Distances from every source to every target node
+    pub fn distance_matrix(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        sources: &[NodeId],
+        targets: &[NodeId],
+    ) -> DistanceMatrix {
+        let no_edges = HashMap::new();
+        sources
+            .par_iter()
+            .map(|source| {
+                Graph::dijkstra_to_targets(graph, &no_edges, *source, &[], targets, &|_, edge| {
+                    edge.cost
+                })
+            })
+            .collect()
+    }
+}
+
+impl Router {
+    /// Distances between coordinates, each snapped onto the closest road like
+    /// route_between_coords and with the costs of the overlay. The row or column of a
+    /// coordinate that snaps onto no road is unreachable
+    pub fn distance_matrix(&self, sources: &[Coord], targets: &[Coord]) -> DistanceMatrix {
+        let targets: Vec<Option<Snapped>> =
+            targets.iter().map(|t| self.snap_with_overlay(*t)).collect();
+        let sources: Vec<Option<Snapped>> =
+            sources.iter().map(|s| self.snap_with_overlay(*s)).collect();
+        let snapped: Vec<(usize, &Snapped)> = targets
+            .iter()
+            .enumerate()
+            .filter_map(|(j, target)| Some((j, target.as_ref()?)))
+            .collect();
+        // Every target is a temporary node reached from the start of the edges it lies on
+        let mut target_edges: HashMap<NodeId, Vec<Edge>> = HashMap::new();
+        for (j, target) in &snapped {
+            for hit in &target.hits {
+                let travelled = hit.cost * hit.fraction as f32;
+                let edge = Edge::new(target_id(*j), travelled);
+                target_edges.entry(hit.from).or_default().push(edge);
+            }
+        }
+        let target_ids: Vec<NodeId> = snapped.iter().map(|(j, _)| target_id(*j)).collect();
+        let cost = |from, edge: &Edge| self.edge_cost(from, edge);
+        sources
+            .par_iter()
+            .map(|source| {
+                let mut row = vec![f32::MAX; targets.len()];
+                let Some(source) = source else {
+                    return row;
+                };
+                let mut start_edges = Vec::new();
+                for hit in &source.hits {
+                    let remaining = hit.cost * (1.0 - hit.fraction as f32);
+                    start_edges.push(Edge::new(hit.to, remaining));
+                    // Targets further along the same edge are reached without leaving it
+                    for (j, target) in &snapped {
+                        for target_hit in &target.hits {
+                            let same_edge = (hit.from, hit.to) == (target_hit.from, target_hit.to);
+                            if same_edge && hit.fraction <= target_hit.fraction {
+                                let between =
+                                    hit.cost * (target_hit.fraction - hit.fraction) as f32;
+                                start_edges.push(Edge::new(target_id(*j), between));
+                            }
+                        }
+                    }
+                }
+                let distances = Graph::dijkstra_to_targets(
+                    &self.graph,
+                    &target_edges,
+                    START,
+                    &start_edges,
+                    &target_ids,
+                    &cost,
+                );
+                for ((j, _), distance) in snapped.iter().zip(distances) {
+                    row[*j] = distance;
+                }
+                row
+            })
+            .collect()
+    }
+}
+
+/// Reads one "lat,lon" per line, skipping blank lines and # comments
+pub fn read_coordinates(text: &str) -> Result<Vec<Coord>, String> {
+    location_lines(text)
+        .map(|(number, line)| {
+            let invalid = || format!("line {}: expected lat,lon but got {}", number, line);
+            let (lat, lon) = line.split_once(',').ok_or_else(invalid)?;
+            match (lat.trim().parse(), lon.trim().parse()) {
+                (Ok(lat), Ok(lon)) if (Coord { lat, lon }).is_valid() => Ok(Coord { lat, lon }),
+                _ => Err(invalid()),
+            }
+        })
+        .collect()
+}
+
+/// Reads one node id per line, skipping blank lines and # comments
+pub fn read_node_ids(text: &str) -> Result<Vec<NodeId>, String> {
+    location_lines(text)
+        .map(|(number, line)| {
+            line.parse()
+                .map(NodeId)
+                .map_err(|_| format!("line {}: expected a node id but got {}", number, line))
+        })
+        .collect()
+}
+
+/// The lines that are not blank or # comments, trimmed and with their line numbers
+pub(crate) fn location_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// One row per source, with an empty field where the target cannot be reached
+pub fn write_matrix_csv<W: Write>(matrix: &DistanceMatrix, writer: &mut W) -> std::io::Result<()> {
+    for row in matrix {
+        let fields: Vec<String> = row
+            .iter()
+            .map(|distance| match *distance {
+                f32::MAX => String::new(),
+                distance => distance.to_string(),
+            })
+            .collect();
+        writeln!(writer, "{}", fields.join(","))?;
+    }
+    Ok(())
+}
+
+/// {"distances": [[...], ...]} with null where the target cannot be reached
+pub fn matrix_to_json(matrix: &DistanceMatrix) -> Value {
+    // Rounded to centimeters, f32 costs widened to f64 would print as 1194.8634033203125
+    let round = |d: f32| ((d as f64) * 100.0).round() / 100.0;
+    let rows: Vec<Vec<Option<f64>>> = matrix
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|d| (*d != f32::MAX).then(|| round(*d)))
+                .collect()
+        })
+        .collect();
+    json!({ "distances": rows })
+}
+
+// TESTS
+#[cfg(test)]
+use crate::preprocessor::routing::grid_router;
+
+#[test]
+fn matrix_matches_dijkstra_all() {
+    let router = grid_router();
+    let nodes: Vec<NodeId> = (0..router.coords.len() as i64).map(NodeId).collect();
+    let matrix = Graph::distance_matrix(&router.graph, &nodes[..4], &nodes);
+    assert_eq!(4, matrix.len());
+    for (source, row) in nodes.iter().zip(&matrix) {
+        let all = Graph::dijkstra_all(&router.graph, *source);
+        assert_eq!(nodes.len(), row.len());
+        for (expected, distance) in all.iter().zip(row) {
+            assert!((expected - distance).abs() < 1e-3);
+        }
+    }
+    let missing = Graph::distance_matrix(&router.graph, &nodes[..1], &[NodeId(9999)]);
+    assert_eq!(vec![vec![f32::MAX]], missing);
+}
+
+#[test]
+fn coordinate_matrix_matches_single_routes() {
+    use crate::preprocessor::routing::Algorithm;
+    let router = grid_router();
+    let locations = read_coordinates(
+        "# lat,lon\n55.0004,10.0001\n\n55.006,10.0089\n55.0021,10.0042\n55.0021,10.0048\n",
+    )
+    .unwrap();
+    assert_eq!(4, locations.len());
+    let matrix = router.distance_matrix(&locations, &locations);
+    for (i, row) in matrix.iter().enumerate() {
+        for (j, distance) in row.iter().enumerate() {
+            let route =
+                router.route_between_coords(locations[i], locations[j], Algorithm::Dijkstra);
+            match route {
+                Some(route) => assert!((route.route.cost - distance).abs() < 1e-2),
+                None => assert_eq!(f32::MAX, *distance),
+            }
+        }
+    }
+
+    let mut csv = Vec::new();
+    write_matrix_csv(&vec![vec![0.0, f32::MAX], vec![1.5, 0.0]], &mut csv).unwrap();
+    assert_eq!("0,\n1.5,0\n", String::from_utf8(csv).unwrap());
+    let json = matrix_to_json(&vec![vec![0.0, f32::MAX]]);
+    assert_eq!(json!({ "distances": [[0.0, null]] }), json);
+    assert!(read_coordinates("55.0;10.0").is_err());
+    for invalid in ["nan,10.0", "55.0,inf", "91.0,10.0", "55.0,-181.0"] {
+        assert!(read_coordinates(invalid).is_err());
+    }
+    assert_eq!(
+        Ok(vec![NodeId(3), NodeId(7)]),
+        read_node_ids("3\n# skipped\n7\n")
+    );
+}
+
+#[test]
+fn coordinates_that_do_not_snap_only_blank_their_row_and_column() {
+    let router = grid_router();
+    let nowhere = Coord {
+        lat: f64::NAN,
+        lon: 10.0,
+    };
+    let locations = vec![
+        Coord {
+            lat: 55.0004,
+            lon: 10.0001,
+        },
+        nowhere,
+        Coord {
+            lat: 55.006,
+            lon: 10.0089,
+        },
+    ];
+    let matrix = router.distance_matrix(&locations, &locations);
+    let all = router.distance_matrix(&[locations[0], locations[2]], &[locations[0], locations[2]]);
+    assert_eq!(vec![f32::MAX; 3], matrix[1]);
+    for (i, row) in [(0, 0), (2, 1)] {
+        assert_eq!(f32::MAX, matrix[i][1]);
+        assert_eq!(all[row][0], matrix[i][0]);
+        assert_eq!(all[row][1], matrix[i][2]);
+        assert!(all[row].iter().all(|distance| *distance != f32::MAX));
+    }
+}
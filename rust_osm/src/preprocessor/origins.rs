@@ -0,0 +1,280 @@
+use crate::preprocessor::edge::Edge;
+use crate::preprocessor::graph_file::{open_graph_file, GraphFileError};
+use crate::preprocessor::preprocessor::{CarDirection, Road};
+
+use osmpbfreader::{NodeId, WayId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io::{Read, Write};
+
+/*
+   Which OSM ways and nodes every edge of the minimized graph follows.
+   Minimizing merges the edges through intermediate nodes into one edge between junctions and
+   rewrite_ids renumbers the junctions, so the graph no longer knows its OSM ids. They are
+   recovered by following each minimized edge through the graph from before minimization:
+   from its start, only through nodes that were removed, until its end is reached with the
+   cost of the edge. Edges that cannot be followed that way are listed in untraced instead of
+   being guessed. Only recorded when Preprocessor::record_origins is set, since a copy of
+   the whole graph is kept while minimizing.
+   Stored next to the graph file with MessagePack, it is only valid for the graph written by
+   the same run.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EdgeOrigin {
+    pub from: NodeId,
+    pub to: NodeId,
+    /// The ways driven from `from` to `to` in order, with the meters driven on each
+    pub ways: Vec<(WayId, f32)>,
+    /// OSM ids of every node passed, both ends included
+    pub nodes: Vec<NodeId>,
+    /// Meters from every node in nodes to the next
+    pub lengths: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct EdgeOrigins {
+    /// OSM id of every graph node, indexed by graph node id
+    pub osm_nodes: Vec<NodeId>,
+    pub edges: Vec<EdgeOrigin>,
+    /// The ends of the edges whose way through the original graph was not found
+    #[serde(default)]
+    pub untraced: Vec<(NodeId, NodeId)>,
+}
+
+/// The way of every edge between two consecutive nodes of a road
+pub(crate) fn road_ways(roads: &[Road]) -> HashMap<(NodeId, NodeId), WayId> {
+    let mut ways = HashMap::new();
+    for road in roads {
+        for pair in road.node_refs.windows(2) {
+            ways.insert((pair[0], pair[1]), road.id);
+            if road.direction == CarDirection::Twoway {
+                ways.insert((pair[1], pair[0]), road.id);
+            }
+        }
+    }
+    ways
+}
+
+/// For every edge leaving from in minimized, the nodes of original from `from` to its end
+/// through nodes not in minimized, arriving with the cost closest to that of the edge
+fn trace_edges(
+    original: &HashMap<NodeId, Vec<Edge>>,
+    minimized: &HashMap<NodeId, Vec<Edge>>,
+    from: NodeId,
+    edges: &[Edge],
+) -> Vec<Option<Vec<NodeId>>> {
+    // Dijkstra through the removed nodes, which apart from dead ends that were cut off lie on
+    // chains between two junctions. The costs of minimized edges are sums in another order
+    // and at times a few meters off, hence the margin
+    let max_cost = edges.iter().map(|edge| edge.cost).fold(0.0, f32::max) * 1.1 + 1.0;
+    let mut tree: HashMap<NodeId, (f32, Option<NodeId>)> = HashMap::new();
+    let mut settled = HashSet::new();
+    let mut heap = BinaryHeap::new();
+    // Every way of reaching a junction as (junction, cost, node before it)
+    let mut arrivals: Vec<(NodeId, f32, NodeId)> = Vec::new();
+    tree.insert(from, (0.0, None));
+    heap.push(Edge::new(from, 0.0));
+
+    while let Some(Edge { node, cost }) = heap.pop() {
+        if !settled.insert(node) {
+            continue;
+        }
+        for next in original.get(&node).into_iter().flatten() {
+            let next_cost = cost + next.cost;
+            if next_cost > max_cost {
+                continue;
+            }
+            if minimized.contains_key(&next.node) {
+                arrivals.push((next.node, next_cost, node));
+            } else if tree
+                .get(&next.node)
+                .is_none_or(|(best, _)| next_cost < *best)
+            {
+                tree.insert(next.node, (next_cost, Some(node)));
+                heap.push(Edge::new(next.node, next_cost));
+            }
+        }
+    }
+
+    edges
+        .iter()
+        .map(|edge| {
+            let error = |cost: f32| (cost - edge.cost).abs();
+            let &(_, _, last) = arrivals
+                .iter()
+                .filter(|(junction, _, _)| *junction == edge.node)
+                .min_by(|a, b| error(a.1).total_cmp(&error(b.1)))?;
+            let mut nodes = vec![edge.node, last];
+            while let Some(previous) = tree[nodes.last().unwrap()].1 {
+                nodes.push(previous);
+            }
+            nodes.reverse();
+            Some(nodes)
+        })
+        .collect()
+}
+
+impl EdgeOrigins {
+    /// Follows every edge of minimized through original, both still with OSM ids. ways is
+    /// the way of every edge of original, see road_ways
+    pub(crate) fn trace(
+        original: &HashMap<NodeId, Vec<Edge>>,
+        ways: &HashMap<(NodeId, NodeId), WayId>,
+        minimized: &HashMap<NodeId, Vec<Edge>>,
+    ) -> EdgeOrigins {
+        let mut edges = Vec::new();
+        let mut untraced = Vec::new();
+        for (from, minimized_edges) in minimized {
+            let traced = trace_edges(original, minimized, *from, minimized_edges);
+            for (edge, nodes) in minimized_edges.iter().zip(traced) {
+                let Some(nodes) = nodes else {
+                    untraced.push((*from, edge.node));
+                    continue;
+                };
+                let mut edge_ways: Vec<(WayId, f32)> = Vec::new();
+                let mut lengths = Vec::new();
+                for pair in nodes.windows(2) {
+                    let cost = original[&pair[0]]
+                        .iter()
+                        .find(|edge| edge.node == pair[1])
+                        .map_or(0.0, |edge| edge.cost);
+                    lengths.push(cost);
+                    let Some(way) = ways.get(&(pair[0], pair[1])) else {
+                        continue;
+                    };
+                    match edge_ways.last_mut() {
+                        Some((last, meters)) if last == way => *meters += cost,
+                        _ => edge_ways.push((*way, cost)),
+                    }
+                }
+                edges.push(EdgeOrigin {
+                    from: *from,
+                    to: edge.node,
+                    ways: edge_ways,
+                    nodes,
+                    lengths,
+                });
+            }
+        }
+        EdgeOrigins {
+            osm_nodes: Vec::new(),
+            edges,
+            untraced,
+        }
+    }
+
+    /// Moves the ends of every edge to the ids given by Preprocessor::rewrite_ids, keeping the
+    /// OSM ids of the node_count graph nodes in osm_nodes
+    pub(crate) fn rewrite_ids(&mut self, old_to_new: &HashMap<NodeId, NodeId>, node_count: usize) {
+        self.osm_nodes = vec![NodeId(-1); node_count];
+        for (old, new) in old_to_new {
+            if let Some(osm_node) = self.osm_nodes.get_mut(new.0 as usize) {
+                *osm_node = *old;
+            }
+        }
+        for edge in &mut self.edges {
+            edge.from = old_to_new[&edge.from];
+            edge.to = old_to_new[&edge.to];
+        }
+        self.edges.sort_by_key(|edge| (edge.from, edge.to));
+        for (from, to) in &mut self.untraced {
+            (*from, *to) = (old_to_new[from], old_to_new[to]);
+        }
+        self.untraced.sort();
+    }
+
+    /// The origin of every edge by its ends
+    pub fn by_edge(&self) -> HashMap<(NodeId, NodeId), &EdgeOrigin> {
+        self.edges
+            .iter()
+            .map(|edge| ((edge.from, edge.to), edge))
+            .collect()
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), GraphFileError> {
+        rmp_serde::encode::write(writer, self).map_err(|e| GraphFileError::Encode(e.to_string()))
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<EdgeOrigins, GraphFileError> {
+        rmp_serde::from_read(reader).map_err(|e| GraphFileError::Decode(e.to_string()))
+    }
+
+    pub fn load(filename: &str) -> Result<EdgeOrigins, GraphFileError> {
+        EdgeOrigins::read(&mut open_graph_file(filename)?)
+    }
+}
+
+// TESTS
+#[test]
+fn origins_follow_the_roads() {
+    use crate::preprocessor::preprocessor::Preprocessor;
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.record_origins = true;
+    preprocessor.get_roads_and_nodes("src/test_data/grid.osm");
+    let roads = preprocessor.roads.clone();
+    let osm_nodes = preprocessor.nodes.clone();
+    let (graph, _, _) = preprocessor.build_graph();
+    let origins = preprocessor.origins.clone().unwrap();
+    assert_eq!(graph.len(), origins.osm_nodes.len());
+    let edge_count: usize = graph.values().map(Vec::len).sum();
+    assert_eq!(edge_count, origins.edges.len());
+    assert!(origins.untraced.is_empty());
+
+    let by_edge = origins.by_edge();
+    for (from, edges) in &graph {
+        for edge in edges {
+            let origin = by_edge[&(*from, edge.node)];
+            assert_eq!(origins.osm_nodes[from.0 as usize], origin.nodes[0]);
+            assert_eq!(
+                origins.osm_nodes[edge.node.0 as usize],
+                *origin.nodes.last().unwrap()
+            );
+            // The nodes lie on the ways, and the ways add up to the cost of the edge
+            let length: f32 = origin
+                .nodes
+                .windows(2)
+                .map(|pair| osm_nodes[&pair[0]].distance_to(osm_nodes[&pair[1]]))
+                .sum();
+            let meters: f32 = origin.ways.iter().map(|(_, meters)| meters).sum();
+            assert_eq!(origin.nodes.len() - 1, origin.lengths.len());
+            let lengths: f32 = origin.lengths.iter().sum();
+            assert!((lengths - edge.cost).abs() < 0.5);
+            assert!((length - edge.cost).abs() < 0.5);
+            assert!((meters - edge.cost).abs() < 0.5);
+            for (way, _) in &origin.ways {
+                let road = roads.iter().find(|road| road.id == *way).unwrap();
+                assert!(origin
+                    .nodes
+                    .iter()
+                    .any(|node| road.node_refs.contains(node)));
+            }
+        }
+    }
+
+    let mut buf = Vec::new();
+    origins.write(&mut buf).unwrap();
+    let read = EdgeOrigins::read(&mut buf.as_slice()).unwrap();
+    assert_eq!(origins, read);
+}
+
+#[test]
+fn edges_without_a_way_are_reported() {
+    let original = HashMap::from([
+        (NodeId(1), vec![Edge::new(NodeId(2), 10.0)]),
+        (NodeId(2), vec![Edge::new(NodeId(3), 10.0)]),
+        (NodeId(3), vec![]),
+    ]);
+    let ways = HashMap::from([
+        ((NodeId(1), NodeId(2)), WayId(7)),
+        ((NodeId(2), NodeId(3)), WayId(7)),
+    ]);
+    // The edge back from 3 to 1 has no counterpart in the original graph
+    let minimized = HashMap::from([
+        (NodeId(1), vec![Edge::new(NodeId(3), 20.0)]),
+        (NodeId(3), vec![Edge::new(NodeId(1), 20.0)]),
+    ]);
+    let origins = EdgeOrigins::trace(&original, &ways, &minimized);
+    assert_eq!(1, origins.edges.len());
+    assert_eq!(vec![(WayId(7), 20.0)], origins.edges[0].ways);
+    assert_eq!(vec![(NodeId(3), NodeId(1))], origins.untraced);
+}
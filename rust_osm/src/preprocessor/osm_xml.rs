@@ -0,0 +1,241 @@
+use osmpbfreader::{Node, NodeId, OsmObj, Tags, Way, WayId};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::io::BufRead;
+
+/// Streams the nodes and ways of an OSM XML file (.osm, .osm.bz2 or .osm.gz) as the same
+/// objects the PBF reader produces, so both go through the same extraction and filtering.
+/// Relations are skipped since the preprocessor does not use them.
+/// OsmChange files (.osc) are read the same way, see `changes`.
+pub struct OsmXmlReader<R: BufRead> {
+    reader: Reader<R>,
+    buf: Vec<u8>,
+    current: Option<OsmObj>,
+    action: Option<ChangeAction>,
+}
+
+/// The section of an OsmChange file an object is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeAction {
+    Create,
+    Modify,
+    Delete,
+}
+
+pub fn is_osm_xml(filename: &str) -> bool {
+    filename.ends_with(".osm") || filename.ends_with(".osm.bz2") || filename.ends_with(".osm.gz")
+}
+
+pub fn is_osm_change(filename: &str) -> bool {
+    filename.ends_with(".osc") || filename.ends_with(".osc.bz2") || filename.ends_with(".osc.gz")
+}
+
+pub fn open(filename: &str) -> OsmXmlReader<Box<dyn BufRead>> {
+    let file = std::fs::File::open(std::path::Path::new(filename))
+        .unwrap_or_else(|e| panic!("Could not open {}: {}", filename, e));
+    let reader: Box<dyn BufRead> = if filename.ends_with(".bz2") {
+        Box::new(std::io::BufReader::new(bzip2::read::MultiBzDecoder::new(file)))
+    } else if filename.ends_with(".gz") {
+        Box::new(std::io::BufReader::new(flate2::read::MultiGzDecoder::new(file)))
+    } else {
+        Box::new(std::io::BufReader::new(file))
+    };
+    OsmXmlReader::new(reader)
+}
+
+impl<R: BufRead> OsmXmlReader<R> {
+    pub fn new(reader: R) -> Self {
+        OsmXmlReader {
+            reader: Reader::from_reader(reader),
+            buf: Vec::new(),
+            current: None,
+            action: None,
+        }
+    }
+
+    /// The objects of an OsmChange file with the section they are in. Deleted objects only
+    /// have their id
+    pub fn changes(mut self) -> impl Iterator<Item = (ChangeAction, OsmObj)> {
+        std::iter::from_fn(move || loop {
+            if let (Some(action), obj) = self.next_object()? {
+                return Some((action, obj));
+            }
+        })
+    }
+
+    fn attribute(element: &BytesStart, name: &[u8]) -> Option<String> {
+        element
+            .attributes()
+            .map(|attr| attr.unwrap_or_else(|e| panic!("Malformed attribute: {}", e)))
+            .find(|attr| attr.key.as_ref() == name)
+            .map(|attr| attr.unescape_value().unwrap().into_owned())
+    }
+
+    fn required<T: std::str::FromStr>(element: &BytesStart, name: &[u8]) -> T {
+        Self::attribute(element, name)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_else(|| {
+                panic!(
+                    "Missing or invalid attribute {} on <{}>",
+                    String::from_utf8_lossy(name),
+                    String::from_utf8_lossy(element.name().as_ref())
+                )
+            })
+    }
+
+    fn is_deleted(element: &BytesStart) -> bool {
+        // Files saved by JOSM keep deleted objects around with action="delete"
+        Self::attribute(element, b"action").is_some_and(|v| v == "delete")
+            || Self::attribute(element, b"visible").is_some_and(|v| v == "false")
+    }
+
+    fn to_decimicro(degrees: f64) -> i32 {
+        (degrees * 1e7).round() as i32
+    }
+
+    fn start_object(element: &BytesStart, action: Option<ChangeAction>) -> Option<OsmObj> {
+        // Nodes deleted by a change file may come without coordinates
+        let degrees = |name: &[u8]| match action {
+            Some(ChangeAction::Delete) => Self::attribute(element, name)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0.0),
+            _ => Self::required(element, name),
+        };
+        match element.name().as_ref() {
+            b"node" => Some(OsmObj::Node(Node {
+                id: NodeId(Self::required(element, b"id")),
+                tags: Tags::new(),
+                decimicro_lat: Self::to_decimicro(degrees(b"lat")),
+                decimicro_lon: Self::to_decimicro(degrees(b"lon")),
+            })),
+            b"way" => Some(OsmObj::Way(Way {
+                id: WayId(Self::required(element, b"id")),
+                tags: Tags::new(),
+                nodes: Vec::new(),
+            })),
+            _ => None,
+        }
+    }
+
+    fn add_child(&mut self, element: &BytesStart) {
+        match (element.name().as_ref(), self.current.as_mut()) {
+            (b"tag", Some(OsmObj::Node(node))) => {
+                node.tags.insert(Self::required(element, b"k"), Self::required(element, b"v"));
+            }
+            (b"tag", Some(OsmObj::Way(way))) => {
+                way.tags.insert(Self::required(element, b"k"), Self::required(element, b"v"));
+            }
+            (b"nd", Some(OsmObj::Way(way))) => {
+                way.nodes.push(NodeId(Self::required(element, b"ref")));
+            }
+            _ => {}
+        }
+    }
+
+    fn change_action(element: &BytesStart) -> Option<ChangeAction> {
+        match element.name().as_ref() {
+            b"create" => Some(ChangeAction::Create),
+            b"modify" => Some(ChangeAction::Modify),
+            b"delete" => Some(ChangeAction::Delete),
+            _ => None,
+        }
+    }
+
+    /// The next node or way with the section of the change file it is in, None outside of one
+    fn next_object(&mut self) -> Option<(Option<ChangeAction>, OsmObj)> {
+        loop {
+            self.buf.clear();
+            let event = self
+                .reader
+                .read_event_into(&mut self.buf)
+                .unwrap_or_else(|e| panic!("Malformed OSM XML: {}", e))
+                .into_owned();
+            match event {
+                // Objects in a change file are reported even when deleted
+                Event::Start(element) => match element.name().as_ref() {
+                    b"node" | b"way" => {
+                        let deleted = self.action.is_none() && Self::is_deleted(&element);
+                        self.current = Some(Self::start_object(&element, self.action))
+                            .filter(|_| !deleted)
+                            .flatten();
+                    }
+                    b"create" | b"modify" | b"delete" => {
+                        self.action = Self::change_action(&element);
+                    }
+                    _ => self.add_child(&element),
+                },
+                Event::Empty(element) => match element.name().as_ref() {
+                    b"node" | b"way" if self.action.is_some() || !Self::is_deleted(&element) => {
+                        let obj = Self::start_object(&element, self.action)?;
+                        return Some((self.action, obj));
+                    }
+                    _ => self.add_child(&element),
+                },
+                Event::End(element) => match element.name().as_ref() {
+                    b"node" | b"way" => {
+                        if let Some(obj) = self.current.take() {
+                            return Some((self.action, obj));
+                        }
+                    }
+                    b"create" | b"modify" | b"delete" => self.action = None,
+                    _ => {}
+                },
+                Event::Eof => return None,
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for OsmXmlReader<R> {
+    type Item = OsmObj;
+
+    /// The next live node or way. Objects deleted by a change file are skipped
+    fn next(&mut self) -> Option<OsmObj> {
+        loop {
+            match self.next_object()? {
+                (Some(ChangeAction::Delete), _) => {}
+                (_, obj) => return Some(obj),
+            }
+        }
+    }
+}
+
+// TESTS
+#[test]
+fn reads_tags_and_node_refs() {
+    let objs: Vec<OsmObj> = open("src/test_data/hand_edited.osm").collect();
+    // three live nodes and two live ways, the relation is skipped
+    assert_eq!(3, objs.iter().filter(|obj| obj.is_node()).count());
+    assert_eq!(2, objs.iter().filter(|obj| obj.is_way()).count());
+    let way = objs[3].way().unwrap();
+    assert_eq!(vec![NodeId(-101), NodeId(-102), NodeId(-103)], way.nodes);
+    assert!(way.tags.contains("name", "Test & Vej"));
+    let node = objs[1].node().unwrap();
+    assert!(node.tags.contains("highway", "traffic_signals"));
+    assert_eq!(553291230, node.decimicro_lat);
+}
+
+#[test]
+fn reads_osm_change_sections() {
+    let changes: Vec<(ChangeAction, OsmObj)> =
+        open("src/test_data/grid_change.osc").changes().collect();
+    let count = |action| changes.iter().filter(|(a, _)| *a == action).count();
+    assert_eq!(3, count(ChangeAction::Create));
+    assert_eq!(2, count(ChangeAction::Modify));
+    assert_eq!(4, count(ChangeAction::Delete));
+    let (_, modified) = &changes[4];
+    let way = modified.way().unwrap();
+    assert_eq!(WayId(201), way.id);
+    assert!(!way.tags.contains_key("oneway"));
+    assert_eq!(
+        OsmObj::Way(Way {
+            id: WayId(303),
+            tags: Tags::new(),
+            nodes: Vec::new()
+        }),
+        changes[5].1
+    );
+    // Read as a plain file the deleted objects are left out
+    assert_eq!(5, open("src/test_data/grid_change.osc").count());
+}
@@ -0,0 +1,268 @@
+use crate::preprocessor::coord::Coord;
+use crate::preprocessor::routing::{Algorithm, Router, SnappedRoute};
+
+use serde_json::{json, Value};
+
+/*
+   Responses in the shape of OSRM's /route/v1 service, so tools written against OSRM can
+   use a rust_osm graph instead. Only the fields those tools read are filled: no steps,
+   annotations or hints. Edge costs are distances, so durations assume a fixed speed and the
+   weight is the distance.
+*/
+
+/// Meters per second used for durations, 50 km/h
+pub const ASSUMED_SPEED: f64 = 50.0 / 3.6;
+
+/// How the route geometry is returned, like OSRM's geometries parameter
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Geometries {
+    /// Encoded polyline with 5 decimals
+    Polyline,
+    /// Encoded polyline with 6 decimals
+    Polyline6,
+    /// A GeoJSON LineString
+    GeoJson,
+}
+
+impl std::str::FromStr for Geometries {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "polyline" => Ok(Geometries::Polyline),
+            "polyline6" => Ok(Geometries::Polyline6),
+            "geojson" => Ok(Geometries::GeoJson),
+            _ => Err(format!("unknown geometries {}", s)),
+        }
+    }
+}
+
+/// Encodes the coordinates with Google's polyline algorithm, lat before lon
+pub fn encode_polyline(coords: &[Coord], precision: u32) -> String {
+    let factor = 10f64.powi(precision as i32);
+    let mut encoded = String::new();
+    let mut previous = (0, 0);
+    for coord in coords {
+        let lat = (coord.lat * factor).round() as i64;
+        let lon = (coord.lon * factor).round() as i64;
+        encode_value(lat - previous.0, &mut encoded);
+        encode_value(lon - previous.1, &mut encoded);
+        previous = (lat, lon);
+    }
+    encoded
+}
+
+fn encode_value(value: i64, encoded: &mut String) {
+    let mut value = if value < 0 { !(value << 1) } else { value << 1 };
+    while value >= 0x20 {
+        encoded.push((((value & 0x1f) | 0x20) as u8 + 63) as char);
+        value >>= 5;
+    }
+    encoded.push((value as u8 + 63) as char);
+}
+
+pub fn decode_polyline(encoded: &str, precision: u32) -> Result<Vec<Coord>, String> {
+    let factor = 10f64.powi(precision as i32);
+    let mut bytes = encoded.bytes();
+    let mut next = || -> Result<Option<i64>, String> {
+        let (mut value, mut shift) = (0i64, 0);
+        loop {
+            // Longer values would overflow, no coordinate needs more than 35 bits
+            if shift > 30 {
+                return Err("polyline value is too long".to_owned());
+            }
+            let byte = match bytes.next() {
+                None if shift == 0 => return Ok(None),
+                Some(byte @ 63..=126) => (byte - 63) as i64,
+                _ => return Err("truncated or invalid polyline".to_owned()),
+            };
+            value |= (byte & 0x1f) << shift;
+            shift += 5;
+            if byte < 0x20 {
+                return Ok(Some(if value & 1 == 1 {
+                    !(value >> 1)
+                } else {
+                    value >> 1
+                }));
+            }
+        }
+    };
+    let (mut lat, mut lon) = (0, 0);
+    let mut coords = Vec::new();
+    while let Some(dlat) = next()? {
+        lat += dlat;
+        lon += next()?.ok_or("polyline ends after a latitude")?;
+        coords.push(Coord {
+            lat: lat as f64 / factor,
+            lon: lon as f64 / factor,
+        });
+    }
+    Ok(coords)
+}
+
+/// Parses OSRM's "lon,lat;lon,lat;..." coordinate lists
+pub fn parse_coordinates(s: &str) -> Result<Vec<Coord>, String> {
+    s.split(';')
+        .map(|pair| {
+            let (lon, lat) = pair
+                .split_once(',')
+                .ok_or(format!("{} is not lon,lat", pair))?;
+            match (lon.trim().parse::<f64>(), lat.trim().parse::<f64>()) {
+                (Ok(lon), Ok(lat)) if (Coord { lat, lon }).is_valid() => Ok(Coord { lat, lon }),
+                (Ok(_), Ok(_)) => Err(format!(
+                    "{} is outside the valid longitudes and latitudes",
+                    pair
+                )),
+                _ => Err(format!("{} is not lon,lat", pair)),
+            }
+        })
+        .collect()
+}
+
+fn geometry(coords: &[Coord], geometries: Geometries) -> Value {
+    match geometries {
+        Geometries::Polyline => json!(encode_polyline(coords, 5)),
+        Geometries::Polyline6 => json!(encode_polyline(coords, 6)),
+        Geometries::GeoJson => json!({
+            "type": "LineString",
+            "coordinates": coords.iter().map(|c| [c.lon, c.lat]).collect::<Vec<_>>(),
+        }),
+    }
+}
+
+fn leg(route: &SnappedRoute) -> Value {
+    let distance = route.route.cost as f64;
+    json!({
+        "steps": [],
+        "summary": "",
+        "weight": distance,
+        "duration": distance / ASSUMED_SPEED,
+        "distance": distance,
+    })
+}
+
+fn error(code: &str, message: &str) -> Value {
+    json!({ "code": code, "message": message })
+}
+
+impl Router {
+    /// Routes through the waypoints in order and answers like OSRM's /route/v1, with "code"
+    /// "Ok" on success and "NoSegment" or "NoRoute" otherwise
+    pub fn osrm_route(
+        &self,
+        waypoints: &[Coord],
+        algorithm: Algorithm,
+        geometries: Geometries,
+    ) -> Value {
+        if waypoints.len() < 2 {
+            return error("InvalidQuery", "Query string malformed close to position 0");
+        }
+        // Snapping only fails when the graph has no edges at all
+        if self.snap(waypoints[0]).is_none() {
+            return error(
+                "NoSegment",
+                "Could not find a matching segment for coordinate",
+            );
+        }
+        let mut legs = Vec::new();
+        for pair in waypoints.windows(2) {
+            match self.route_between_coords(pair[0], pair[1], algorithm) {
+                Some(route) => legs.push(route),
+                None => return error("NoRoute", "Impossible route between points"),
+            }
+        }
+        // Each leg starts where the previous one ended, so the joint is only included once
+        let mut coords = legs[0].coords.clone();
+        for route in &legs[1..] {
+            coords.extend_from_slice(&route.coords[1..]);
+        }
+        let distance: f64 = legs.iter().map(|route| route.route.cost as f64).sum();
+        let snapped = std::iter::once(legs[0].start.coord).chain(legs.iter().map(|r| r.end.coord));
+        let waypoints: Vec<Value> = snapped
+            .zip(waypoints)
+            .map(|(location, requested)| {
+                json!({
+                    "hint": "",
+                    "name": "",
+                    "distance": requested.distance_to(location) as f64,
+                    "location": [location.lon, location.lat],
+                })
+            })
+            .collect();
+        json!({
+            "code": "Ok",
+            "routes": [{
+                "geometry": geometry(&coords, geometries),
+                "legs": legs.iter().map(leg).collect::<Vec<_>>(),
+                "weight_name": "distance",
+                "weight": distance,
+                "duration": distance / ASSUMED_SPEED,
+                "distance": distance,
+            }],
+            "waypoints": waypoints,
+        })
+    }
+}
+
+// TESTS
+#[test]
+fn encodes_polylines_like_google() {
+    // The example from Google's polyline documentation
+    let coords = [
+        Coord {
+            lat: 38.5,
+            lon: -120.2,
+        },
+        Coord {
+            lat: 40.7,
+            lon: -120.95,
+        },
+        Coord {
+            lat: 43.252,
+            lon: -126.453,
+        },
+    ];
+    assert_eq!("_p~iF~ps|U_ulLnnqC_mqNvxq`@", encode_polyline(&coords, 5));
+    let decoded = decode_polyline(&encode_polyline(&coords, 6), 6).unwrap();
+    for (a, b) in coords.iter().zip(&decoded) {
+        assert!((a.lat - b.lat).abs() < 1e-9 && (a.lon - b.lon).abs() < 1e-9);
+    }
+    assert!(decode_polyline("_p~iF~ps|U_", 5).is_err());
+    // Continuation bytes without end
+    assert!(decode_polyline(&"~".repeat(20), 5).is_err());
+}
+
+#[test]
+fn answers_in_osrm_shape() {
+    let router = crate::preprocessor::routing::grid_router();
+
+    let waypoints = parse_coordinates("10.0001,55.0004;10.0089,55.006;10.004,55.0002").unwrap();
+    let response = router.osrm_route(&waypoints, Algorithm::AStar, Geometries::Polyline6);
+    assert_eq!("Ok", response["code"]);
+    let route = &response["routes"][0];
+    let legs = route["legs"].as_array().unwrap();
+    assert_eq!(2, legs.len());
+    let distance = route["distance"].as_f64().unwrap();
+    let leg_distances: f64 = legs
+        .iter()
+        .map(|leg| leg["distance"].as_f64().unwrap())
+        .sum();
+    assert!((distance - leg_distances).abs() < 1e-6);
+    assert!((route["duration"].as_f64().unwrap() - distance / ASSUMED_SPEED).abs() < 1e-6);
+    assert_eq!(3, response["waypoints"].as_array().unwrap().len());
+
+    // The geometry starts and ends at the snapped waypoints
+    let coords = decode_polyline(route["geometry"].as_str().unwrap(), 6).unwrap();
+    let first = &response["waypoints"][0]["location"];
+    let last = &response["waypoints"][2]["location"];
+    assert!((coords[0].lon - first[0].as_f64().unwrap()).abs() < 1e-6);
+    assert!((coords[coords.len() - 1].lat - last[1].as_f64().unwrap()).abs() < 1e-6);
+
+    let response = router.osrm_route(&waypoints[..1], Algorithm::Dijkstra, Geometries::GeoJson);
+    assert_eq!("InvalidQuery", response["code"]);
+    assert!(parse_coordinates("10.0;55.0").is_err());
+    assert!(parse_coordinates("NaN,55.0").is_err());
+    assert!(parse_coordinates("10.0,inf").is_err());
+    assert!(parse_coordinates("10.0,91").is_err());
+    assert!(parse_coordinates("-181,55.0").is_err());
+}
@@ -0,0 +1,392 @@
+use crate::preprocessor::coord::Coord;
+use crate::preprocessor::edge::Edge;
+use crate::preprocessor::matrix::location_lines;
+use crate::preprocessor::origins::{EdgeOrigin, EdgeOrigins};
+use crate::preprocessor::routing::Algorithm;
+
+use osmpbfreader::{NodeId, WayId};
+use std::collections::{HashMap, HashSet};
+
+/*
+   Live traffic and closures on a loaded graph, without preprocessing it again.
+   Updates name roads by their OSM ids: a whole way, the stretch from one node to the next in
+   one direction, or a node. EdgeOrigins, written together with the graph, tells which edges of
+   the graph they fall on and how many meters of each edge they cover. An update changes the
+   cost of that part of every edge it falls on: blocking makes the edge unusable, scaling
+   multiplies the part and setting replaces it. The part of a node is every whole edge through
+   it. Several updates on the same edge add up their changes.
+   The overlay only holds the changed costs. Router::set_overlay makes routes use them.
+   Landmark distances were computed with the costs in the graph, so their bounds stay valid as
+   long as no cost goes down. When one does, routes with landmarks use A* instead, and routes
+   with A* use Dijkstra once an edge costs less than the straight line between its ends.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpdateTarget {
+    Way(WayId),
+    /// The stretch from one OSM node to the next, in this direction only
+    Segment(NodeId, NodeId),
+    /// Every edge through or to the OSM node
+    Node(NodeId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CostChange {
+    Block,
+    /// Multiplies the cost
+    Scale(f32),
+    /// Replaces the cost, in meters
+    Set(f32),
+}
+
+impl CostChange {
+    /// Whether the change gives a cost, which factors and meters that are negative or not
+    /// finite do not
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            CostChange::Block => true,
+            CostChange::Scale(value) | CostChange::Set(value) => value.is_finite() && value >= 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EdgeUpdate {
+    pub target: UpdateTarget,
+    pub change: CostChange,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Overlay {
+    /// Changed cost of every edge by its ends, infinite for blocked edges
+    costs: HashMap<(NodeId, NodeId), f32>,
+    /// Whether any cost went down, which breaks landmark bounds
+    lowers_costs: bool,
+    /// Whether any edge costs less than the straight line between its ends, which breaks A*
+    below_straight_line: bool,
+    /// Indices of the updates that fell on no edge, or were not valid
+    pub unmatched: Vec<usize>,
+}
+
+/// The cost of the part of the edge the target covers, None if it is not on the edge
+fn covered_cost(origin: &EdgeOrigin, target: UpdateTarget, edge_cost: f32) -> Option<f32> {
+    match target {
+        UpdateTarget::Way(way) => {
+            let on_way = origin.ways.iter().filter(|(w, _)| *w == way);
+            on_way.map(|(_, meters)| *meters).reduce(|a, b| a + b)
+        }
+        UpdateTarget::Segment(a, b) => origin
+            .nodes
+            .windows(2)
+            .zip(&origin.lengths)
+            .filter(|(pair, _)| pair[0] == a && pair[1] == b)
+            .map(|(_, length)| *length)
+            .reduce(|a, b| a + b),
+        UpdateTarget::Node(node) => origin.nodes.contains(&node).then_some(edge_cost),
+    }
+}
+
+impl Overlay {
+    /// The changed costs of the edges of graph the updates fall on. origins must have been
+    /// written with graph
+    pub fn new(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        coords: &HashMap<NodeId, Coord>,
+        origins: &EdgeOrigins,
+        updates: &[EdgeUpdate],
+    ) -> Overlay {
+        let mut ways = HashSet::new();
+        let mut nodes = HashSet::new();
+        for update in updates {
+            match update.target {
+                UpdateTarget::Way(way) => ways.insert(way),
+                UpdateTarget::Segment(node, _) | UpdateTarget::Node(node) => nodes.insert(node),
+            };
+        }
+        let mut overlay = Overlay::default();
+        let mut matched = vec![false; updates.len()];
+        for origin in &origins.edges {
+            let on_way = origin.ways.iter().any(|(way, _)| ways.contains(way));
+            if !on_way && !origin.nodes.iter().any(|node| nodes.contains(node)) {
+                continue;
+            }
+            let edges = graph.get(&origin.from).into_iter().flatten();
+            let Some(edge) = edges.into_iter().find(|edge| edge.node == origin.to) else {
+                continue;
+            };
+            let mut cost = edge.cost;
+            let mut changed = false;
+            for (i, update) in updates.iter().enumerate() {
+                if !update.change.is_valid() {
+                    continue;
+                }
+                let Some(covered) = covered_cost(origin, update.target, edge.cost) else {
+                    continue;
+                };
+                matched[i] = true;
+                changed = true;
+                cost += match update.change {
+                    CostChange::Block => f32::INFINITY,
+                    CostChange::Scale(factor) => covered * (factor - 1.0),
+                    CostChange::Set(meters) => meters - covered,
+                };
+            }
+            if !changed {
+                continue;
+            }
+            let cost = cost.max(0.0);
+            overlay.lowers_costs |= cost < edge.cost;
+            let straight_line = coords[&origin.from].distance_to(coords[&origin.to]);
+            overlay.below_straight_line |= cost < straight_line * 0.999;
+            overlay.costs.insert((origin.from, origin.to), cost);
+        }
+        overlay.unmatched = (0..updates.len()).filter(|i| !matched[*i]).collect();
+        overlay
+    }
+
+    /// The cost of an edge leaving from, infinite when it is blocked
+    pub fn cost(&self, from: NodeId, edge: &Edge) -> f32 {
+        self.costs
+            .get(&(from, edge.node))
+            .copied()
+            .unwrap_or(edge.cost)
+    }
+
+    /// How many edges have another cost
+    pub fn changed_edges(&self) -> usize {
+        self.costs.len()
+    }
+
+    /// The algorithm that still finds optimal routes with the changed costs
+    pub fn admissible(&self, algorithm: Algorithm) -> Algorithm {
+        match algorithm {
+            Algorithm::Landmarks if self.lowers_costs => self.admissible(Algorithm::AStar),
+            Algorithm::AStar if self.below_straight_line => Algorithm::Dijkstra,
+            algorithm => algorithm,
+        }
+    }
+}
+
+/// Reads one update per line, skipping blank lines and # comments:
+/// "way <id> <change>", "segment <from node> <to node> <change>" or "node <id> <change>",
+/// where the change is "block", "scale <factor>" or "set <meters>"
+pub fn read_edge_updates(text: &str) -> Result<Vec<EdgeUpdate>, String> {
+    location_lines(text)
+        .map(|(number, line)| {
+            let invalid = || format!("line {}: cannot read the update {}", number, line);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let id = |word: &str| word.parse::<i64>().map_err(|_| invalid());
+            let (target, change) = match words.as_slice() {
+                ["way", way, change @ ..] => (UpdateTarget::Way(WayId(id(way)?)), change),
+                ["segment", a, b, change @ ..] => (
+                    UpdateTarget::Segment(NodeId(id(a)?), NodeId(id(b)?)),
+                    change,
+                ),
+                ["node", node, change @ ..] => (UpdateTarget::Node(NodeId(id(node)?)), change),
+                _ => return Err(invalid()),
+            };
+            let number = |word: &str| word.parse::<f32>().map_err(|_| invalid());
+            let change = match change {
+                ["block"] => CostChange::Block,
+                ["scale", factor] => CostChange::Scale(number(factor)?),
+                ["set", meters] => CostChange::Set(number(meters)?),
+                _ => return Err(invalid()),
+            };
+            // Negative or infinite factors and meters are no cost
+            if !change.is_valid() {
+                return Err(invalid());
+            }
+            Ok(EdgeUpdate { target, change })
+        })
+        .collect()
+}
+
+// TESTS
+#[test]
+fn reads_edge_updates() {
+    let updates =
+        read_edge_updates("# closures\nway 200 block\nsegment 1 2 scale 2.5\n\nnode 7 set 100\n")
+            .unwrap();
+    assert_eq!(
+        vec![
+            EdgeUpdate {
+                target: UpdateTarget::Way(WayId(200)),
+                change: CostChange::Block
+            },
+            EdgeUpdate {
+                target: UpdateTarget::Segment(NodeId(1), NodeId(2)),
+                change: CostChange::Scale(2.5)
+            },
+            EdgeUpdate {
+                target: UpdateTarget::Node(NodeId(7)),
+                change: CostChange::Set(100.0)
+            },
+        ],
+        updates
+    );
+    assert!(read_edge_updates("way 200").is_err());
+    assert!(read_edge_updates("road 200 block").is_err());
+    assert!(read_edge_updates("segment 1 block").is_err());
+    for line in [
+        "way 1 scale nan",
+        "way 1 scale -2",
+        "node 1 set inf",
+        "node 1 set -5",
+    ] {
+        assert!(read_edge_updates(line).is_err());
+    }
+}
+
+#[test]
+fn closures_reroute_with_valid_landmarks() {
+    use crate::preprocessor::preprocessor::{preprocess_grid, Preprocessor};
+    use crate::preprocessor::routing::Router;
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.record_origins = true;
+    let mut router = Router::new(&preprocess_grid(&mut preprocessor));
+    let origins = preprocessor.origins.unwrap();
+    let graph = router.graph.clone();
+    let (start, end) = (NodeId(0), NodeId(graph.len() as i64 - 1));
+    let before = router
+        .route_between_nodes(start, end, Algorithm::Landmarks)
+        .unwrap();
+    let by_edge = origins.by_edge();
+    let first = by_edge[&(before.nodes[0], before.nodes[1])];
+
+    // Closing the first stretch of the route in its direction sends it another way
+    let closed = EdgeUpdate {
+        target: UpdateTarget::Segment(first.nodes[0], first.nodes[1]),
+        change: CostChange::Block,
+    };
+    let unknown = EdgeUpdate {
+        target: UpdateTarget::Way(WayId(999)),
+        change: CostChange::Scale(2.0),
+    };
+    let invalid = EdgeUpdate {
+        target: closed.target,
+        change: CostChange::Scale(f32::NAN),
+    };
+    let overlay = Overlay::new(
+        &router.graph,
+        &router.coords,
+        &origins,
+        &[closed, unknown, invalid],
+    );
+    assert_eq!(vec![1, 2], overlay.unmatched);
+    assert_eq!(1, overlay.changed_edges());
+    assert_eq!(
+        Algorithm::Landmarks,
+        overlay.admissible(Algorithm::Landmarks)
+    );
+    router.set_overlay(Some(overlay));
+    let after = router
+        .route_between_nodes(start, end, Algorithm::Landmarks)
+        .unwrap();
+    assert_ne!(before.nodes[1], after.nodes[1]);
+    assert!(after.cost >= before.cost);
+    for algorithm in [Algorithm::Dijkstra, Algorithm::AStar] {
+        let route = router.route_between_nodes(start, end, algorithm).unwrap();
+        assert!((route.cost - after.cost).abs() < 1e-2);
+    }
+    // The way back is still open
+    let back = router.route_between_nodes(before.nodes[1], start, Algorithm::Dijkstra);
+    assert!(back.is_some());
+
+    // Slower traffic on a way costs in proportion to the meters driven on it
+    let (way, meters) = first.ways[0];
+    let scaled = EdgeUpdate {
+        target: UpdateTarget::Way(way),
+        change: CostChange::Scale(3.0),
+    };
+    let overlay = Overlay::new(&router.graph, &router.coords, &origins, &[scaled]);
+    let edge = Edge::new(
+        before.nodes[1],
+        graph[&start]
+            .iter()
+            .find(|e| e.node == before.nodes[1])
+            .unwrap()
+            .cost,
+    );
+    assert!((overlay.cost(start, &edge) - (edge.cost + 2.0 * meters)).abs() < 1e-2);
+
+    // Cheaper roads make the bounds overestimate, so the searches fall back to Dijkstra
+    let cheaper = EdgeUpdate {
+        target: UpdateTarget::Node(first.nodes[0]),
+        change: CostChange::Set(0.0),
+    };
+    let overlay = Overlay::new(&router.graph, &router.coords, &origins, &[cheaper]);
+    assert_eq!(
+        Algorithm::Dijkstra,
+        overlay.admissible(Algorithm::Landmarks)
+    );
+    router.set_overlay(Some(overlay));
+    let dijkstra = router.route_between_nodes(start, end, Algorithm::Dijkstra);
+    let landmarks = router.route_between_nodes(start, end, Algorithm::Landmarks);
+    assert_eq!(dijkstra.unwrap().cost, landmarks.unwrap().cost);
+
+    router.set_overlay(None);
+    let reset = router
+        .route_between_nodes(start, end, Algorithm::Landmarks)
+        .unwrap();
+    assert_eq!(before.cost, reset.cost);
+}
+
+#[test]
+fn matrices_and_isochrones_use_the_overlay() {
+    use crate::preprocessor::isochrone::Budget;
+    use crate::preprocessor::preprocessor::{preprocess_grid, Preprocessor};
+    use crate::preprocessor::routing::Router;
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.record_origins = true;
+    let mut router = Router::new(&preprocess_grid(&mut preprocessor));
+    let origins = preprocessor.origins.unwrap();
+    let (start, closed, end) = (NodeId(0), NodeId(5), NodeId(router.graph.len() as i64 - 1));
+    let coords: Vec<Coord> = [start, closed, end]
+        .iter()
+        .map(|node| router.coords[node])
+        .collect();
+    let reaches_closed = |router: &Router| {
+        let isochrone = router.isochrone(coords[0], Budget::Meters(1e6), 500.0);
+        let reachable = isochrone.unwrap().reachable;
+        reachable.iter().any(|(node, _)| *node == closed)
+    };
+    assert!(reaches_closed(&router));
+    assert!(router.distance_matrix(&coords, &coords)[0][1] < f32::MAX);
+
+    // Closing a junction closes every edge through it
+    let block = EdgeUpdate {
+        target: UpdateTarget::Node(origins.osm_nodes[closed.0 as usize]),
+        change: CostChange::Block,
+    };
+    router.set_overlay(Some(Overlay::new(
+        &router.graph,
+        &router.coords,
+        &origins,
+        &[block],
+    )));
+    assert!(!reaches_closed(&router));
+    let matrix = router.distance_matrix(&coords, &coords);
+    assert_eq!(f32::MAX, matrix[0][1]);
+    let route = router.route_between_coords(coords[0], coords[2], Algorithm::Dijkstra);
+    assert!((route.unwrap().route.cost - matrix[0][2]).abs() < 1e-2);
+
+    // Slower ways make the matrix agree with the routes, not with the graph
+    let (way, _) = origins.by_edge()[&(start, router.graph[&start][0].node)].ways[0];
+    let slower = EdgeUpdate {
+        target: UpdateTarget::Way(way),
+        change: CostChange::Scale(5.0),
+    };
+    router.set_overlay(None);
+    let before = router.distance_matrix(&coords, &coords);
+    router.set_overlay(Some(Overlay::new(
+        &router.graph,
+        &router.coords,
+        &origins,
+        &[slower],
+    )));
+    let matrix = router.distance_matrix(&coords, &coords);
+    assert!(matrix[0][2] >= before[0][2]);
+    for (i, j) in [(0, 1), (0, 2), (2, 0), (1, 2)] {
+        let route = router.route_between_coords(coords[i], coords[j], Algorithm::Dijkstra);
+        assert!((route.unwrap().route.cost - matrix[i][j]).abs() < 1e-2);
+    }
+}
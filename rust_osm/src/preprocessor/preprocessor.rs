@@ -1,53 +1,112 @@
 use crate::preprocessor::coord::Coord;
 
-use crate::{azimuthal_equidistant_projection, Graph};
-use osmpbfreader::NodeId;
+use crate::Graph;
+use osmpbfreader::{NodeId, WayId};
 use rayon::iter::{FromParallelIterator, IntoParallelRefIterator, ParallelIterator};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-
-use rmp_serde::Serializer;
+use std::ops::ControlFlow;
 
 use super::edge::Edge;
-
-#[derive(Debug, Clone, Serialize)]
-pub struct Node {
-    pub id: NodeId,
-    pub coord: Coord,
-}
-
-#[derive(Debug, Clone)]
+use super::external::MemoryBudget;
+use super::graph_file::{Compression, GraphFileError, GraphHeader, GraphWriter};
+use super::graph_format::{BincodeFormat, GraphFormat, JsonFormat, MessagePackFormat};
+use super::origins::{self, EdgeOrigins};
+use super::osm_xml;
+use super::projection::{Projection, ProjectionKind};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Road {
+    pub id: WayId,
     pub node_refs: Vec<NodeId>,
     pub direction: CarDirection,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum CarDirection {
     Forward,
     Twoway,
 }
 
+/// How the input files are read.
+/// `TwoPass` reads the ways first and then only the nodes they reference. When the first pass
+/// sees that all nodes come before the ways (as in Geofabrik extracts) the node pass stops at
+/// the first way, otherwise it reads the whole file.
+/// `SinglePass` reads every file once and keeps all node coordinates until the ways are known,
+/// which costs more memory but avoids decoding the file twice.
+/// `External` keeps roads and node coordinates in temporary files instead of memory and
+/// only loads the graph between junctions, see `external.rs`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Ingestion {
+    TwoPass,
+    SinglePass,
+    External(MemoryBudget),
+}
+
+/// Layout written by `write_graph`.
+/// `MessagePack` is what the Unity client reads, `Bincode` and `Json` are written through the
+/// backends in `graph_format.rs`. `Flat` is a memory-mappable layout of aligned arrays that
+/// `MappedGraph` uses without parsing, see `flat_graph.rs`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OutputFormat {
+    MessagePack,
+    Bincode,
+    Json,
+    Flat,
+}
+
+/// Result of checking that every node referenced by a kept road was found in the input
+#[derive(Debug, Default)]
+pub struct IngestionReport {
+    pub missing_nodes: HashSet<NodeId>,
+    pub ways_with_missing_nodes: Vec<WayId>,
+}
+
+impl IngestionReport {
+    pub fn is_complete(&self) -> bool {
+        self.missing_nodes.is_empty()
+    }
+}
+
 #[derive(Clone)]
 pub struct Preprocessor {
     pub nodes: HashMap<NodeId, Coord>,
     pub roads: Vec<Road>,
+    pub ingestion: Ingestion,
+    pub compressed_graph: Option<HashMap<NodeId, Vec<Edge>>>,
+    /// An already minimized graph, which build_graph uses instead of minimizing, see road_store.rs
+    pub minimized_graph: Option<HashMap<NodeId, Vec<Edge>>>,
+    /// Set to Some before reading to also keep the nodes of the ways that are not roads, there
+    /// instead of in nodes, for road_store
+    pub way_nodes: Option<HashMap<NodeId, Coord>>,
+    pub source_files: Vec<String>,
+    pub projection: ProjectionKind,
+    /// (lat, lon) to project around instead of the mean of all nodes
+    pub custom_center: Option<(f64, f64)>,
+    /// Set to have build_graph fill origins with the OSM ways and nodes of every edge
+    pub record_origins: bool,
+    pub origins: Option<EdgeOrigins>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct FullGraph {
+    #[serde(skip)]
+    pub header: GraphHeader,
     pub nodes: Vec<NodeWriteFormat>,
     pub landmarks: Vec<Landmark>,
+    /// Node ids in k-d tree order, see spatial_index.rs. Left out of the file when None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spatial_index: Option<Vec<NodeId>>,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Landmark {
     pub node_id: NodeId,
     pub distances: Vec<f32>,
     pub bi_distances: Vec<f32>,
 }
 
-#[derive(Serialize,Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct NodeWriteFormat {
     pub node_id: NodeId,
     pub x: f32,
@@ -58,7 +117,28 @@ pub struct NodeWriteFormat {
     pub bi_neighbours: Vec<(NodeId, f32)>,
 }
 
-fn create_blacklist() -> HashSet<&'static str> {
+/// The name of the file without its directories, as kept in source_files
+pub(crate) fn source_file_name(filename: &str) -> String {
+    let path = std::path::Path::new(filename);
+    path.file_name().map_or(filename.to_string(), |name| {
+        name.to_string_lossy().into_owned()
+    })
+}
+
+/// The parts of road between the nodes missing from nodes that still have an edge
+pub(crate) fn split_at_missing_nodes(road: &Road, nodes: &HashMap<NodeId, Coord>) -> Vec<Road> {
+    road.node_refs
+        .split(|node| !nodes.contains_key(node))
+        .filter(|part| part.len() >= 2)
+        .map(|part| Road {
+            id: road.id,
+            node_refs: part.to_vec(),
+            direction: road.direction.clone(),
+        })
+        .collect()
+}
+
+pub(crate) fn create_blacklist() -> HashSet<&'static str> {
     HashSet::from_iter([
         "pedestrian",
         "footway",
@@ -92,10 +172,11 @@ fn create_blacklist() -> HashSet<&'static str> {
 impl Preprocessor {
     pub fn is_valid_highway(&self, blacklist: &HashSet<&str>, tags: &osmpbfreader::Tags) -> bool {
         tags.iter()
-            .any(|(k, v)| (k == "highway" && !blacklist.contains(v.as_str())))
+            .any(|(k, v)| k == "highway" && !blacklist.contains(v.as_str()))
             && !tags.contains_key("area")
     }
 
+    #[allow(clippy::type_complexity)]
     pub fn build_graph(
         &mut self,
     ) -> (
@@ -104,25 +185,45 @@ impl Preprocessor {
         Vec<Landmark>,
     ) {
         let time = std::time::Instant::now();
-        let mut graph = Graph::build_graph(&self.nodes, &self.roads);
+        let mut graph = match self.compressed_graph.take() {
+            Some(graph) => graph,
+            None => Graph::build_graph(&self.nodes, &self.roads),
+        };
         println!("Size of graph: {}", graph.len());
+        let original = self
+            .record_origins
+            .then(|| (graph.clone(), origins::road_ways(&self.roads)));
         self.roads = Vec::new(); // Clear the roads since we don't need them anymore
         println!("Time to build graph: {:?}", time.elapsed());
-        let time = std::time::Instant::now();
-        Graph::minimize_graph(&mut graph, true);
-        println!("Time to minimize graph: {:?}", time.elapsed());
+        if let Some(minimized) = self.minimized_graph.take() {
+            graph = minimized;
+        } else {
+            let time = std::time::Instant::now();
+            Graph::minimize_graph(&mut graph, true);
+            println!("Time to minimize graph: {:?}", time.elapsed());
+        }
+        let origins = original.map(|(original, ways)| EdgeOrigins::trace(&original, &ways, &graph));
 
-        Preprocessor::rewrite_ids(&mut self.nodes, &mut graph);
+        let old_to_new = Preprocessor::rewrite_ids(&mut self.nodes, &mut graph);
+        self.origins = origins.map(|mut origins| {
+            origins.rewrite_ids(&old_to_new, graph.len());
+            origins
+        });
 
         let bi_graph = Graph::get_bidirectional_graph(&graph);
         //let mut landmarks = Graph::random_landmarks(&graph, &bi_graph, 16);
         let mut landmarks = Graph::farthest_landmarks(&graph, &bi_graph, 16);
-        landmarks.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        landmarks.sort_by_key(|a| a.node_id);
 
         (graph, bi_graph, landmarks.to_vec())
     }
 
-    pub fn rewrite_ids(nodes: &mut HashMap<NodeId, Coord>, graph: &mut HashMap<NodeId, Vec<Edge>>) {
+    /// Numbers the graph nodes 0..n and the other nodes after them. Returns the new id of
+    /// every old id
+    pub fn rewrite_ids(
+        nodes: &mut HashMap<NodeId, Coord>,
+        graph: &mut HashMap<NodeId, Vec<Edge>>,
+    ) -> HashMap<NodeId, NodeId> {
         let mut new_id = 0;
         let mut old_to_new: HashMap<NodeId, NodeId> = HashMap::new();
     
@@ -132,15 +233,15 @@ impl Preprocessor {
         for node in &sorted_nodes {
             let edges = graph.get_mut(node).unwrap();
             let mut new_edges = Vec::new();
-            if !old_to_new.contains_key(node) {
-                old_to_new.insert(*node, NodeId(new_id));
+            old_to_new.entry(*node).or_insert_with(|| {
                 new_id += 1;
-            }
+                NodeId(new_id - 1)
+            });
             for edge in edges.iter_mut() {
-                if !old_to_new.contains_key(&edge.node) {
-                    old_to_new.insert(edge.node, NodeId(new_id));
+                old_to_new.entry(edge.node).or_insert_with(|| {
                     new_id += 1;
-                }
+                    NodeId(new_id - 1)
+                });
     
                 new_edges.push(Edge {
                     node: old_to_new[&edge.node],
@@ -160,9 +261,10 @@ impl Preprocessor {
                 old_to_new.insert(*node, NodeId(new_id));
                 new_id += 1;
             }
-            new_nodes.insert(old_to_new[node], coord.clone());
+            new_nodes.insert(old_to_new[node], *coord);
         }
         *nodes = new_nodes;
+        old_to_new
     }
 
     pub fn build_full_graph(
@@ -171,6 +273,21 @@ impl Preprocessor {
         bi_graph: &HashMap<NodeId, Vec<Edge>>,
         landmarks: Vec<Landmark>,
         projected_points: &HashMap<NodeId, (f32, f32)>,
+    ) -> FullGraph {
+        let coords = &self.nodes;
+        self.full_graph_from(graph, bi_graph, landmarks, coords, projected_points, "distance")
+    }
+
+    /// A FullGraph of the given graph with its nodes placed at coords, see turn_graph.rs for one
+    /// whose nodes are not the nodes of the map
+    pub(crate) fn full_graph_from(
+        &self,
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        bi_graph: &HashMap<NodeId, Vec<Edge>>,
+        landmarks: Vec<Landmark>,
+        coords: &HashMap<NodeId, Coord>,
+        projected_points: &HashMap<NodeId, (f32, f32)>,
+        metric: &str,
     ) -> FullGraph {
         let mut nodes: Vec<NodeWriteFormat> = graph
             .iter()
@@ -184,7 +301,7 @@ impl Preprocessor {
                     .map(|edge| (edge.node, edge.cost))
                     .collect();
 
-                let node = self.nodes.get(node_id).unwrap();
+                let node = coords.get(node_id).unwrap();
                 NodeWriteFormat {
                     node_id: *node_id,
                     x: *x,
@@ -196,90 +313,303 @@ impl Preprocessor {
                 }
             })
             .collect();
-        nodes.sort_by(|a, b| a.node_id.cmp(&b.node_id));
-        FullGraph { nodes, landmarks }
+        nodes.sort_by_key(|a| a.node_id);
+        let header = GraphHeader {
+            source_files: self.source_files.clone(),
+            created: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            profile: "car".to_owned(),
+            metric: metric.to_owned(),
+            projection: self.projection().name(),
+            projection_center: self.projection_center(),
+            node_count: nodes.len() as u64,
+            edge_count: nodes.iter().map(|node| node.neighbours.len() as u64).sum(),
+            landmark_count: landmarks.len() as u64,
+        };
+        FullGraph {
+            header,
+            nodes,
+            landmarks,
+            spatial_index: None,
+        }
     }
 
-    pub fn write_graph(full_graph: FullGraph, filename: &str) {
-        /*
-           Format:
-           nodeId x y neighbour cost neighbour cost \n
-        */
+    pub fn write_graph(
+        full_graph: FullGraph,
+        filename: &str,
+        format: OutputFormat,
+        compression: Compression,
+    ) -> Result<(), GraphFileError> {
+        // The file layouts are described in graph_file.rs and flat_graph.rs
         let filename = "../OSM_Unity_Client/Assets/Maps/".to_owned() + filename;
+        if format == OutputFormat::Flat && compression != Compression::None {
+            return Err(GraphFileError::Encode(
+                "flat graphs are memory-mapped and cannot be compressed".to_owned(),
+            ));
+        }
 
-        let mut buf = Vec::new();
-        full_graph
-            .serialize(&mut Serializer::new(&mut buf))
-            .unwrap();
-        std::fs::write(filename, buf).unwrap();
+        // Serialized straight into the file instead of building the whole file in memory first
+        let mut writer = GraphWriter::create(&filename, compression)?;
+        match format {
+            OutputFormat::MessagePack => MessagePackFormat::write(&full_graph, &mut writer)?,
+            OutputFormat::Bincode => BincodeFormat::write(&full_graph, &mut writer)?,
+            OutputFormat::Json => JsonFormat::write(&full_graph, &mut writer)?,
+            OutputFormat::Flat => full_graph.write_flat(&mut writer)?,
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    pub fn get_roads_and_nodes(&mut self, filename: &str) -> IngestionReport {
+        self.get_roads_and_nodes_from_files(&[filename])
     }
 
-    pub fn get_roads_and_nodes(&mut self, filename: &str) {
-        let r = std::fs::File::open(&std::path::Path::new(filename)).unwrap();
-        let mut pbf = osmpbfreader::OsmPbfReader::new(r);
+    pub fn get_roads_and_nodes_from_files(&mut self, filenames: &[&str]) -> IngestionReport {
+        /*
+           Extracts overlapping at the borders share nodes and ways with the same OSM ids,
+           so ways are only kept the first time they are seen and nodes are keyed by id.
+           This makes the border nodes connect the extracts into one graph.
+        */
+        self.source_files.extend(filenames.iter().map(|filename| source_file_name(filename)));
+        let mut seen_ways: HashSet<WayId> = self.roads.iter().map(|road| road.id).collect();
         let mut nodes_to_keep: Vec<NodeId> = Vec::new();
+        match self.ingestion.clone() {
+            Ingestion::TwoPass => {
+                let mut sorted = Vec::new();
+                for filename in filenames {
+                    sorted.push(self.get_roads(filename, &mut seen_ways, &mut nodes_to_keep));
+                }
+                let nodes_to_keep_hashset = HashSet::from_par_iter(nodes_to_keep);
+
+                for (filename, sorted) in filenames.iter().zip(sorted) {
+                    self.get_nodes(filename, &nodes_to_keep_hashset, sorted);
+                }
+            }
+            Ingestion::SinglePass => {
+                let mut all_nodes: Vec<(NodeId, Coord)> = Vec::new();
+                for filename in filenames {
+                    self.get_roads_and_all_nodes(
+                        filename,
+                        &mut seen_ways,
+                        &mut nodes_to_keep,
+                        &mut all_nodes,
+                    );
+                }
+                let nodes_to_keep_hashset: HashSet<NodeId> = HashSet::from_par_iter(nodes_to_keep);
+                for (id, coord) in all_nodes {
+                    if nodes_to_keep_hashset.contains(&id) {
+                        self.nodes.entry(id).or_insert(coord);
+                    }
+                }
+            }
+            Ingestion::External(budget) => {
+                return self.get_compressed_graph_external(filenames, &mut seen_ways, &budget);
+            }
+        }
+        if let Some(way_nodes) = &mut self.way_nodes {
+            let road_nodes: HashSet<NodeId> =
+                self.roads.iter().flat_map(|road| road.node_refs.iter().copied()).collect();
+            let other_nodes: Vec<NodeId> =
+                self.nodes.keys().filter(|node| !road_nodes.contains(node)).copied().collect();
+            for node in other_nodes {
+                way_nodes.insert(node, self.nodes.remove(&node).unwrap());
+            }
+        }
+        self.remove_missing_nodes()
+    }
+
+    pub fn remove_missing_nodes(&mut self) -> IngestionReport {
+        /*
+           Roads referencing nodes that were not in the input are split at the missing nodes,
+           so no edge is made across the gap and Graph::build_graph does not look up unknown nodes
+        */
+        let mut report = IngestionReport::default();
+        let mut roads = Vec::with_capacity(self.roads.len());
+        for road in std::mem::take(&mut self.roads) {
+            if road.node_refs.iter().all(|node| self.nodes.contains_key(node)) {
+                roads.push(road);
+                continue;
+            }
+            report.ways_with_missing_nodes.push(road.id);
+            roads.extend(split_at_missing_nodes(&road, &self.nodes));
+            report.missing_nodes.extend(
+                road.node_refs
+                    .iter()
+                    .filter(|node| !self.nodes.contains_key(node)),
+            );
+        }
+        self.roads = roads;
+        if !report.is_complete() {
+            println!(
+                "{} referenced nodes were missing from the input, affecting {} ways",
+                report.missing_nodes.len(),
+                report.ways_with_missing_nodes.len()
+            );
+        }
+        report
+    }
+
+    pub(crate) fn read_objects<F>(filename: &str, mut f: F)
+    where
+        F: FnMut(osmpbfreader::OsmObj) -> ControlFlow<()>,
+    {
+        if osm_xml::is_osm_xml(filename) {
+            for obj in osm_xml::open(filename) {
+                if f(obj).is_break() {
+                    break;
+                }
+            }
+        } else {
+            let r = std::fs::File::open(std::path::Path::new(filename)).unwrap();
+            let mut pbf = osmpbfreader::OsmPbfReader::new(r);
+            for obj in pbf.par_iter().map(Result::unwrap) {
+                if f(obj).is_break() {
+                    break;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn to_road(&self, blacklist: &HashSet<&str>, way: osmpbfreader::Way) -> Option<Road> {
+        if !self.is_valid_highway(blacklist, &way.tags) {
+            return None;
+        }
+        let oneway = way.tags.get("oneway").is_some_and(|v| v == "yes");
+        let roundabout = way.tags.values().any(|v| v == "roundabout");
+        Some(Road {
+            id: way.id,
+            node_refs: way.nodes,
+            direction: if oneway || roundabout {
+                CarDirection::Forward
+            } else {
+                CarDirection::Twoway
+            },
+        })
+    }
+
+    fn add_road(
+        &mut self,
+        way: osmpbfreader::Way,
+        blacklist: &HashSet<&str>,
+        seen_ways: &mut HashSet<WayId>,
+        nodes_to_keep: &mut Vec<NodeId>,
+    ) {
+        if seen_ways.contains(&way.id) {
+            return;
+        }
+        if self.way_nodes.is_some() {
+            nodes_to_keep.extend(&way.nodes);
+        }
+        if let Some(road) = self.to_road(blacklist, way) {
+            seen_ways.insert(road.id);
+            nodes_to_keep.extend(&road.node_refs);
+            self.roads.push(road);
+        }
+    }
+
+    fn get_roads(
+        &mut self,
+        filename: &str,
+        seen_ways: &mut HashSet<WayId>,
+        nodes_to_keep: &mut Vec<NodeId>,
+    ) -> bool {
+        // Returns whether all nodes came before the ways and relations in the file
         let blacklist = create_blacklist();
-        for obj in pbf.par_iter().map(Result::unwrap) {
+        let mut seen_non_node = false;
+        let mut sorted = true;
+        Self::read_objects(filename, |obj| {
             match obj {
+                osmpbfreader::OsmObj::Node(_) => sorted &= !seen_non_node,
                 osmpbfreader::OsmObj::Way(way) => {
-                    if !self.is_valid_highway(&blacklist, &way.tags) {
-                        continue;
-                    }
-                    nodes_to_keep.extend(&way.nodes);
-                    let oneway = way.tags.get("oneway").map_or(false, |v| v == "yes");
-                    let roundabout = way.tags.values().any(|v| v == "roundabout");
-                    self.roads.push(Road {
-                        node_refs: way.nodes,
-                        direction: if oneway || roundabout {
-                            CarDirection::Forward
-                        } else {
-                            CarDirection::Twoway
-                        },
-                    })
+                    seen_non_node = true;
+                    self.add_road(way, &blacklist, seen_ways, nodes_to_keep);
                 }
-                _ => continue,
+                osmpbfreader::OsmObj::Relation(_) => seen_non_node = true,
             }
-        }
-        let nodes_to_keep_hashset = HashSet::from_par_iter(nodes_to_keep);
+            ControlFlow::Continue(())
+        });
+        sorted
+    }
 
-        self.get_nodes(filename, &nodes_to_keep_hashset);
+    pub fn get_nodes(&mut self, filename: &str, nodes_to_keep: &HashSet<NodeId>, sorted: bool) {
+        Self::read_objects(filename, |obj| match obj {
+            osmpbfreader::OsmObj::Node(node)
+                if nodes_to_keep.contains(&node.id) && !self.nodes.contains_key(&node.id) =>
+            {
+                self.nodes.insert(node.id, Coord { lat: node.lat(), lon: node.lon() });
+                ControlFlow::Continue(())
+            }
+            osmpbfreader::OsmObj::Node(_) => ControlFlow::Continue(()),
+            // Can return early when all nodes are at the start of the file
+            _ if sorted => ControlFlow::Break(()),
+            _ => ControlFlow::Continue(()),
+        });
     }
 
-    pub fn get_nodes(&mut self, filename: &str, nodes_to_keep: &HashSet<NodeId>)  {
-        let r = std::fs::File::open(&std::path::Path::new(filename)).unwrap();
-        let mut pbf = osmpbfreader::OsmPbfReader::new(r);
-        for obj in pbf.par_iter().map(Result::unwrap) {
+    fn get_roads_and_all_nodes(
+        &mut self,
+        filename: &str,
+        seen_ways: &mut HashSet<WayId>,
+        nodes_to_keep: &mut Vec<NodeId>,
+        all_nodes: &mut Vec<(NodeId, Coord)>,
+    ) {
+        let blacklist = create_blacklist();
+        Self::read_objects(filename, |obj| {
             match obj {
-                osmpbfreader::OsmObj::Node(node) if nodes_to_keep.contains(&node.id) => {
-                    self.nodes.insert(node.id, Coord { lat: node.lat(), lon: node.lon() });
+                osmpbfreader::OsmObj::Node(node) => {
+                    all_nodes.push((node.id, Coord { lat: node.lat(), lon: node.lon() }));
                 }
-                osmpbfreader::OsmObj::Node(_) => continue,
-                _ => break, // Can return early since nodes are at the start of the file
+                osmpbfreader::OsmObj::Way(way) => {
+                    self.add_road(way, &blacklist, seen_ways, nodes_to_keep);
+                }
+                osmpbfreader::OsmObj::Relation(_) => {}
             }
-        }
+            ControlFlow::Continue(())
+        });
     }
 
     pub fn new() -> Self {
         Preprocessor {
             nodes: HashMap::new(),
             roads: Vec::new(),
+            ingestion: Ingestion::TwoPass,
+            compressed_graph: None,
+            minimized_graph: None,
+            way_nodes: None,
+            source_files: Vec::new(),
+            projection: ProjectionKind::AzimuthalEquidistant,
+            custom_center: None,
+            record_origins: false,
+            origins: None,
         }
     }
 
-    pub fn project_nodes_to_2d(&self) -> HashMap<NodeId, (f32, f32)> {
+    pub fn projection_center(&self) -> (f64, f64) {
+        if let Some(center) = self.custom_center {
+            return center;
+        }
         let center_point = self.nodes.iter().fold((0.0, 0.0), |acc, (_, node)| {
             (acc.0 + node.lat, acc.1 + node.lon)
         });
-        let center_point = (
+        (
             center_point.0 / self.nodes.len() as f64,
             center_point.1 / self.nodes.len() as f64,
-        );
+        )
+    }
+
+    pub fn projection(&self) -> Box<dyn Projection> {
+        self.projection.with_center(self.projection_center())
+    }
+
+    pub fn project_nodes_to_2d(&self) -> HashMap<NodeId, (f32, f32)> {
+        let projection = self.projection();
 
         let projected_points = self
             .nodes
             .par_iter()
             .map(|(nodeid, node)| {
-                let (x, y) = azimuthal_equidistant_projection(*node, center_point);
+                let (x, y) = projection.project(*node);
                 (*nodeid, (x as f32, y as f32))
             })
             .collect();
@@ -287,13 +617,36 @@ impl Preprocessor {
     }
 }
 
+impl Default for Preprocessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 //TESTS
+#[cfg(test)]
 fn initialize(filename: &str) -> Preprocessor {
     let mut preprocessor = Preprocessor::new();
     preprocessor.get_roads_and_nodes(filename);
     preprocessor
 }
 
+/// The test grid preprocessed like main does, shared by the tests of every module
+#[cfg(test)]
+pub(crate) fn grid_full_graph() -> FullGraph {
+    preprocess_grid(&mut Preprocessor::new())
+}
+
+/// Preprocesses the test grid with the options set on preprocessor, which keeps what
+/// build_graph recorded
+#[cfg(test)]
+pub(crate) fn preprocess_grid(preprocessor: &mut Preprocessor) -> FullGraph {
+    preprocessor.get_roads_and_nodes("src/test_data/grid.osm");
+    let (graph, bi_graph, landmarks) = preprocessor.build_graph();
+    let projected_points = preprocessor.project_nodes_to_2d();
+    preprocessor.build_full_graph(&graph, &bi_graph, landmarks, &projected_points)
+}
+
 #[test]
 fn test_real_all() {
     //checks if file has been parsed correctly, with 2 nodes and 1 road
@@ -322,3 +675,165 @@ fn one_node_is_dropped() {
     let preprocessor = initialize("src/test_data/one_node_is_dropped.osm.testpbf");
     assert_eq!(2, preprocessor.nodes.len());
 }
+
+#[test]
+fn merging_overlapping_extracts_deduplicates_ways_and_nodes() {
+    //both files contain way 12 between node 8 and 9, so it should only be kept once
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.get_roads_and_nodes_from_files(&[
+        "src/test_data/minimal.osm.testpbf",
+        "src/test_data/minimal_twoway.osm.testpbf",
+    ]);
+    assert_eq!(1, preprocessor.roads.len());
+    assert_eq!(2, preprocessor.nodes.len());
+}
+
+#[test]
+fn merging_disjoint_extracts_keeps_everything() {
+    let ribe = initialize("src/test_data/ribe_slice.osm.testpbf");
+    let andorra = initialize("src/test_data/andorra_double_cycle.osm.testpbf");
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.get_roads_and_nodes_from_files(&[
+        "src/test_data/ribe_slice.osm.testpbf",
+        "src/test_data/andorra_double_cycle.osm.testpbf",
+    ]);
+    assert_eq!(ribe.roads.len() + andorra.roads.len(), preprocessor.roads.len());
+    assert_eq!(ribe.nodes.len() + andorra.nodes.len(), preprocessor.nodes.len());
+}
+
+#[test]
+fn merging_an_extract_with_itself_gives_the_same_graph() {
+    let mut single = initialize("src/test_data/minimize_example.osm.testpbf");
+    let mut merged = Preprocessor::new();
+    merged.get_roads_and_nodes_from_files(&[
+        "src/test_data/minimize_example.osm.testpbf",
+        "src/test_data/minimize_example.osm.testpbf",
+    ]);
+    assert_eq!(single.roads.len(), merged.roads.len());
+    assert_eq!(single.build_graph().0.len(), merged.build_graph().0.len());
+}
+
+#[test]
+fn reads_osm_xml_like_pbf() {
+    //the xml file describes the same oneway road as minimal.osm.testpbf
+    let xml = initialize("src/test_data/minimal.osm");
+    let pbf = initialize("src/test_data/minimal.osm.testpbf");
+    assert_eq!(pbf.roads.len(), xml.roads.len());
+    assert_eq!(pbf.nodes.len(), xml.nodes.len());
+    assert_eq!(pbf.roads[0].node_refs, xml.roads[0].node_refs);
+    assert_eq!(CarDirection::Forward, xml.roads[0].direction);
+}
+
+#[test]
+fn reads_compressed_osm_xml() {
+    for filename in ["src/test_data/minimal.osm.gz", "src/test_data/minimal.osm.bz2"] {
+        let preprocessor = initialize(filename);
+        assert_eq!(1, preprocessor.roads.len());
+        assert_eq!(2, preprocessor.nodes.len());
+    }
+}
+
+#[test]
+fn hand_edited_osm_xml_skips_deleted_and_blacklisted() {
+    //the footway and the deleted road are dropped, leaving one road over three nodes
+    let preprocessor = initialize("src/test_data/hand_edited.osm");
+    assert_eq!(1, preprocessor.roads.len());
+    assert_eq!(WayId(-201), preprocessor.roads[0].id);
+    assert_eq!(3, preprocessor.nodes.len());
+    assert!(!preprocessor.nodes.contains_key(&NodeId(-104)));
+}
+
+#[test]
+fn finds_nodes_after_ways_in_both_modes() {
+    //nodes 10 and 11 come after the first way, so stopping at the first way would lose them
+    for ingestion in [Ingestion::TwoPass, Ingestion::SinglePass] {
+        let mut preprocessor = Preprocessor::new();
+        preprocessor.ingestion = ingestion;
+        let report = preprocessor.get_roads_and_nodes("src/test_data/ways_before_nodes.osm");
+        assert!(report.is_complete());
+        assert_eq!(2, preprocessor.roads.len());
+        assert_eq!(4, preprocessor.nodes.len());
+    }
+}
+
+#[test]
+fn single_pass_matches_two_pass() {
+    let two_pass = initialize("src/test_data/ribe_slice.osm.testpbf");
+    let mut single_pass = Preprocessor::new();
+    single_pass.ingestion = Ingestion::SinglePass;
+    single_pass.get_roads_and_nodes("src/test_data/ribe_slice.osm.testpbf");
+    assert_eq!(two_pass.roads.len(), single_pass.roads.len());
+    assert_eq!(two_pass.nodes.len(), single_pass.nodes.len());
+}
+
+#[test]
+fn reports_and_splits_ways_with_missing_nodes() {
+    //node 10 is missing, so way 20 is split into 8-9 and 11-12
+    let mut preprocessor = Preprocessor::new();
+    let report = preprocessor.get_roads_and_nodes("src/test_data/missing_node.osm");
+    assert!(!report.is_complete());
+    assert_eq!(HashSet::from([NodeId(10)]), report.missing_nodes);
+    assert_eq!(vec![WayId(20)], report.ways_with_missing_nodes);
+    assert_eq!(3, preprocessor.roads.len());
+    assert!(preprocessor
+        .roads
+        .iter()
+        .all(|road| !road.node_refs.contains(&NodeId(10))));
+    let graph = Graph::build_graph(&preprocessor.nodes, &preprocessor.roads);
+    assert_eq!(4, graph.len());
+}
+
+#[test]
+fn full_graph_header_describes_graph() {
+    let mut preprocessor = initialize("src/test_data/ribe_slice.osm.testpbf");
+    let (graph, bi_graph, landmarks) = preprocessor.build_graph();
+    let projected_points = preprocessor.project_nodes_to_2d();
+    let full_graph = preprocessor.build_full_graph(&graph, &bi_graph, landmarks, &projected_points);
+    let header = &full_graph.header;
+    assert_eq!(
+        vec!["ribe_slice.osm.testpbf".to_owned()],
+        header.source_files
+    );
+    assert_eq!(graph.len() as u64, header.node_count);
+    assert_eq!(
+        graph.values().map(|edges| edges.len() as u64).sum::<u64>(),
+        header.edge_count
+    );
+    assert_eq!(full_graph.landmarks.len() as u64, header.landmark_count);
+    assert_eq!(preprocessor.projection_center(), header.projection_center);
+}
+
+#[test]
+fn header_records_chosen_projection() {
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.get_roads_and_nodes("src/test_data/grid.osm");
+    preprocessor.projection = ProjectionKind::Utm(None);
+    let (graph, bi_graph, landmarks) = preprocessor.build_graph();
+    // Project around the first node left after minimization
+    let first = preprocessor.nodes[&NodeId(0)];
+    preprocessor.custom_center = Some((first.lat, first.lon));
+    let projected_points = preprocessor.project_nodes_to_2d();
+    let full_graph = preprocessor.build_full_graph(&graph, &bi_graph, landmarks, &projected_points);
+    assert_eq!("utm_32N", full_graph.header.projection);
+    assert_eq!((first.lat, first.lon), full_graph.header.projection_center);
+    let node = &full_graph.nodes[0];
+    assert_eq!((0.0, 0.0), (node.x, node.y));
+}
+
+#[test]
+fn write_graph_reports_errors() {
+    let compressed_flat = Preprocessor::write_graph(
+        grid_full_graph(),
+        "grid.graph",
+        OutputFormat::Flat,
+        Compression::Gzip,
+    );
+    assert!(matches!(compressed_flat, Err(GraphFileError::Encode(_))));
+    let missing_directory = Preprocessor::write_graph(
+        grid_full_graph(),
+        "no_such_directory/grid.graph",
+        OutputFormat::MessagePack,
+        Compression::None,
+    );
+    assert!(matches!(missing_directory, Err(GraphFileError::Io(_))));
+}
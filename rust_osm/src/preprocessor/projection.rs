@@ -0,0 +1,304 @@
+use crate::preprocessor::coord::Coord;
+
+use std::f64::consts::PI;
+
+/*
+   Projections from lat/lon to the planar x, y stored with each node.
+   All of them are shifted so the projection center lands on (0, 0), since x and y are stored
+   as f32 and would lose precision at the millions of meters UTM and Web Mercator give.
+   The header records the name and center, which is enough to undo the projection.
+*/
+pub trait Projection: Send + Sync {
+    /// Recorded in the graph header, e.g. "azimuthal_equidistant" or "utm_32N"
+    fn name(&self) -> String;
+    fn project(&self, coord: Coord) -> (f64, f64);
+}
+
+/// Which projection the preprocessor uses, the center is picked when projecting
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ProjectionKind {
+    AzimuthalEquidistant,
+    WebMercator,
+    /// The zone and hemisphere, or None to take them from the center
+    Utm(Option<(u8, bool)>),
+}
+
+impl ProjectionKind {
+    pub fn with_center(self, center: (f64, f64)) -> Box<dyn Projection> {
+        match self {
+            ProjectionKind::AzimuthalEquidistant => Box::new(AzimuthalEquidistant { center }),
+            ProjectionKind::WebMercator => Box::new(WebMercator::new(center)),
+            ProjectionKind::Utm(zone) => {
+                let (zone, north) = zone.unwrap_or_else(|| Utm::zone_of(center));
+                Box::new(Utm::new(zone, north, center))
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for ProjectionKind {
+    type Err = String;
+
+    /// Parses "azimuthal", "webmercator", "utm" or a fixed zone like "utm:32N". The names
+    /// recorded in graph headers, like "utm_32N", are accepted as well
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "azimuthal" | "azimuthal_equidistant" => Ok(ProjectionKind::AzimuthalEquidistant),
+            "webmercator" | "web_mercator" => Ok(ProjectionKind::WebMercator),
+            "utm" => Ok(ProjectionKind::Utm(None)),
+            _ => {
+                let zone = s
+                    .strip_prefix("utm:")
+                    .or_else(|| s.strip_prefix("utm_"))
+                    .ok_or(format!("unknown projection {}", s))?;
+                let north = match zone.chars().last() {
+                    Some('N') => true,
+                    Some('S') => false,
+                    _ => return Err(format!("UTM zone {} must end in N or S", zone)),
+                };
+                match zone[..zone.len() - 1].parse() {
+                    Ok(number @ 1..=60) => Ok(ProjectionKind::Utm(Some((number, north)))),
+                    _ => Err(format!("invalid UTM zone {}", zone)),
+                }
+            }
+        }
+    }
+}
+
+pub struct AzimuthalEquidistant {
+    pub center: (f64, f64),
+}
+
+impl Projection for AzimuthalEquidistant {
+    fn name(&self) -> String {
+        "azimuthal_equidistant".to_owned()
+    }
+
+    fn project(&self, coord: Coord) -> (f64, f64) {
+        azimuthal_equidistant_projection(coord, self.center)
+    }
+}
+
+pub fn azimuthal_equidistant_projection(coord: Coord, center: (f64, f64)) -> (f64, f64) {
+    let lat_rad = coord.lat * (PI / 180.0);
+    let lon_rad = coord.lon * (PI / 180.0);
+    let center_lat_rad = center.0 * (PI / 180.0);
+    let center_lon_rad = center.1 * (PI / 180.0);
+
+    let r = 6371000.0;
+
+    let delta_lon = lon_rad - center_lon_rad;
+    let central_angle = (center_lat_rad.sin() * lat_rad.sin()
+        + center_lat_rad.cos() * lat_rad.cos() * delta_lon.cos())
+    .acos();
+
+    let distance = r * central_angle;
+
+    let azimuth = delta_lon
+        .sin()
+        .atan2(center_lat_rad.cos() * lat_rad.tan() - center_lat_rad.sin() * delta_lon.cos());
+
+    let x = distance * azimuth.sin();
+    let y = distance * azimuth.cos();
+
+    (x, y)
+}
+
+/// Spherical Mercator as used by web maps (EPSG:3857)
+pub struct WebMercator {
+    origin: (f64, f64),
+}
+
+impl WebMercator {
+    const R: f64 = 6378137.0;
+
+    pub fn new(center: (f64, f64)) -> WebMercator {
+        let origin = WebMercator::mercator(center.0, center.1);
+        WebMercator { origin }
+    }
+
+    pub fn mercator(lat: f64, lon: f64) -> (f64, f64) {
+        let x = WebMercator::R * lon.to_radians();
+        let y = WebMercator::R * (PI / 4.0 + lat.to_radians() / 2.0).tan().ln();
+        (x, y)
+    }
+}
+
+impl Projection for WebMercator {
+    fn name(&self) -> String {
+        "web_mercator".to_owned()
+    }
+
+    fn project(&self, coord: Coord) -> (f64, f64) {
+        let (x, y) = WebMercator::mercator(coord.lat, coord.lon);
+        (x - self.origin.0, y - self.origin.1)
+    }
+}
+
+/// Universal Transverse Mercator on the WGS84 ellipsoid
+pub struct Utm {
+    pub zone: u8,
+    pub north: bool,
+    origin: (f64, f64),
+}
+
+impl Utm {
+    const A: f64 = 6378137.0;
+    const F: f64 = 1.0 / 298.257223563;
+    const K0: f64 = 0.9996;
+
+    pub fn new(zone: u8, north: bool, center: (f64, f64)) -> Utm {
+        let mut utm = Utm {
+            zone,
+            north,
+            origin: (0.0, 0.0),
+        };
+        utm.origin = utm.easting_northing(Coord {
+            lat: center.0,
+            lon: center.1,
+        });
+        utm
+    }
+
+    /// The standard 6 degree zone containing (lat, lon), without the Norway and Svalbard exceptions
+    pub fn zone_of(center: (f64, f64)) -> (u8, bool) {
+        let zone = ((center.1 + 180.0) / 6.0).floor() as i64 + 1;
+        (zone.clamp(1, 60) as u8, center.0 >= 0.0)
+    }
+
+    /// Easting and northing in meters, including the false easting and northing
+    pub fn easting_northing(&self, coord: Coord) -> (f64, f64) {
+        // Series from Snyder, Map Projections: A Working Manual, p. 61
+        let e2 = Utm::F * (2.0 - Utm::F);
+        let e4 = e2 * e2;
+        let e6 = e4 * e2;
+        let ep2 = e2 / (1.0 - e2);
+        let lat = coord.lat.to_radians();
+        let central_meridian = (self.zone as f64 * 6.0 - 183.0).to_radians();
+
+        let n = Utm::A / (1.0 - e2 * lat.sin().powi(2)).sqrt();
+        let t = lat.tan().powi(2);
+        let c = ep2 * lat.cos().powi(2);
+        let a = lat.cos() * (coord.lon.to_radians() - central_meridian);
+        let m = Utm::A
+            * ((1.0 - e2 / 4.0 - 3.0 * e4 / 64.0 - 5.0 * e6 / 256.0) * lat
+                - (3.0 * e2 / 8.0 + 3.0 * e4 / 32.0 + 45.0 * e6 / 1024.0) * (2.0 * lat).sin()
+                + (15.0 * e4 / 256.0 + 45.0 * e6 / 1024.0) * (4.0 * lat).sin()
+                - (35.0 * e6 / 3072.0) * (6.0 * lat).sin());
+
+        let easting = Utm::K0
+            * n
+            * (a + (1.0 - t + c) * a.powi(3) / 6.0
+                + (5.0 - 18.0 * t + t * t + 72.0 * c - 58.0 * ep2) * a.powi(5) / 120.0)
+            + 500000.0;
+        let mut northing = Utm::K0
+            * (m + n
+                * lat.tan()
+                * (a * a / 2.0
+                    + (5.0 - t + 9.0 * c + 4.0 * c * c) * a.powi(4) / 24.0
+                    + (61.0 - 58.0 * t + t * t + 600.0 * c - 330.0 * ep2) * a.powi(6) / 720.0));
+        if !self.north {
+            northing += 10000000.0;
+        }
+        (easting, northing)
+    }
+}
+
+impl Projection for Utm {
+    fn name(&self) -> String {
+        format!("utm_{}{}", self.zone, if self.north { 'N' } else { 'S' })
+    }
+
+    fn project(&self, coord: Coord) -> (f64, f64) {
+        let (easting, northing) = self.easting_northing(coord);
+        (easting - self.origin.0, northing - self.origin.1)
+    }
+}
+
+// TESTS
+#[test]
+fn every_projection_puts_the_center_at_the_origin() {
+    let center = (55.7, 12.5);
+    let kinds = [
+        ProjectionKind::AzimuthalEquidistant,
+        ProjectionKind::WebMercator,
+        ProjectionKind::Utm(None),
+    ];
+    for kind in kinds {
+        let (x, y) = kind.with_center(center).project(Coord {
+            lat: center.0,
+            lon: center.1,
+        });
+        assert!(x.abs() < 1e-6 && y.abs() < 1e-6, "{:?}", kind);
+    }
+}
+
+#[test]
+fn utm_matches_known_values() {
+    assert_eq!((33, true), Utm::zone_of((55.7, 12.5)));
+    assert_eq!((19, false), Utm::zone_of((-33.4, -70.6)));
+    let utm = Utm::new(31, true, (0.0, 0.0));
+    // On the central meridian the easting is the false easting and the northing is the
+    // meridian arc length scaled by 0.9996
+    let (easting, northing) = utm.easting_northing(Coord {
+        lat: 45.0,
+        lon: 3.0,
+    });
+    assert!((easting - 500000.0).abs() < 1e-6);
+    assert!((northing - 4982950.4).abs() < 0.1);
+    let (east, _) = utm.easting_northing(Coord {
+        lat: 45.0,
+        lon: 4.0,
+    });
+    let (west, _) = utm.easting_northing(Coord {
+        lat: 45.0,
+        lon: 2.0,
+    });
+    assert!((east - 500000.0 - (500000.0 - west)).abs() < 1e-6);
+    assert_eq!("utm_31N", utm.name());
+}
+
+#[test]
+fn projections_keep_local_distances() {
+    let center = (55.7, 12.5);
+    let a = Coord {
+        lat: 55.7,
+        lon: 12.5,
+    };
+    let b = Coord {
+        lat: 55.71,
+        lon: 12.52,
+    };
+    let distance = a.distance_to(b) as f64;
+    // Web Mercator stretches by 1 / cos(lat), the others stay within a fraction of a percent
+    for (kind, scale) in [
+        (ProjectionKind::AzimuthalEquidistant, 1.0),
+        (ProjectionKind::Utm(None), 1.0),
+        (
+            ProjectionKind::WebMercator,
+            1.0 / 55.7f64.to_radians().cos(),
+        ),
+    ] {
+        let projection = kind.with_center(center);
+        let (ax, ay) = projection.project(a);
+        let (bx, by) = projection.project(b);
+        let projected = ((bx - ax).powi(2) + (by - ay).powi(2)).sqrt();
+        assert!(
+            (projected / scale / distance - 1.0).abs() < 5e-3,
+            "{:?}",
+            kind
+        );
+    }
+}
+
+#[test]
+fn parses_projection_names() {
+    assert_eq!(Ok(ProjectionKind::Utm(Some((32, true)))), "utm:32N".parse());
+    assert_eq!(
+        Ok(ProjectionKind::Utm(Some((19, false)))),
+        "utm:19S".parse()
+    );
+    assert_eq!(Ok(ProjectionKind::WebMercator), "webmercator".parse());
+    assert!("utm:61N".parse::<ProjectionKind>().is_err());
+    assert!("mollweide".parse::<ProjectionKind>().is_err());
+}
@@ -0,0 +1,528 @@
+use crate::preprocessor::coord::Coord;
+use crate::preprocessor::edge::Edge;
+use crate::preprocessor::graph::Graph;
+use crate::preprocessor::graph_file::{open_graph_file, GraphFileError};
+use crate::preprocessor::osm_xml::{self, ChangeAction};
+use crate::preprocessor::preprocessor::{
+    create_blacklist, source_file_name, split_at_missing_nodes, CarDirection, IngestionReport,
+    Preprocessor, Road,
+};
+
+use osmpbfreader::{NodeId, OsmObj, WayId};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+
+/*
+   Keeping a graph up to date with OsmChange files (.osc), such as the daily diffs from
+   Geofabrik, instead of reading the whole extract again.
+   The store keeps what the Preprocessor read: the roads, the coordinates of the nodes of every
+   way, roads or not, and the graph of the roads before and after minimizing, still with OSM
+   ids. It is written with MessagePack, see --store in main.rs.
+   apply_change replaces the changed ways, moves the changed nodes and rebuilds the edges of the
+   graph only at the nodes the change touches: every node of the old and new version of a
+   changed way, and moved or deleted nodes with their neighbours. The roads are indexed by
+   their nodes, so only the roads through those nodes are looked at.
+   The minimized graph is then minimized again only around the rebuilt nodes: along the chains
+   of removed nodes from them to the junctions the chains end in, and on along the other chains
+   of those junctions to the next junctions, which are kept as they are. Should one of the kept
+   junctions get other neighbours, it could stop being a junction, so the area is grown past it
+   and minimized again. load_into hands the result to a Preprocessor, whose build_graph then
+   only picks landmarks, since they depend on the whole graph.
+   Nodes are only dropped when a change deletes them, since a change that only retags a way
+   does not repeat its nodes. A new road through a node the store does not have is split there
+   like a road with nodes missing from an extract.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RoadStore {
+    /// The nodes of every way read, and every node of the changes applied since
+    pub nodes: HashMap<NodeId, Coord>,
+    /// The pieces of every road, more than one when it was split at missing nodes
+    pub roads: HashMap<WayId, Vec<Road>>,
+    /// The graph of the roads before minimizing, with OSM ids
+    pub graph: HashMap<NodeId, Vec<Edge>>,
+    /// graph minimized like build_graph does, with OSM ids
+    pub minimized: HashMap<NodeId, Vec<Edge>>,
+    pub source_files: Vec<String>,
+    /// The roads through every node, made again when the store is read
+    #[serde(skip)]
+    node_roads: HashMap<NodeId, Vec<WayId>>,
+}
+
+/// What apply_change did
+#[derive(Debug, Default)]
+pub struct ChangeReport {
+    /// Ways that were or became roads and were created, modified or deleted, sorted
+    pub changed_ways: Vec<WayId>,
+    /// How many nodes got their edges rebuilt
+    pub rebuilt_nodes: usize,
+    /// How many nodes of the graph before minimizing were minimized again
+    pub reminimized_nodes: usize,
+    /// Roads that were split at nodes the store does not have
+    pub missing: IngestionReport,
+}
+
+impl Preprocessor {
+    /// The roads and nodes read so far with the graph between them, to apply changes to later.
+    /// Must be taken before build_graph, which clears the roads. Set way_nodes before reading
+    /// for changes that turn other ways into roads to find their nodes
+    pub fn road_store(&self) -> RoadStore {
+        if self.compressed_graph.is_some() {
+            panic!("The roads are not kept when reading with a memory budget or from DIMACS");
+        }
+        let mut nodes = self.nodes.clone();
+        nodes.extend(self.way_nodes.iter().flatten());
+        let graph = Graph::build_graph(&self.nodes, &self.roads);
+        let mut minimized = graph.clone();
+        Graph::minimize_graph(&mut minimized, true);
+        let mut store = RoadStore {
+            nodes,
+            graph,
+            minimized,
+            source_files: self.source_files.clone(),
+            ..RoadStore::default()
+        };
+        for road in &self.roads {
+            store.insert_road(road.clone());
+        }
+        store
+    }
+}
+
+impl RoadStore {
+    /// Applies an OsmChange file (.osc, .osc.gz or .osc.bz2)
+    pub fn apply_change(&mut self, filename: &str) -> ChangeReport {
+        self.source_files.push(source_file_name(filename));
+        self.apply(osm_xml::open(filename).changes())
+    }
+
+    /// Applies the objects of a change in the order they come, the last version of an object
+    /// wins
+    pub fn apply<I: IntoIterator<Item = (ChangeAction, OsmObj)>>(
+        &mut self,
+        changes: I,
+    ) -> ChangeReport {
+        let blacklist = create_blacklist();
+        let preprocessor = Preprocessor::new();
+        // None for deleted nodes, and for deleted ways and ways that are not roads
+        let mut changed_nodes: HashMap<NodeId, Option<Coord>> = HashMap::new();
+        let mut changed_ways: HashMap<WayId, Option<Road>> = HashMap::new();
+        for (action, obj) in changes {
+            match (action, obj) {
+                (ChangeAction::Delete, OsmObj::Node(node)) => {
+                    changed_nodes.insert(node.id, None);
+                }
+                (_, OsmObj::Node(node)) => {
+                    let coord = Coord {
+                        lat: node.lat(),
+                        lon: node.lon(),
+                    };
+                    changed_nodes.insert(node.id, Some(coord));
+                }
+                (ChangeAction::Delete, OsmObj::Way(way)) => {
+                    changed_ways.insert(way.id, None);
+                }
+                (_, OsmObj::Way(way)) => {
+                    changed_ways.insert(way.id, preprocessor.to_road(&blacklist, way));
+                }
+                (_, OsmObj::Relation(_)) => {}
+            }
+        }
+
+        let mut report = ChangeReport::default();
+        // Nodes whose own edges change
+        let mut touched: HashSet<NodeId> = HashSet::new();
+        for way in changed_ways.keys() {
+            let old_roads = self.remove_road(*way);
+            if !old_roads.is_empty() {
+                report.changed_ways.push(*way);
+            }
+            for road in &old_roads {
+                touched.extend(&road.node_refs);
+            }
+        }
+
+        let mut deleted = HashSet::new();
+        for (node, coord) in &changed_nodes {
+            match coord {
+                Some(coord) if self.nodes.insert(*node, *coord).is_some() => {
+                    touched.insert(*node);
+                }
+                Some(_) => {}
+                None if self.nodes.remove(node).is_some() => {
+                    touched.insert(*node);
+                    deleted.insert(*node);
+                }
+                None => {}
+            }
+        }
+        // Valid changes delete no node that an unchanged road still uses, but split them if so
+        let broken: HashSet<WayId> = deleted
+            .iter()
+            .filter_map(|node| self.node_roads.get(node))
+            .flatten()
+            .copied()
+            .collect();
+        for way in broken {
+            report.missing.ways_with_missing_nodes.push(way);
+            for road in self.remove_road(way) {
+                touched.extend(&road.node_refs);
+                let missing = road.node_refs.iter().filter(|node| deleted.contains(node));
+                report.missing.missing_nodes.extend(missing);
+                for piece in split_at_missing_nodes(&road, &self.nodes) {
+                    self.insert_road(piece);
+                }
+            }
+        }
+        for road in changed_ways.into_values().flatten() {
+            touched.extend(&road.node_refs);
+            report.changed_ways.push(road.id);
+            if road
+                .node_refs
+                .iter()
+                .all(|node| self.nodes.contains_key(node))
+            {
+                self.insert_road(road);
+                continue;
+            }
+            report.missing.ways_with_missing_nodes.push(road.id);
+            report.missing.missing_nodes.extend(
+                road.node_refs
+                    .iter()
+                    .filter(|node| !self.nodes.contains_key(node)),
+            );
+            for piece in split_at_missing_nodes(&road, &self.nodes) {
+                self.insert_road(piece);
+            }
+        }
+        report.changed_ways.sort();
+        report.changed_ways.dedup();
+
+        // The neighbours of touched nodes have edges to them. Nodes no road uses anymore leave
+        // the graph
+        let mut rebuilt = touched.clone();
+        for node in &touched {
+            rebuilt.extend(self.neighbours(*node));
+        }
+        let mut edges: HashMap<NodeId, Vec<Edge>> = HashMap::new();
+        for node in &rebuilt {
+            if self.node_roads.contains_key(node) {
+                edges.insert(*node, self.edges_from(*node));
+            } else {
+                self.graph.remove(node);
+            }
+        }
+        Graph::remove_duplicate_edges(&mut edges);
+        report.rebuilt_nodes = edges.len();
+        self.graph.extend(edges);
+        report.reminimized_nodes = self.minimize_around(rebuilt);
+        report
+    }
+
+    fn insert_road(&mut self, road: Road) {
+        for node in &road.node_refs {
+            let ways = self.node_roads.entry(*node).or_default();
+            if !ways.contains(&road.id) {
+                ways.push(road.id);
+            }
+        }
+        self.roads.entry(road.id).or_default().push(road);
+    }
+
+    fn remove_road(&mut self, way: WayId) -> Vec<Road> {
+        let roads = self.roads.remove(&way).unwrap_or_default();
+        for node in roads.iter().flat_map(|road| &road.node_refs) {
+            if let Some(ways) = self.node_roads.get_mut(node) {
+                ways.retain(|other| *other != way);
+                if ways.is_empty() {
+                    self.node_roads.remove(node);
+                }
+            }
+        }
+        roads
+    }
+
+    /// The nodes next to node on any road, in either direction
+    fn neighbours(&self, node: NodeId) -> Vec<NodeId> {
+        let mut neighbours = Vec::new();
+        for way in self.node_roads.get(&node).into_iter().flatten() {
+            for road in &self.roads[way] {
+                for pair in road.node_refs.windows(2) {
+                    if pair[0] == node {
+                        neighbours.push(pair[1]);
+                    } else if pair[1] == node {
+                        neighbours.push(pair[0]);
+                    }
+                }
+            }
+        }
+        neighbours
+    }
+
+    /// The edges from node, made the same way as Graph::build_graph
+    fn edges_from(&self, node: NodeId) -> Vec<Edge> {
+        let mut edges = Vec::new();
+        for way in &self.node_roads[&node] {
+            for road in &self.roads[way] {
+                for pair in road.node_refs.windows(2) {
+                    let next_node = if pair[0] == node {
+                        pair[1]
+                    } else if pair[1] == node && road.direction == CarDirection::Twoway {
+                        pair[0]
+                    } else {
+                        continue;
+                    };
+                    let distance = self.nodes[&node].distance_to(self.nodes[&next_node]);
+                    edges.push(Edge::new(next_node, distance));
+                }
+            }
+        }
+        edges
+    }
+
+    /// Minimizes the graph again around the changed nodes, and returns how many nodes it
+    /// minimized
+    fn minimize_around(&mut self, mut changed: HashSet<NodeId>) -> usize {
+        loop {
+            // The changed nodes and the chains from them to the first junctions, then the other
+            // chains of those junctions. Their minimized edges are made again
+            let mut free: HashSet<NodeId> = changed.clone();
+            let mut junctions = Vec::new();
+            let mut queue: Vec<NodeId> = changed.iter().copied().collect();
+            while let Some(node) = queue.pop() {
+                for next in self.neighbours(node) {
+                    if free.insert(next) {
+                        if self.minimized.contains_key(&next) {
+                            junctions.push(next);
+                        } else {
+                            queue.push(next);
+                        }
+                    }
+                }
+            }
+            // The junctions at the far end of those chains are kept
+            let mut kept: HashSet<NodeId> = HashSet::new();
+            let mut queue = junctions;
+            while let Some(node) = queue.pop() {
+                for next in self.neighbours(node) {
+                    if free.contains(&next) || kept.contains(&next) {
+                        continue;
+                    }
+                    if self.minimized.contains_key(&next) {
+                        kept.insert(next);
+                    } else {
+                        free.insert(next);
+                        queue.push(next);
+                    }
+                }
+            }
+
+            let mut local: HashMap<NodeId, Vec<Edge>> = free
+                .iter()
+                .filter_map(|node| Some((*node, self.graph.get(node)?.clone())))
+                .collect();
+            for (i, node) in kept.iter().enumerate() {
+                let mut edges: Vec<Edge> = self.graph[node]
+                    .iter()
+                    .filter(|edge| free.contains(&edge.node))
+                    .copied()
+                    .collect();
+                // Three made up nodes connected to each other and to node keep it a junction
+                let pin: Vec<NodeId> = (1..=3).map(|k| NodeId(-(3 * i as i64 + k))).collect();
+                for fake in &pin {
+                    edges.push(Edge::new(*fake, 0.0));
+                    let fake_edges = pin
+                        .iter()
+                        .chain([node])
+                        .filter(|other| *other != fake)
+                        .map(|other| Edge::new(*other, 0.0))
+                        .collect();
+                    local.insert(*fake, fake_edges);
+                }
+                local.insert(*node, edges);
+            }
+            Graph::minimize_graph(&mut local, true);
+
+            // A kept junction whose neighbours in the area changed could stop being one
+            let to_area = |from: &NodeId, to: &NodeId| {
+                kept.contains(from) && free.contains(to) || free.contains(from) && kept.contains(to)
+            };
+            let mut old_pairs = HashSet::new();
+            for node in free.iter().chain(&kept) {
+                for edge in self.minimized.get(node).into_iter().flatten() {
+                    if to_area(node, &edge.node) {
+                        old_pairs.insert((*node, edge.node));
+                    }
+                }
+            }
+            let mut new_pairs = HashSet::new();
+            for (node, edges) in &local {
+                for edge in edges {
+                    let real = node.0 >= 0 && edge.node.0 >= 0;
+                    if real && (kept.contains(node) || kept.contains(&edge.node)) {
+                        new_pairs.insert((*node, edge.node));
+                    }
+                }
+            }
+            let grown: Vec<NodeId> = old_pairs
+                .symmetric_difference(&new_pairs)
+                .flat_map(|(from, to)| [*from, *to])
+                .filter(|node| kept.contains(node))
+                .collect();
+            if !grown.is_empty() {
+                changed.extend(grown);
+                continue;
+            }
+
+            for node in &free {
+                self.minimized.remove(node);
+            }
+            for node in &kept {
+                let edges = self.minimized.get_mut(node).unwrap();
+                edges.retain(|edge| !free.contains(&edge.node));
+            }
+            for (node, edges) in local {
+                if node.0 < 0 {
+                    continue;
+                }
+                let edges = edges.into_iter().filter(|edge| edge.node.0 >= 0);
+                let minimized = self.minimized.entry(node).or_default();
+                minimized.extend(edges);
+                minimized.sort_unstable_by_key(|edge| edge.node.0);
+            }
+            return free.len();
+        }
+    }
+
+    /// Gives the roads, their nodes and both graphs to preprocessor, for build_graph to
+    /// continue from without minimizing
+    pub fn load_into(mut self, preprocessor: &mut Preprocessor) {
+        self.nodes
+            .retain(|node, _| self.node_roads.contains_key(node));
+        let mut roads: Vec<Road> = self.roads.into_values().flatten().collect();
+        roads.sort_by_key(|road| road.id);
+        preprocessor.nodes = self.nodes;
+        preprocessor.roads = roads;
+        preprocessor.compressed_graph = Some(self.graph);
+        preprocessor.minimized_graph = Some(self.minimized);
+        preprocessor.source_files = self.source_files;
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), GraphFileError> {
+        rmp_serde::encode::write(writer, self).map_err(|e| GraphFileError::Encode(e.to_string()))
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<RoadStore, GraphFileError> {
+        let mut store: RoadStore =
+            rmp_serde::from_read(reader).map_err(|e| GraphFileError::Decode(e.to_string()))?;
+        for road in std::mem::take(&mut store.roads).into_values().flatten() {
+            store.insert_road(road);
+        }
+        Ok(store)
+    }
+
+    pub fn load(filename: &str) -> Result<RoadStore, GraphFileError> {
+        RoadStore::read(&mut open_graph_file(filename)?)
+    }
+}
+
+// TESTS
+#[cfg(test)]
+fn sorted_edges(graph: &HashMap<NodeId, Vec<Edge>>) -> Vec<(NodeId, Vec<(NodeId, f32)>)> {
+    let mut nodes: Vec<_> = graph
+        .iter()
+        .map(|(node, edges)| {
+            let mut edges: Vec<_> = edges.iter().map(|edge| (edge.node, edge.cost)).collect();
+            edges.sort_by_key(|(node, _)| *node);
+            (*node, edges)
+        })
+        .collect();
+    nodes.sort_by_key(|(node, _)| *node);
+    nodes
+}
+
+/// Checks the minimized graphs are the same, up to the order the costs of merged edges are
+/// added in
+#[cfg(test)]
+fn assert_same_minimized(full: &HashMap<NodeId, Vec<Edge>>, graph: &HashMap<NodeId, Vec<Edge>>) {
+    let (full, graph) = (sorted_edges(full), sorted_edges(graph));
+    assert_eq!(full.len(), graph.len());
+    for ((node, edges), (full_node, full_edges)) in graph.iter().zip(&full) {
+        assert_eq!(full_node, node);
+        assert_eq!(full_edges.len(), edges.len());
+        for ((to, cost), (full_to, full_cost)) in edges.iter().zip(full_edges) {
+            assert_eq!(full_to, to);
+            assert!((cost - full_cost).abs() < 1e-2);
+        }
+    }
+}
+
+#[cfg(test)]
+fn grid_store() -> RoadStore {
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.way_nodes = Some(HashMap::new());
+    preprocessor.get_roads_and_nodes("src/test_data/grid.osm");
+    let mut buf = Vec::new();
+    preprocessor.road_store().write(&mut buf).unwrap();
+    RoadStore::read(&mut buf.as_slice()).unwrap()
+}
+
+#[test]
+fn incremental_update_matches_full_rebuild() {
+    let mut store = grid_store();
+    let report = store.apply_change("src/test_data/grid_change.osc");
+    assert_eq!(
+        vec![WayId(201), WayId(303), WayId(400)],
+        report.changed_ways
+    );
+    assert!(report.missing.is_complete());
+    assert!(report.rebuilt_nodes < store.graph.len());
+    assert_eq!(vec!["grid.osm", "grid_change.osc"], store.source_files);
+
+    // grid_changed.osm is grid.osm with the change applied by hand
+    let mut full = Preprocessor::new();
+    full.get_roads_and_nodes("src/test_data/grid_changed.osm");
+    let full_graph = Graph::build_graph(&full.nodes, &full.roads);
+    assert_eq!(sorted_edges(&full_graph), sorted_edges(&store.graph));
+    let mut full_minimized = full_graph;
+    Graph::minimize_graph(&mut full_minimized, true);
+    assert_same_minimized(&full_minimized, &store.minimized);
+
+    // The graph built from the store has the same nodes as after a full read
+    let mut incremental = Preprocessor::new();
+    store.load_into(&mut incremental);
+    let mut node_ids: Vec<_> = incremental.nodes.keys().collect();
+    let mut full_ids: Vec<_> = full.nodes.keys().collect();
+    node_ids.sort();
+    full_ids.sort();
+    assert_eq!(full_ids, node_ids);
+    let (graph, _, landmarks) = incremental.build_graph();
+    let (full_graph, _, _) = full.build_graph();
+    assert!(!landmarks.is_empty());
+    assert_same_minimized(&full_graph, &graph);
+    for node in graph.keys() {
+        let (coord, full_coord) = (incremental.nodes[node], full.nodes[node]);
+        assert_eq!((full_coord.lat, full_coord.lon), (coord.lat, coord.lon));
+    }
+}
+
+#[test]
+fn retagged_way_becomes_a_road() {
+    let mut store = grid_store();
+    // Its nodes are not roads, but were kept
+    assert!(store.nodes.contains_key(&NodeId(2000)));
+    assert!(!store.graph.contains_key(&NodeId(2000)));
+    let report = store.apply_change("src/test_data/grid_retag.osc");
+    assert_eq!(vec![WayId(500)], report.changed_ways);
+    assert!(report.missing.is_complete());
+    assert!(report.reminimized_nodes < store.graph.len());
+
+    let mut full = Preprocessor::new();
+    full.get_roads_and_nodes("src/test_data/grid_retagged.osm");
+    let mut full_graph = Graph::build_graph(&full.nodes, &full.roads);
+    assert_eq!(sorted_edges(&full_graph), sorted_edges(&store.graph));
+    Graph::minimize_graph(&mut full_graph, true);
+    // The railway joins the middle of two blocks, which become junctions
+    assert!(full_graph.contains_key(&NodeId(1020)));
+    assert_same_minimized(&full_graph, &store.minimized);
+}
@@ -0,0 +1,551 @@
+use crate::preprocessor::coord::Coord;
+use crate::preprocessor::edge::Edge;
+use crate::preprocessor::graph::Graph;
+use crate::preprocessor::overlay::Overlay;
+use crate::preprocessor::preprocessor::{FullGraph, Landmark};
+use crate::preprocessor::projection::{Projection, ProjectionKind};
+use crate::preprocessor::spatial_index::{KdTree, SegmentHit, SegmentIndex};
+
+use osmpbfreader::NodeId;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// A path through the graph with its total cost and how many nodes the search settled
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub nodes: Vec<NodeId>,
+    pub cost: f32,
+    pub visited: usize,
+}
+
+impl Graph {
+    /// Dijkstra from start until end is settled
+    pub fn shortest_path(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        start: NodeId,
+        end: NodeId,
+    ) -> Option<Route> {
+        Graph::shortest_path_with(graph, &HashMap::new(), start, end, &|_| 0.0)
+    }
+
+    /// A* from start to end, also following the edges in extra. Extra edges are how queries add
+    /// temporary nodes without copying the graph. The heuristic must never overestimate the
+    /// remaining cost to end, and a heuristic of 0 makes this Dijkstra
+    pub fn shortest_path_with(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        extra: &HashMap<NodeId, Vec<Edge>>,
+        start: NodeId,
+        end: NodeId,
+        heuristic: &dyn Fn(NodeId) -> f32,
+    ) -> Option<Route> {
+        Graph::shortest_path_with_costs(graph, extra, start, end, heuristic, &|_, edge| edge.cost)
+    }
+
+    /// Like shortest_path_with, with the cost of every edge given by cost from the node it
+    /// leaves and the edge. Edges with an infinite cost are not followed
+    pub fn shortest_path_with_costs(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        extra: &HashMap<NodeId, Vec<Edge>>,
+        start: NodeId,
+        end: NodeId,
+        heuristic: &dyn Fn(NodeId) -> f32,
+        cost: &dyn Fn(NodeId, &Edge) -> f32,
+    ) -> Option<Route> {
+        // Best known cost and the node it was reached from
+        let mut distances: HashMap<NodeId, (f32, Option<NodeId>)> = HashMap::new();
+        let mut settled = HashSet::new();
+        // Ordered by cost so far plus the heuristic
+        let mut heap = BinaryHeap::new();
+        distances.insert(start, (0.0, None));
+        heap.push(Edge::new(start, heuristic(start)));
+
+        while let Some(Edge { node, .. }) = heap.pop() {
+            if !settled.insert(node) {
+                continue;
+            }
+            let node_cost = distances[&node].0;
+            if node == end {
+                let mut nodes = vec![end];
+                while let Some(previous) = distances[nodes.last().unwrap()].1 {
+                    nodes.push(previous);
+                }
+                nodes.reverse();
+                return Some(Route {
+                    nodes,
+                    cost: node_cost,
+                    visited: settled.len(),
+                });
+            }
+            let edges = graph.get(&node).into_iter().flatten();
+            for edge in edges.chain(extra.get(&node).into_iter().flatten()) {
+                let next_cost = node_cost + cost(node, edge);
+                if next_cost.is_infinite() {
+                    continue;
+                }
+                if distances
+                    .get(&edge.node)
+                    .is_none_or(|(best, _)| next_cost < *best)
+                {
+                    distances.insert(edge.node, (next_cost, Some(node)));
+                    heap.push(Edge::new(edge.node, next_cost + heuristic(edge.node)));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The search used for a query, like the algorithms of the Unity client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Dijkstra,
+    /// A* with the great-circle distance to the end as heuristic
+    AStar,
+    /// A* with lower bounds from the landmark distances (ALT)
+    Landmarks,
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dijkstra" => Ok(Algorithm::Dijkstra),
+            "astar" => Ok(Algorithm::AStar),
+            "landmarks" | "alt" => Ok(Algorithm::Landmarks),
+            _ => Err(format!("unknown algorithm {}", s)),
+        }
+    }
+}
+
+/*
+   Routing between arbitrary coordinates.
+   Both coordinates are snapped onto the closest edge. The start becomes a temporary node with
+   edges to the ends of the edges it lies on, and the end a temporary node reached from their
+   starts, each costing the part of the edge between the node and the snapped point. Since
+   edges are directed, a point on a oneway street can only leave in the direction of travel.
+   The temporary nodes use the negative ids START and END, which never occur in a FullGraph.
+*/
+pub const START: NodeId = NodeId(-1);
+pub const END: NodeId = NodeId(-2);
+
+/// Where a coordinate was snapped to and the edges it lies on
+#[derive(Debug, Clone)]
+pub struct Snapped {
+    pub coord: Coord,
+    pub hits: Vec<SegmentHit>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SnappedRoute {
+    /// The graph nodes passed, without the temporary start and end
+    pub route: Route,
+    pub start: Snapped,
+    pub end: Snapped,
+    /// The snapped start, every node passed and the snapped end
+    pub coords: Vec<Coord>,
+}
+
+/// Everything needed to answer queries on a loaded graph
+pub struct Router {
+    pub graph: HashMap<NodeId, Vec<Edge>>,
+    pub coords: HashMap<NodeId, Coord>,
+    pub landmarks: Vec<Landmark>,
+    projection: Box<dyn Projection>,
+    nodes: KdTree<NodeId>,
+    segments: SegmentIndex,
+    overlay: Option<Overlay>,
+}
+
+impl Router {
+    pub fn new(full_graph: &FullGraph) -> Router {
+        let coords = full_graph.coords();
+        // Graphs from before the projection was recorded are projected again around their mean
+        let projection = full_graph.projection().unwrap_or_else(|| {
+            let n = coords.len().max(1) as f64;
+            let (lat, lon) = coords.values().fold((0.0, 0.0), |(lat, lon), coord| {
+                (lat + coord.lat / n, lon + coord.lon / n)
+            });
+            ProjectionKind::AzimuthalEquidistant.with_center((lat, lon))
+        });
+        let points: HashMap<NodeId, (f32, f32)> = coords
+            .iter()
+            .map(|(node, coord)| {
+                let (x, y) = projection.project(*coord);
+                (*node, (x as f32, y as f32))
+            })
+            .collect();
+        let graph = full_graph.graph();
+        let nodes = KdTree::new(
+            points
+                .iter()
+                .map(|(node, (x, y))| (*node, (*x as f64, *y as f64)))
+                .collect(),
+        );
+        let segments = SegmentIndex::new(&graph, &points);
+        Router {
+            graph,
+            coords,
+            landmarks: full_graph.landmarks.clone(),
+            projection,
+            nodes,
+            segments,
+            overlay: None,
+        }
+    }
+
+    /// Routes follow the changed costs of the overlay from now on, or the costs in the graph
+    /// again with None. See overlay.rs
+    pub fn set_overlay(&mut self, overlay: Option<Overlay>) {
+        self.overlay = overlay;
+    }
+
+    pub fn overlay(&self) -> Option<&Overlay> {
+        self.overlay.as_ref()
+    }
+
+    /// Cost of an edge leaving from with the overlay applied, infinite when it is blocked
+    pub(crate) fn edge_cost(&self, from: NodeId, edge: &Edge) -> f32 {
+        match &self.overlay {
+            Some(overlay) => overlay.cost(from, edge),
+            None => edge.cost,
+        }
+    }
+
+    /// The algorithm to use instead when the overlay lowers costs below its lower bounds
+    fn admissible(&self, algorithm: Algorithm) -> Algorithm {
+        match &self.overlay {
+            Some(overlay) => overlay.admissible(algorithm),
+            None => algorithm,
+        }
+    }
+
+    /// Planar position in meters, in the projection of the graph
+    pub fn project(&self, coord: Coord) -> (f64, f64) {
+        self.projection.project(coord)
+    }
+
+    pub fn nearest_node(&self, coord: Coord) -> Option<NodeId> {
+        let point = self.projection.project(coord);
+        self.nodes.nearest(point).map(|(node, _)| node)
+    }
+
+    /// Snaps the coordinate onto the closest road
+    pub fn snap(&self, coord: Coord) -> Option<Snapped> {
+        let hits = self.segments.nearest_all(self.projection.project(coord));
+        let hit = hits.first()?;
+        let (from, to) = (self.coords[&hit.from], self.coords[&hit.to]);
+        // Edges are short enough that interpolating lat/lon is as good as unprojecting
+        let coord = Coord {
+            lat: from.lat + (to.lat - from.lat) * hit.fraction,
+            lon: from.lon + (to.lon - from.lon) * hit.fraction,
+        };
+        Some(Snapped { coord, hits })
+    }
+
+    pub fn route_between_nodes(
+        &self,
+        start: NodeId,
+        end: NodeId,
+        algorithm: Algorithm,
+    ) -> Option<Route> {
+        let algorithm = self.admissible(algorithm);
+        let heuristic = self.heuristic(algorithm, vec![(end, 0.0)], *self.coords.get(&end)?);
+        let cost = |from, edge: &Edge| self.edge_cost(from, edge);
+        Graph::shortest_path_with_costs(
+            &self.graph,
+            &HashMap::new(),
+            start,
+            end,
+            &heuristic,
+            &cost,
+        )
+    }
+
+    pub fn route_between_coords(
+        &self,
+        start: Coord,
+        end: Coord,
+        algorithm: Algorithm,
+    ) -> Option<SnappedRoute> {
+        let start = self.snap_with_overlay(start)?;
+        let end = self.snap_with_overlay(end)?;
+        let algorithm = self.admissible(algorithm);
+        // The temporary end is reached from the start of each edge it lies on
+        let targets = end
+            .hits
+            .iter()
+            .map(|hit| (hit.from, hit.cost * hit.fraction as f32))
+            .collect();
+        let heuristic = self.heuristic(algorithm, targets, end.coord);
+        let cost = |from, edge: &Edge| self.edge_cost(from, edge);
+        let route = Graph::shortest_path_with_costs(
+            &self.graph,
+            &Router::virtual_edges(&start, &end),
+            START,
+            END,
+            &heuristic,
+            &cost,
+        )?;
+        let nodes = route.nodes[1..route.nodes.len() - 1].to_vec();
+        let mut coords = vec![start.coord];
+        coords.extend(nodes.iter().map(|node| self.coords[node]));
+        coords.push(end.coord);
+        Some(SnappedRoute {
+            route: Route { nodes, ..route },
+            start,
+            end,
+            coords,
+        })
+    }
+
+    /// Like snap, with the costs of the edges from the overlay and without blocked edges
+    pub(crate) fn snap_with_overlay(&self, coord: Coord) -> Option<Snapped> {
+        let mut snapped = self.snap(coord)?;
+        if self.overlay.is_some() {
+            for hit in &mut snapped.hits {
+                hit.cost = self.edge_cost(hit.from, &Edge::new(hit.to, hit.cost));
+            }
+            snapped.hits.retain(|hit| hit.cost.is_finite());
+        }
+        Some(snapped)
+    }
+
+    /// Lower bound on the cost from a node to the end. The end is reached through one of
+    /// the targets, with the given cost after it. Temporary nodes get 0
+    fn heuristic<'a>(
+        &'a self,
+        algorithm: Algorithm,
+        targets: Vec<(NodeId, f32)>,
+        end: Coord,
+    ) -> impl Fn(NodeId) -> f32 + 'a {
+        move |node: NodeId| {
+            if node.0 < 0 {
+                return 0.0;
+            }
+            match algorithm {
+                Algorithm::Dijkstra => 0.0,
+                // Slightly below the straight line distance so f32 rounding in the edge
+                // costs never makes it overestimate
+                Algorithm::AStar => self.coords[&node].distance_to(end) * 0.999,
+                Algorithm::Landmarks => targets
+                    .iter()
+                    .map(|(target, after)| self.landmark_bound(node, *target) + after)
+                    .fold(f32::INFINITY, f32::min),
+            }
+        }
+    }
+
+    /// The largest lower bound on the cost from node to target the triangle inequality gives
+    /// for any landmark. Distances are indexed by node id, which is 0..n after rewrite_ids
+    fn landmark_bound(&self, node: NodeId, target: NodeId) -> f32 {
+        let (v, t) = (node.0 as usize, target.0 as usize);
+        let bound = |to_v: Option<&f32>, to_t: Option<&f32>| match (to_v, to_t) {
+            (Some(&a), Some(&b)) if a != f32::MAX && b != f32::MAX => b - a,
+            _ => 0.0,
+        };
+        self.landmarks
+            .iter()
+            .map(|landmark| {
+                let (from_landmark, to_landmark) = (&landmark.distances, &landmark.bi_distances);
+                // d(L, t) <= d(L, v) + d(v, t) and d(v, L) <= d(v, t) + d(t, L)
+                let behind = bound(from_landmark.get(v), from_landmark.get(t));
+                let ahead = bound(to_landmark.get(t), to_landmark.get(v));
+                behind.max(ahead)
+            })
+            .fold(0.0, f32::max)
+    }
+
+    fn virtual_edges(start: &Snapped, end: &Snapped) -> HashMap<NodeId, Vec<Edge>> {
+        let mut extra: HashMap<NodeId, Vec<Edge>> = HashMap::new();
+        for hit in &start.hits {
+            let remaining = hit.cost * (1.0 - hit.fraction as f32);
+            extra
+                .entry(START)
+                .or_default()
+                .push(Edge::new(hit.to, remaining));
+        }
+        for hit in &end.hits {
+            let travelled = hit.cost * hit.fraction as f32;
+            extra
+                .entry(hit.from)
+                .or_default()
+                .push(Edge::new(END, travelled));
+            // Both points on the same edge, with the end further along
+            for start_hit in &start.hits {
+                let same_edge = (start_hit.from, start_hit.to) == (hit.from, hit.to);
+                if same_edge && start_hit.fraction <= hit.fraction {
+                    let between = hit.cost * (hit.fraction - start_hit.fraction) as f32;
+                    extra
+                        .entry(START)
+                        .or_default()
+                        .push(Edge::new(END, between));
+                }
+            }
+        }
+        extra
+    }
+}
+
+// TESTS
+/// A router on the test grid, shared by the tests of every module
+#[cfg(test)]
+pub(crate) fn grid_router() -> Router {
+    Router::new(&crate::preprocessor::preprocessor::grid_full_graph())
+}
+
+#[cfg(test)]
+fn along(router: &Router, from: NodeId, to: NodeId, fraction: f64) -> Coord {
+    let (a, b) = (router.coords[&from], router.coords[&to]);
+    Coord {
+        lat: a.lat + (b.lat - a.lat) * fraction,
+        lon: a.lon + (b.lon - a.lon) * fraction,
+    }
+}
+
+#[test]
+fn shortest_path_matches_dijkstra_all() {
+    let router = grid_router();
+    for start in router.graph.keys() {
+        let distances = Graph::dijkstra_all(&router.graph, *start);
+        for (end, distance) in distances.iter().enumerate() {
+            let route = router.route_between_nodes(*start, NodeId(end as i64), Algorithm::Dijkstra);
+            match route {
+                Some(route) => {
+                    assert_eq!(*distance, route.cost);
+                    assert_eq!(
+                        (*start, NodeId(end as i64)),
+                        (route.nodes[0], route.nodes[route.nodes.len() - 1])
+                    );
+                    let cost: f32 = route
+                        .nodes
+                        .windows(2)
+                        .map(|pair| {
+                            let edges = &router.graph[&pair[0]];
+                            edges.iter().find(|e| e.node == pair[1]).unwrap().cost
+                        })
+                        .sum();
+                    assert!((cost - route.cost).abs() < 1e-2);
+                }
+                None => assert_eq!(f32::MAX, *distance),
+            }
+        }
+    }
+}
+
+#[test]
+fn routes_along_a_single_edge() {
+    let router = grid_router();
+    // A two-way edge, so the route can go either way along it
+    let (from, edge) = router
+        .graph
+        .iter()
+        .flat_map(|(from, edges)| edges.iter().map(move |edge| (*from, *edge)))
+        .find(|(from, edge)| router.graph[&edge.node].iter().any(|e| e.node == *from))
+        .unwrap();
+    for (start, end) in [(0.2, 0.7), (0.7, 0.2)] {
+        let route = router
+            .route_between_coords(
+                along(&router, from, edge.node, start),
+                along(&router, from, edge.node, end),
+                Algorithm::Dijkstra,
+            )
+            .unwrap();
+        assert!(route.route.nodes.is_empty());
+        assert!((route.route.cost / edge.cost - 0.5).abs() < 1e-2);
+        assert_eq!(2, route.coords.len());
+    }
+}
+
+#[test]
+fn respects_oneway_edges() {
+    let router = grid_router();
+    let (from, edge) = router
+        .graph
+        .iter()
+        .flat_map(|(from, edges)| edges.iter().map(move |edge| (*from, *edge)))
+        .find(|(from, edge)| !router.graph[&edge.node].iter().any(|e| e.node == *from))
+        .expect("row 1 of the grid is oneway");
+    // Going with the direction of travel stays on the edge
+    let forward = router
+        .route_between_coords(
+            along(&router, from, edge.node, 0.2),
+            along(&router, from, edge.node, 0.7),
+            Algorithm::Dijkstra,
+        )
+        .unwrap();
+    assert!(forward.route.nodes.is_empty());
+    // Going against it has to leave the edge at its end and come back around
+    let backward = router
+        .route_between_coords(
+            along(&router, from, edge.node, 0.7),
+            along(&router, from, edge.node, 0.2),
+            Algorithm::Landmarks,
+        )
+        .unwrap();
+    assert_eq!(Some(&edge.node), backward.route.nodes.first());
+    assert_eq!(Some(&from), backward.route.nodes.last());
+    assert!(backward.route.cost > edge.cost);
+}
+
+#[test]
+fn snaps_onto_the_closest_road() {
+    let router = grid_router();
+    let (from, edge) = router
+        .graph
+        .iter()
+        .flat_map(|(from, edges)| edges.iter().map(move |edge| (*from, *edge)))
+        .next()
+        .unwrap();
+    let on_road = along(&router, from, edge.node, 0.5);
+    // Roughly ten meters off the road
+    let off_road = Coord {
+        lat: on_road.lat + 0.00005,
+        lon: on_road.lon + 0.00005,
+    };
+    let snapped = router.snap(off_road).unwrap();
+    assert!(snapped.coord.distance_to(on_road) < 10.0);
+    assert!(snapped
+        .hits
+        .iter()
+        .all(|hit| (hit.from, hit.to) == (from, edge.node)
+            || (hit.from, hit.to) == (edge.node, from)));
+    assert!(router.nearest_node(router.coords[&from]) == Some(from));
+}
+
+#[test]
+fn algorithms_agree_on_the_cost() {
+    let router = grid_router();
+    let algorithms = [Algorithm::Dijkstra, Algorithm::AStar, Algorithm::Landmarks];
+    for start in router.graph.keys() {
+        for end in router.graph.keys() {
+            let routes: Vec<Option<Route>> = algorithms
+                .iter()
+                .map(|algorithm| router.route_between_nodes(*start, *end, *algorithm))
+                .collect();
+            let dijkstra = routes[0].as_ref().map(|route| route.cost);
+            for route in &routes[1..] {
+                let cost = route.as_ref().map(|route| route.cost);
+                match (dijkstra, cost) {
+                    (Some(a), Some(b)) => assert!((a - b).abs() <= a * 1e-5),
+                    _ => assert_eq!(dijkstra, cost),
+                }
+            }
+            // The heuristics only ever save work
+            if let [Some(dijkstra), Some(astar), Some(alt)] = &routes[..] {
+                assert!(astar.visited <= dijkstra.visited);
+                assert!(alt.visited <= dijkstra.visited);
+            }
+        }
+    }
+    // Between coordinates too
+    let nodes: Vec<&Coord> = router.coords.values().collect();
+    let costs: Vec<f32> = algorithms
+        .iter()
+        .map(|algorithm| {
+            let route = router.route_between_coords(*nodes[0], *nodes[nodes.len() - 1], *algorithm);
+            route.unwrap().route.cost
+        })
+        .collect();
+    assert!((costs[0] - costs[1]).abs() <= costs[0] * 1e-5);
+    assert!((costs[0] - costs[2]).abs() <= costs[0] * 1e-5);
+}
@@ -0,0 +1,582 @@
+use crate::preprocessor::coord::Coord;
+use crate::preprocessor::edge::Edge;
+use crate::preprocessor::preprocessor::FullGraph;
+use crate::preprocessor::projection::Projection;
+
+use osmpbfreader::NodeId;
+use std::collections::HashMap;
+
+/*
+   Nearest neighbour lookups in the projected x, y plane.
+
+   KdTree stores its points as an implicit balanced tree: the middle element of every range
+   is the root of that range, split on x at even depths and on y at odd depths, with the
+   smaller half before it. The order alone is therefore enough to rebuild the tree, which is
+   what FullGraph::spatial_index keeps for the nodes.
+*/
+pub struct KdTree<T: Copy> {
+    ids: Vec<T>,
+    points: Vec<(f64, f64)>,
+}
+
+fn axis(point: (f64, f64), depth: usize) -> f64 {
+    if depth.is_multiple_of(2) {
+        point.0
+    } else {
+        point.1
+    }
+}
+
+fn squared_distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    (a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)
+}
+
+impl<T: Copy> KdTree<T> {
+    pub fn new(mut items: Vec<(T, (f64, f64))>) -> KdTree<T> {
+        fn build<T>(items: &mut [(T, (f64, f64))], depth: usize) {
+            if items.len() <= 1 {
+                return;
+            }
+            let mid = items.len() / 2;
+            items.select_nth_unstable_by(mid, |a, b| axis(a.1, depth).total_cmp(&axis(b.1, depth)));
+            let (left, right) = items.split_at_mut(mid);
+            build(left, depth + 1);
+            build(&mut right[1..], depth + 1);
+        }
+        build(&mut items, 0);
+        KdTree::from_ordered(items)
+    }
+
+    /// Uses items already in tree order, as given by KdTree::ids
+    pub fn from_ordered(items: Vec<(T, (f64, f64))>) -> KdTree<T> {
+        let (ids, points) = items.into_iter().unzip();
+        KdTree { ids, points }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// The ids in tree order
+    pub fn ids(&self) -> &[T] {
+        &self.ids
+    }
+
+    /// The closest point and its distance, None for queries that are not finite
+    pub fn nearest(&self, query: (f64, f64)) -> Option<(T, f64)> {
+        if !query.0.is_finite() || !query.1.is_finite() {
+            return None;
+        }
+        let mut best: Option<(usize, f64)> = None;
+        self.search(0, self.len(), 0, query, &mut |candidate| {
+            match (candidate, best) {
+                (Some((index, distance)), Some((_, bound))) if distance < bound => {
+                    best = Some((index, distance))
+                }
+                (Some(candidate), None) => best = Some(candidate),
+                _ => {}
+            }
+            best.map_or(f64::INFINITY, |(_, distance)| distance)
+        });
+        best.map(|(index, distance)| (self.ids[index], distance.sqrt()))
+    }
+
+    /// Every point at most radius away, with its distance, closest first
+    pub fn within(&self, query: (f64, f64), radius: f64) -> Vec<(T, f64)> {
+        let mut found = Vec::new();
+        let radius2 = radius * radius;
+        self.search(0, self.len(), 0, query, &mut |candidate| {
+            if let Some((index, distance)) = candidate.filter(|(_, d)| *d <= radius2) {
+                found.push((index, distance));
+            }
+            radius2
+        });
+        found.sort_by(|a, b| a.1.total_cmp(&b.1));
+        found
+            .into_iter()
+            .map(|(index, distance)| (self.ids[index], distance.sqrt()))
+            .collect()
+    }
+
+    /// Whether every point lies on the side of its ancestors' splits that the order says
+    pub fn is_tree_order(&self) -> bool {
+        fn check(points: &[(f64, f64)], depth: usize, low: (f64, f64), high: (f64, f64)) -> bool {
+            if points.is_empty() {
+                return true;
+            }
+            let mid = points.len() / 2;
+            let point = points[mid];
+            if !(low.0 <= point.0 && point.0 <= high.0 && low.1 <= point.1 && point.1 <= high.1) {
+                return false;
+            }
+            let (left_high, right_low) = if depth.is_multiple_of(2) {
+                ((point.0, high.1), (point.0, low.1))
+            } else {
+                ((high.0, point.1), (low.0, point.1))
+            };
+            check(&points[..mid], depth + 1, low, left_high)
+                && check(&points[mid + 1..], depth + 1, right_low, high)
+        }
+        let infinity = (f64::INFINITY, f64::INFINITY);
+        check(&self.points, 0, (-infinity.0, -infinity.1), infinity)
+    }
+
+    /// Visits the index and squared distance of points that may be closer than the squared
+    /// bound returned by visit. Visiting None only asks for the current bound
+    fn search(
+        &self,
+        start: usize,
+        end: usize,
+        depth: usize,
+        query: (f64, f64),
+        visit: &mut impl FnMut(Option<(usize, f64)>) -> f64,
+    ) {
+        if start >= end {
+            return;
+        }
+        let mid = start + (end - start) / 2;
+        let point = self.points[mid];
+        visit(Some((mid, squared_distance(point, query))));
+        let diff = axis(query, depth) - axis(point, depth);
+        let (near, far) = if diff < 0.0 {
+            ((start, mid), (mid + 1, end))
+        } else {
+            ((mid + 1, end), (start, mid))
+        };
+        self.search(near.0, near.1, depth + 1, query, visit);
+        if diff * diff <= visit(None) {
+            self.search(far.0, far.1, depth + 1, query, visit);
+        }
+    }
+
+    /// The largest of values, given in tree order, in the subtree rooted at every position
+    fn subtree_max(&self, values: &[f64]) -> Vec<f64> {
+        fn fill(values: &[f64], max: &mut [f64], start: usize, end: usize) -> f64 {
+            if start >= end {
+                return 0.0;
+            }
+            let mid = start + (end - start) / 2;
+            let left = fill(values, max, start, mid);
+            let right = fill(values, max, mid + 1, end);
+            max[mid] = values[mid].max(left).max(right);
+            max[mid]
+        }
+        let mut max = vec![0.0; values.len()];
+        fill(values, &mut max, 0, values.len());
+        max
+    }
+
+    /// Like search, for items that reach up to extents[position] from their point, with
+    /// extents from subtree_max. Visits the tree position of items that may be closer than
+    /// the distance returned by visit, skipping subtrees whose reach cannot get that close.
+    /// Visiting None only asks for the current bound
+    fn search_extents(
+        &self,
+        start: usize,
+        end: usize,
+        depth: usize,
+        query: (f64, f64),
+        extents: &[f64],
+        visit: &mut impl FnMut(Option<usize>) -> f64,
+    ) {
+        if start >= end {
+            return;
+        }
+        let mid = start + (end - start) / 2;
+        visit(Some(mid));
+        let diff = axis(query, depth) - axis(self.points[mid], depth);
+        let (near, far) = if diff < 0.0 {
+            ((start, mid), (mid + 1, end))
+        } else {
+            ((mid + 1, end), (start, mid))
+        };
+        self.search_extents(near.0, near.1, depth + 1, query, extents, visit);
+        if far.0 < far.1 {
+            let reach = extents[far.0 + (far.1 - far.0) / 2];
+            if diff.abs() - reach <= visit(None) {
+                self.search_extents(far.0, far.1, depth + 1, query, extents, visit);
+            }
+        }
+    }
+}
+
+/// The closest point on a directed edge
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentHit {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub cost: f32,
+    /// How far along the edge the point is, from 0 at `from` to 1 at `to`
+    pub fraction: f64,
+    pub point: (f64, f64),
+    pub distance: f64,
+}
+
+struct Segment {
+    from: NodeId,
+    to: NodeId,
+    cost: f32,
+    a: (f64, f64),
+    b: (f64, f64),
+}
+
+impl Segment {
+    fn closest(&self, query: (f64, f64)) -> SegmentHit {
+        let (dx, dy) = (self.b.0 - self.a.0, self.b.1 - self.a.1);
+        let length2 = dx * dx + dy * dy;
+        let fraction = if length2 == 0.0 {
+            0.0
+        } else {
+            (((query.0 - self.a.0) * dx + (query.1 - self.a.1) * dy) / length2).clamp(0.0, 1.0)
+        };
+        let point = (self.a.0 + fraction * dx, self.a.1 + fraction * dy);
+        SegmentHit {
+            from: self.from,
+            to: self.to,
+            cost: self.cost,
+            fraction,
+            point,
+            distance: squared_distance(point, query).sqrt(),
+        }
+    }
+}
+
+/// Closest point on any edge, for snapping to a road rather than to a junction.
+/// Edges are straight lines between their end nodes, as the minimized graph has no other geometry
+pub struct SegmentIndex {
+    segments: Vec<Segment>,
+    midpoints: KdTree<usize>,
+    /// The longest half segment in the subtree at every position of midpoints, as no point of
+    /// a segment is further from its midpoint than that
+    half_lengths: Vec<f64>,
+}
+
+impl SegmentIndex {
+    pub fn new(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        points: &HashMap<NodeId, (f32, f32)>,
+    ) -> SegmentIndex {
+        let point = |node: &NodeId| {
+            let (x, y) = points[node];
+            (x as f64, y as f64)
+        };
+        let segments: Vec<Segment> = graph
+            .iter()
+            .flat_map(|(from, edges)| {
+                edges.iter().map(move |edge| Segment {
+                    from: *from,
+                    to: edge.node,
+                    cost: edge.cost,
+                    a: point(from),
+                    b: point(&edge.node),
+                })
+            })
+            .collect();
+        let midpoints = KdTree::new(
+            segments
+                .iter()
+                .enumerate()
+                .map(|(i, s)| (i, ((s.a.0 + s.b.0) / 2.0, (s.a.1 + s.b.1) / 2.0)))
+                .collect(),
+        );
+        let half_lengths: Vec<f64> = midpoints
+            .ids()
+            .iter()
+            .map(|i| squared_distance(segments[*i].a, segments[*i].b).sqrt() / 2.0)
+            .collect();
+        SegmentIndex {
+            half_lengths: midpoints.subtree_max(&half_lengths),
+            segments,
+            midpoints,
+        }
+    }
+
+    /// The closest edge, both directions of a two-way road are equally close
+    pub fn nearest(&self, query: (f64, f64)) -> Option<SegmentHit> {
+        self.nearest_all(query).into_iter().next()
+    }
+
+    /// Every edge as close as the closest one, so callers can pick the directions they need
+    pub fn nearest_all(&self, query: (f64, f64)) -> Vec<SegmentHit> {
+        if !query.0.is_finite() || !query.1.is_finite() {
+            return Vec::new();
+        }
+        let mut hits: Vec<SegmentHit> = Vec::new();
+        let mut best = f64::INFINITY;
+        let tree = &self.midpoints;
+        tree.search_extents(
+            0,
+            tree.len(),
+            0,
+            query,
+            &self.half_lengths,
+            &mut |position| {
+                if let Some(position) = position {
+                    let hit = self.segments[tree.ids[position]].closest(query);
+                    if hit.distance <= best + 1e-9 {
+                        best = best.min(hit.distance);
+                        hits.push(hit);
+                    }
+                }
+                best + 1e-9
+            },
+        );
+        hits.retain(|hit| hit.distance <= best + 1e-9);
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        hits
+    }
+}
+
+impl FullGraph {
+    /// The projection used for x and y, None for graphs from before it was recorded
+    pub fn projection(&self) -> Option<Box<dyn Projection>> {
+        let kind: crate::preprocessor::projection::ProjectionKind =
+            self.header.projection.parse().ok()?;
+        Some(kind.with_center(self.header.projection_center))
+    }
+
+    /// Index over the projected node positions, reusing the stored order when it is valid
+    pub fn node_index(&self) -> KdTree<NodeId> {
+        self.stored_node_index().unwrap_or_else(|| {
+            KdTree::new(
+                self.nodes
+                    .iter()
+                    .map(|node| (node.node_id, (node.x as f64, node.y as f64)))
+                    .collect(),
+            )
+        })
+    }
+
+    /// The tree in the stored order, None when there is none or it does not fit the nodes
+    fn stored_node_index(&self) -> Option<KdTree<NodeId>> {
+        let order = self.spatial_index.as_ref()?;
+        if order.len() != self.nodes.len() {
+            return None;
+        }
+        let mut positions: HashMap<NodeId, (f64, f64)> = self
+            .nodes
+            .iter()
+            .map(|node| (node.node_id, (node.x as f64, node.y as f64)))
+            .collect();
+        // Removing each id as it is used also rejects orders that repeat one
+        let items = order
+            .iter()
+            .map(|id| positions.remove(id).map(|point| (*id, point)))
+            .collect::<Option<Vec<_>>>()?;
+        let tree = KdTree::from_ordered(items);
+        tree.is_tree_order().then_some(tree)
+    }
+
+    /// Drops a stored order that does not fit the nodes, so it is neither used nor written again
+    pub fn check_spatial_index(&mut self) {
+        if self.spatial_index.is_some() && self.stored_node_index().is_none() {
+            self.spatial_index = None;
+        }
+    }
+
+    /// Stores the node order of the k-d tree so readers can skip building it
+    pub fn add_spatial_index(&mut self) {
+        self.spatial_index = Some(self.node_index().ids().to_vec());
+    }
+
+    pub fn segment_index(&self) -> SegmentIndex {
+        SegmentIndex::new(&self.graph(), &self.projected_points())
+    }
+
+    /// The node closest to a coordinate, projected like the graph was
+    pub fn nearest_node(&self, index: &KdTree<NodeId>, coord: Coord) -> Option<NodeId> {
+        let point = self.projection()?.project(coord);
+        index.nearest(point).map(|(node, _)| node)
+    }
+}
+
+// TESTS
+#[cfg(test)]
+fn scattered_points() -> Vec<(usize, (f64, f64))> {
+    // Deterministic pseudo random points
+    let mut state: u64 = 12345;
+    let mut next = || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (state >> 33) as f64 / (1u64 << 31) as f64 * 1000.0
+    };
+    (0..500).map(|i| (i, (next(), next()))).collect()
+}
+
+#[test]
+fn kd_tree_finds_the_same_nearest_as_brute_force() {
+    let points = scattered_points();
+    let tree = KdTree::new(points.clone());
+    for (_, query) in scattered_points()
+        .iter()
+        .map(|(i, (x, y))| (i, (y + 0.5, x - 0.5)))
+    {
+        let brute = points
+            .iter()
+            .map(|(i, p)| (*i, squared_distance(*p, query).sqrt()))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        assert_eq!(Some(brute), tree.nearest(query));
+    }
+    assert_eq!(None, KdTree::<usize>::new(Vec::new()).nearest((0.0, 0.0)));
+}
+
+#[test]
+fn kd_tree_finds_everything_within_radius() {
+    let points = scattered_points();
+    let tree = KdTree::new(points.clone());
+    let query = (500.0, 500.0);
+    let mut expected: Vec<usize> = points
+        .iter()
+        .filter(|(_, p)| squared_distance(*p, query) <= 100.0 * 100.0)
+        .map(|(i, _)| *i)
+        .collect();
+    let mut found: Vec<usize> = tree.within(query, 100.0).iter().map(|(i, _)| *i).collect();
+    expected.sort();
+    found.sort();
+    assert_eq!(expected, found);
+}
+
+#[test]
+fn kd_tree_order_rebuilds_the_tree() {
+    let tree = KdTree::new(scattered_points());
+    let positions: HashMap<usize, (f64, f64)> = scattered_points().into_iter().collect();
+    let rebuilt = KdTree::from_ordered(tree.ids().iter().map(|i| (*i, positions[i])).collect());
+    assert_eq!(tree.nearest((10.0, 990.0)), rebuilt.nearest((10.0, 990.0)));
+}
+
+#[test]
+fn segment_index_snaps_onto_the_edge() {
+    let graph = HashMap::from([
+        (NodeId(0), vec![Edge::new(NodeId(1), 100.0)]),
+        (NodeId(1), vec![Edge::new(NodeId(0), 100.0)]),
+        (NodeId(2), vec![Edge::new(NodeId(0), 50.0)]),
+    ]);
+    let points = HashMap::from([
+        (NodeId(0), (0.0, 0.0)),
+        (NodeId(1), (100.0, 0.0)),
+        (NodeId(2), (0.0, 50.0)),
+    ]);
+    let index = SegmentIndex::new(&graph, &points);
+    let hits = index.nearest_all((30.0, 10.0));
+    // Both directions of the two-way road between 0 and 1
+    assert_eq!(2, hits.len());
+    let hit = hits.iter().find(|hit| hit.from == NodeId(0)).unwrap();
+    assert_eq!(NodeId(1), hit.to);
+    assert!((hit.fraction - 0.3).abs() < 1e-9);
+    assert_eq!((30.0, 0.0), hit.point);
+    assert!((hit.distance - 10.0).abs() < 1e-9);
+    let hit = index.nearest((-5.0, 40.0)).unwrap();
+    assert_eq!((NodeId(2), NodeId(0)), (hit.from, hit.to));
+}
+
+#[test]
+fn segment_index_finds_the_same_edges_as_brute_force() {
+    // Short edges between neighbouring points, and one long edge across all of them
+    let points: HashMap<NodeId, (f32, f32)> = scattered_points()
+        .into_iter()
+        .map(|(i, (x, y))| (NodeId(i as i64), (x as f32, y as f32)))
+        .collect();
+    let mut graph: HashMap<NodeId, Vec<Edge>> = (0..499)
+        .map(|i| (NodeId(i), vec![Edge::new(NodeId(i + 1), 1.0)]))
+        .collect();
+    graph.insert(NodeId(499), vec![Edge::new(NodeId(0), 1.0)]);
+    let index = SegmentIndex::new(&graph, &points);
+    for (_, (x, y)) in scattered_points() {
+        let query = (y + 0.5, x - 0.5);
+        let brute = index
+            .segments
+            .iter()
+            .map(|segment| segment.closest(query).distance)
+            .fold(f64::INFINITY, f64::min);
+        let hits = index.nearest_all(query);
+        assert!(!hits.is_empty());
+        assert!(hits.iter().all(|hit| (hit.distance - brute).abs() < 1e-9));
+    }
+}
+
+#[test]
+fn segment_index_finds_nothing_for_invalid_queries() {
+    let graph = HashMap::from([(NodeId(0), vec![Edge::new(NodeId(1), 100.0)])]);
+    let points = HashMap::from([(NodeId(0), (0.0, 0.0)), (NodeId(1), (100.0, 0.0))]);
+    let index = SegmentIndex::new(&graph, &points);
+    for query in [
+        (f64::NAN, 0.0),
+        (0.0, f64::INFINITY),
+        (f64::NEG_INFINITY, f64::NAN),
+    ] {
+        assert!(index.nearest_all(query).is_empty());
+        assert_eq!(None, index.nearest(query));
+    }
+    let empty = SegmentIndex::new(&HashMap::new(), &HashMap::new());
+    assert!(empty.nearest_all((0.0, 0.0)).is_empty());
+}
+
+#[test]
+fn full_graph_finds_nearest_node_by_coordinate() {
+    let mut full_graph = crate::preprocessor::preprocessor::grid_full_graph();
+    let index = full_graph.node_index();
+    for node in &full_graph.nodes {
+        let near = Coord {
+            lat: node.lat + 0.0001,
+            lon: node.lon - 0.0001,
+        };
+        assert_eq!(Some(node.node_id), full_graph.nearest_node(&index, near));
+    }
+
+    // The stored order survives a round trip and gives the same tree
+    full_graph.add_spatial_index();
+    let mut buf = Vec::new();
+    full_graph.write(&mut buf).unwrap();
+    let loaded = FullGraph::read(&mut buf.as_slice()).unwrap();
+    assert_eq!(full_graph.spatial_index, loaded.spatial_index);
+    assert_eq!(index.ids(), loaded.node_index().ids());
+}
+
+#[test]
+fn kd_tree_finds_nothing_for_invalid_queries() {
+    let tree = KdTree::new(scattered_points());
+    for query in [
+        (f64::NAN, 0.0),
+        (0.0, f64::INFINITY),
+        (f64::NEG_INFINITY, f64::NAN),
+    ] {
+        assert_eq!(None, tree.nearest(query));
+    }
+}
+
+#[test]
+fn kd_tree_rejects_orders_that_are_not_trees() {
+    assert!(KdTree::new(scattered_points()).is_tree_order());
+    // Sorted by x, apart from the largest x now being first, left of the root
+    let mut points = scattered_points();
+    points.sort_by(|a, b| a.1 .0.total_cmp(&b.1 .0));
+    points.swap(0, 499);
+    assert!(!KdTree::from_ordered(points).is_tree_order());
+}
+
+#[test]
+fn full_graph_falls_back_from_invalid_spatial_indices() {
+    let mut full_graph = crate::preprocessor::preprocessor::grid_full_graph();
+    let expected = full_graph.node_index().ids().to_vec();
+    let mut reversed = expected.clone();
+    reversed.reverse();
+    let mut repeated = expected.clone();
+    repeated[1] = repeated[0];
+    let mut unknown = expected.clone();
+    unknown[0] = NodeId(-1);
+    for order in [expected[1..].to_vec(), repeated, unknown, reversed] {
+        full_graph.spatial_index = Some(order);
+        assert_eq!(expected, full_graph.node_index().ids());
+        full_graph.check_spatial_index();
+        assert_eq!(None, full_graph.spatial_index);
+    }
+    full_graph.add_spatial_index();
+    full_graph.check_spatial_index();
+    assert_eq!(Some(expected), full_graph.spatial_index);
+}
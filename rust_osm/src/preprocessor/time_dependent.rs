@@ -0,0 +1,377 @@
+use crate::preprocessor::coord::Coord;
+use crate::preprocessor::edge::Edge;
+use crate::preprocessor::matrix::location_lines;
+use crate::preprocessor::origins::EdgeOrigins;
+use crate::preprocessor::osrm::ASSUMED_SPEED;
+use crate::preprocessor::routing::Route;
+
+use osmpbfreader::{NodeId, WayId};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/*
+   Time-dependent travel times for rush-hour routing.
+   A speed profile gives the speed on a way at times of the day, linear in between and
+   wrapping around midnight. Every edge gets a piecewise-linear travel time function (TTF),
+   sampled at the times of day where the profiles of its ways change by driving its ways one
+   after another, and linear in between. Ways without a profile are driven at ASSUMED_SPEED.
+   The ways of the edges come from EdgeOrigins.
+   Travel time functions are made FIFO, so that leaving later never arrives earlier, by waiting:
+   the arrival at each sample is lowered to the earliest arrival of any later sample. On FIFO
+   edges, Dijkstra on arrival times finds the earliest arrival (time-dependent Dijkstra), and
+   A* can use the straight line distance at the highest speed of any edge.
+   Times are seconds since midnight of the day of departure.
+*/
+pub const DAY: f32 = 86_400.0;
+
+/// (seconds since midnight, meters per second) sorted by time
+pub type SpeedProfile = Vec<(f32, f32)>;
+
+/// The profile of every way that has one
+pub type SpeedProfiles = HashMap<WayId, SpeedProfile>;
+
+/// Reads "way id,HH:MM,km/h" lines, skipping blank lines and # comments
+pub fn read_speed_profiles(text: &str) -> Result<SpeedProfiles, String> {
+    let mut profiles: SpeedProfiles = HashMap::new();
+    for (number, line) in location_lines(text) {
+        let invalid = || {
+            format!(
+                "line {}: expected way id,HH:MM,km/h but got {}",
+                number, line
+            )
+        };
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let [way, time, speed] = fields.as_slice() else {
+            return Err(invalid());
+        };
+        let way = way.parse().map(WayId).map_err(|_| invalid())?;
+        let time = parse_time_of_day(time).ok_or_else(invalid)?;
+        let speed: f32 = speed.parse().map_err(|_| invalid())?;
+        if speed <= 0.0 || !speed.is_finite() {
+            return Err(format!("line {}: speeds must be finite and above 0", number));
+        }
+        profiles.entry(way).or_default().push((time, speed / 3.6));
+    }
+    for profile in profiles.values_mut() {
+        profile.sort_by(|a, b| a.0.total_cmp(&b.0));
+        profile.dedup_by(|a, b| a.0 == b.0);
+    }
+    Ok(profiles)
+}
+
+/// Seconds since midnight of "HH:MM" or "HH:MM:SS"
+pub fn parse_time_of_day(time: &str) -> Option<f32> {
+    let parts: Vec<u32> = time
+        .split(':')
+        .map(|p| p.parse().ok())
+        .collect::<Option<_>>()?;
+    let (hours, minutes, seconds) = match parts.as_slice() {
+        [hours, minutes] => (*hours, *minutes, 0),
+        [hours, minutes, seconds] => (*hours, *minutes, *seconds),
+        _ => return None,
+    };
+    (hours < 24 && minutes < 60 && seconds < 60)
+        .then(|| (hours * 3600 + minutes * 60 + seconds) as f32)
+}
+
+/// The value of a periodic piecewise-linear function given by points sorted by time of day
+fn interpolate(points: &[(f32, f32)], time: f32) -> f32 {
+    let time = time.rem_euclid(DAY);
+    let after = points.partition_point(|(t, _)| *t <= time);
+    // Before the first point or after the last, between the last point and the first of the
+    // next day
+    let (t0, v0) = match after {
+        0 => (points[points.len() - 1].0 - DAY, points[points.len() - 1].1),
+        _ => points[after - 1],
+    };
+    let (t1, v1) = match points.get(after) {
+        Some(point) => *point,
+        None => (points[0].0 + DAY, points[0].1),
+    };
+    match t1 - t0 {
+        span if span > 0.0 => v0 + (v1 - v0) * (time - t0) / span,
+        _ => v0,
+    }
+}
+
+/// Travel time of an edge by time of departure, periodic over a day and FIFO
+#[derive(Debug, Clone, PartialEq)]
+pub struct TravelTimeFunction {
+    /// (departure, travel time) in seconds, sorted by departure
+    points: Vec<(f32, f32)>,
+}
+
+impl TravelTimeFunction {
+    pub fn constant(duration: f32) -> TravelTimeFunction {
+        TravelTimeFunction {
+            points: vec![(0.0, duration)],
+        }
+    }
+
+    /// The function through the given (departure, travel time) points, made FIFO by waiting.
+    /// Departures are taken modulo a day, and there must be at least one point
+    pub fn new(mut points: Vec<(f32, f32)>) -> TravelTimeFunction {
+        for point in &mut points {
+            point.0 = point.0.rem_euclid(DAY);
+        }
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        points.dedup_by(|a, b| a.0 == b.0);
+        // Arrivals at the points of this day and the next, each lowered to the earliest
+        // arrival after it. A day later is far enough since no edge takes a day
+        let n = points.len();
+        let mut arrivals: Vec<f32> = (0..2 * n)
+            .map(|i| points[i % n].0 + points[i % n].1 + if i < n { 0.0 } else { DAY })
+            .collect();
+        for i in (0..2 * n - 1).rev() {
+            arrivals[i] = arrivals[i].min(arrivals[i + 1]);
+        }
+        for (point, arrival) in points.iter_mut().zip(arrivals) {
+            point.1 = arrival - point.0;
+        }
+        TravelTimeFunction { points }
+    }
+
+    pub fn duration_at(&self, departure: f32) -> f32 {
+        interpolate(&self.points, departure)
+    }
+
+    pub fn arrival(&self, departure: f32) -> f32 {
+        departure + self.duration_at(departure)
+    }
+
+    /// The shortest travel time at any time of day
+    pub fn min_duration(&self) -> f32 {
+        self.points
+            .iter()
+            .map(|(_, duration)| *duration)
+            .fold(f32::INFINITY, f32::min)
+    }
+}
+
+/// Travel time function of driving the given (meters, speed profile) pieces in order
+fn edge_function(pieces: &[(f32, Option<&SpeedProfile>)]) -> TravelTimeFunction {
+    let speed = |profile: Option<&SpeedProfile>, time| {
+        profile.map_or(ASSUMED_SPEED as f32, |profile| interpolate(profile, time))
+    };
+    let mut departures: Vec<f32> = pieces
+        .iter()
+        .flat_map(|(_, profile)| profile.iter().flat_map(|p| p.iter()).map(|(time, _)| *time))
+        .collect();
+    if departures.is_empty() {
+        let meters: f32 = pieces.iter().map(|(meters, _)| meters).sum();
+        return TravelTimeFunction::constant(meters / ASSUMED_SPEED as f32);
+    }
+    departures.sort_by(f32::total_cmp);
+    departures.dedup();
+    let points = departures
+        .into_iter()
+        .map(|departure| {
+            let mut time = departure;
+            for (meters, profile) in pieces {
+                time += meters / speed(*profile, time);
+            }
+            (departure, time - departure)
+        })
+        .collect();
+    TravelTimeFunction::new(points)
+}
+
+/// A graph whose edges take a time that depends on when they are entered
+pub struct TimeDependentGraph {
+    pub graph: HashMap<NodeId, Vec<(NodeId, TravelTimeFunction)>>,
+    pub coords: HashMap<NodeId, Coord>,
+    /// The highest speed on any edge in meters per second, for the A* heuristic
+    max_speed: f32,
+}
+
+impl TimeDependentGraph {
+    /// Travel time functions for every edge of graph from the profiles of the ways in origins.
+    /// Edges without origins are driven at ASSUMED_SPEED
+    pub fn new(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        coords: &HashMap<NodeId, Coord>,
+        origins: &EdgeOrigins,
+        profiles: &SpeedProfiles,
+    ) -> TimeDependentGraph {
+        let by_edge = origins.by_edge();
+        let mut max_speed = ASSUMED_SPEED as f32;
+        let mut td_graph = HashMap::new();
+        for (from, edges) in graph {
+            let functions = edges
+                .iter()
+                .map(|edge| {
+                    let pieces: Vec<(f32, Option<&SpeedProfile>)> =
+                        match by_edge.get(&(*from, edge.node)) {
+                            Some(origin) => origin
+                                .ways
+                                .iter()
+                                .map(|(way, meters)| (*meters, profiles.get(way)))
+                                .collect(),
+                            None => vec![(edge.cost, None)],
+                        };
+                    for (_, profile) in &pieces {
+                        for (_, speed) in profile.iter().flat_map(|p| p.iter()) {
+                            max_speed = max_speed.max(*speed);
+                        }
+                    }
+                    (edge.node, edge_function(&pieces))
+                })
+                .collect();
+            td_graph.insert(*from, functions);
+        }
+        TimeDependentGraph {
+            graph: td_graph,
+            coords: coords.clone(),
+            max_speed,
+        }
+    }
+
+    /// The route from start to end arriving first when leaving at departure, with
+    /// time-dependent Dijkstra. The cost of the route is its travel time in seconds
+    pub fn earliest_arrival(&self, start: NodeId, end: NodeId, departure: f32) -> Option<Route> {
+        self.search(start, end, departure, &|_| 0.0)
+    }
+
+    /// Like earliest_arrival, with A* using the time to reach end in a straight line at the
+    /// highest speed of any edge
+    pub fn earliest_arrival_astar(
+        &self,
+        start: NodeId,
+        end: NodeId,
+        departure: f32,
+    ) -> Option<Route> {
+        let target = *self.coords.get(&end)?;
+        let heuristic = |node: NodeId| match self.coords.get(&node) {
+            Some(coord) => coord.distance_to(target) / self.max_speed,
+            None => 0.0,
+        };
+        self.search(start, end, departure, &heuristic)
+    }
+
+    fn search(
+        &self,
+        start: NodeId,
+        end: NodeId,
+        departure: f32,
+        heuristic: &dyn Fn(NodeId) -> f32,
+    ) -> Option<Route> {
+        // Earliest known arrival and the node it was reached from
+        let mut arrivals: HashMap<NodeId, (f32, Option<NodeId>)> = HashMap::new();
+        let mut settled = HashSet::new();
+        // Ordered by arrival plus the heuristic
+        let mut heap = BinaryHeap::new();
+        arrivals.insert(start, (departure, None));
+        heap.push(Edge::new(start, departure + heuristic(start)));
+
+        while let Some(Edge { node, .. }) = heap.pop() {
+            if !settled.insert(node) {
+                continue;
+            }
+            let time = arrivals[&node].0;
+            if node == end {
+                let mut nodes = vec![end];
+                while let Some(previous) = arrivals[nodes.last().unwrap()].1 {
+                    nodes.push(previous);
+                }
+                nodes.reverse();
+                return Some(Route {
+                    nodes,
+                    cost: time - departure,
+                    visited: settled.len(),
+                });
+            }
+            for (next, function) in self.graph.get(&node).into_iter().flatten() {
+                let arrival = function.arrival(time);
+                if arrivals.get(next).is_none_or(|(best, _)| arrival < *best) {
+                    arrivals.insert(*next, (arrival, Some(node)));
+                    heap.push(Edge::new(*next, arrival + heuristic(*next)));
+                }
+            }
+        }
+        None
+    }
+}
+
+// TESTS
+#[test]
+fn travel_time_functions_are_fifo() {
+    // An hour when leaving at 8:00 but 10 minutes when leaving at 8:30, so waiting until 8:30
+    // arrives at 8:40 instead of 9:00
+    let function = TravelTimeFunction::new(vec![
+        (9.0 * 3600.0, 600.0),
+        (7.0 * 3600.0, 600.0),
+        (8.0 * 3600.0, 3600.0),
+        (8.5 * 3600.0, 600.0),
+    ]);
+    assert_eq!(2400.0, function.duration_at(8.0 * 3600.0));
+    assert_eq!(600.0, function.duration_at(7.0 * 3600.0 + DAY));
+    let mut previous = f32::MIN;
+    for minute in 0..2 * 24 * 60 {
+        let arrival = function.arrival(minute as f32 * 60.0);
+        assert!(arrival >= previous - 1e-2);
+        previous = arrival;
+    }
+
+    let wrapped = TravelTimeFunction::new(vec![(23.5 * 3600.0, 3600.0), (3600.0, 600.0)]);
+    assert!((wrapped.duration_at(0.25 * 3600.0) - 2100.0).abs() < 1e-2);
+    assert_eq!(600.0, wrapped.min_duration());
+
+    assert_eq!(Some(7.0 * 3600.0 + 1800.0), parse_time_of_day("07:30"));
+    assert_eq!(None, parse_time_of_day("24:00"));
+    let profiles = read_speed_profiles("# way,time,km/h\n42,08:00,18\n42,06:00,36\n").unwrap();
+    assert_eq!(
+        vec![(6.0 * 3600.0, 10.0), (8.0 * 3600.0, 5.0)],
+        profiles[&WayId(42)]
+    );
+    assert!(read_speed_profiles("42,08:00").is_err());
+    assert!(read_speed_profiles("42,08:00,0").is_err());
+    assert!(read_speed_profiles("42,08:00,nan").is_err());
+    assert!(read_speed_profiles("42,08:00,inf").is_err());
+}
+
+#[test]
+fn rush_hour_changes_the_route() {
+    use crate::preprocessor::graph::Graph;
+    use crate::preprocessor::preprocessor::{preprocess_grid, Preprocessor};
+    use crate::preprocessor::routing::Router;
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.record_origins = true;
+    let router = Router::new(&preprocess_grid(&mut preprocessor));
+    let origins = preprocessor.origins.unwrap();
+    let (graph, coords) = (&router.graph, &router.coords);
+    let (start, end) = (NodeId(0), NodeId(graph.len() as i64 - 1));
+
+    // Without profiles every edge is driven at ASSUMED_SPEED at any time
+    let free = TimeDependentGraph::new(graph, coords, &origins, &HashMap::new());
+    let shortest = Graph::shortest_path(graph, start, end).unwrap();
+    let night = free.earliest_arrival(start, end, 3.0 * 3600.0).unwrap();
+    assert_eq!(shortest.nodes, night.nodes);
+    assert!((night.cost - shortest.cost / ASSUMED_SPEED as f32).abs() < 0.1);
+
+    // Crawling along the ways of the shortest route at 8:00 sends the morning route elsewhere
+    let mut profiles: SpeedProfiles = HashMap::new();
+    let by_edge = origins.by_edge();
+    for pair in shortest.nodes.windows(2) {
+        for (way, _) in &by_edge[&(pair[0], pair[1])].ways {
+            let profile = vec![
+                (7.0 * 3600.0, 50.0 / 3.6),
+                (8.0 * 3600.0, 5.0 / 3.6),
+                (9.0 * 3600.0, 50.0 / 3.6),
+            ];
+            profiles.insert(*way, profile);
+        }
+    }
+    let td_graph = TimeDependentGraph::new(graph, coords, &origins, &profiles);
+    let early = td_graph.earliest_arrival(start, end, 3.0 * 3600.0).unwrap();
+    let morning = td_graph.earliest_arrival(start, end, 8.0 * 3600.0).unwrap();
+    assert!((early.cost - night.cost).abs() < 0.1);
+    assert!(morning.cost > early.cost);
+    assert_ne!(shortest.nodes, morning.nodes);
+    let astar = td_graph
+        .earliest_arrival_astar(start, end, 8.0 * 3600.0)
+        .unwrap();
+    assert!((astar.cost - morning.cost).abs() < 1e-2);
+    assert!(astar.visited <= morning.visited);
+    assert!(td_graph
+        .earliest_arrival(start, NodeId(9999), 0.0)
+        .is_none());
+}
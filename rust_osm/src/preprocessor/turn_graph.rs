@@ -0,0 +1,237 @@
+use crate::preprocessor::coord::Coord;
+use crate::preprocessor::edge::Edge;
+use crate::preprocessor::graph::Graph;
+use crate::preprocessor::preprocessor::{FullGraph, Preprocessor};
+
+use osmpbfreader::NodeId;
+use std::collections::HashMap;
+
+/*
+   Turn costs through an edge-based (turn-expanded) graph.
+   Every directed edge u -> v of the minimized graph becomes a node of its own, standing for
+   having driven along it up to v. Its coordinates are those of v, so the edges of the turn
+   graph follow the roads from junction to junction and the Router can snap onto them. There
+   is an edge from u -> v to every v -> w, costing the length of v -> w plus a penalty for the
+   change in bearing at v. Driving back to u is a U-turn with its own, larger penalty. U-turns
+   are still allowed so that dead ends are not traps.
+   Bearings are taken between the junctions at each end of an edge, since the minimized graph
+   no longer has the shape of the road in between.
+   The turn graph is an ordinary graph, so landmarks and every search work on it unchanged.
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct TurnCosts {
+    /// Cost of a 90 degree turn in meters. Other turns cost in proportion to their angle
+    pub right_angle: f32,
+    /// Cost of turning back the way the edge came
+    pub u_turn: f32,
+}
+
+impl Default for TurnCosts {
+    fn default() -> TurnCosts {
+        TurnCosts {
+            right_angle: 20.0,
+            u_turn: 500.0,
+        }
+    }
+}
+
+impl TurnCosts {
+    /// Penalty for leaving a road heading in_bearing onto one heading out_bearing, in degrees
+    pub fn penalty(&self, in_bearing: f64, out_bearing: f64) -> f32 {
+        let change = (out_bearing - in_bearing).rem_euclid(360.0);
+        let angle = change.min(360.0 - change);
+        self.right_angle * (angle / 90.0) as f32
+    }
+}
+
+impl Graph {
+    /// The turn-expanded graph of graph and the (from, to) junctions of the edge each of its
+    /// nodes stands for, indexed by node id. Nodes are numbered in the order of their from
+    /// junction and then of the edges in graph
+    #[allow(clippy::type_complexity)]
+    pub fn turn_expanded_graph(
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        coords: &HashMap<NodeId, Coord>,
+        costs: TurnCosts,
+    ) -> (HashMap<NodeId, Vec<Edge>>, Vec<(NodeId, NodeId)>) {
+        let mut junctions: Vec<&NodeId> = graph.keys().collect();
+        junctions.sort();
+        let mut segments = Vec::new();
+        let mut leaving: HashMap<NodeId, Vec<(NodeId, f32)>> = HashMap::new();
+        for from in junctions {
+            for edge in &graph[from] {
+                let id = NodeId(segments.len() as i64);
+                segments.push((*from, edge.node));
+                leaving.entry(*from).or_default().push((id, edge.cost));
+            }
+        }
+
+        let mut turn_graph = HashMap::new();
+        for (id, (from, to)) in segments.iter().enumerate() {
+            let in_bearing = coords[from].bearing_to(coords[to]);
+            let turns = leaving
+                .get(to)
+                .into_iter()
+                .flatten()
+                .map(|(next, cost)| {
+                    let (_, next_to) = segments[next.0 as usize];
+                    let penalty = match next_to == *from {
+                        true => costs.u_turn,
+                        false => costs.penalty(in_bearing, coords[to].bearing_to(coords[&next_to])),
+                    };
+                    Edge::new(*next, cost + penalty)
+                })
+                .collect();
+            turn_graph.insert(NodeId(id as i64), turns);
+        }
+        (turn_graph, segments)
+    }
+}
+
+impl Preprocessor {
+    /// The turn-expanded graph of the minimized graph from build_graph, with its own
+    /// landmarks, ready for write_graph. Each node lies at the junction its edge leads to
+    pub fn build_turn_graph(
+        &self,
+        graph: &HashMap<NodeId, Vec<Edge>>,
+        projected_points: &HashMap<NodeId, (f32, f32)>,
+        costs: TurnCosts,
+    ) -> FullGraph {
+        let (turn_graph, segments) = Graph::turn_expanded_graph(graph, &self.nodes, costs);
+        let bi_graph = Graph::get_bidirectional_graph(&turn_graph);
+        let mut landmarks = Graph::farthest_landmarks(&turn_graph, &bi_graph, 16);
+        landmarks.sort_by_key(|a| a.node_id);
+
+        let mut coords = HashMap::new();
+        let mut points = HashMap::new();
+        for (id, (_, to)) in segments.iter().enumerate() {
+            coords.insert(NodeId(id as i64), self.nodes[to]);
+            points.insert(NodeId(id as i64), projected_points[to]);
+        }
+        let metric = "distance+turns";
+        self.full_graph_from(&turn_graph, &bi_graph, landmarks, &coords, &points, metric)
+    }
+}
+
+// TESTS
+#[test]
+fn turns_cost_their_angle() {
+    // A crossroads at 1 with arms to 0 (west), 2 (east), 3 (north) and 4 (south), all two-way
+    let coords: HashMap<NodeId, Coord> = [
+        (0, 55.0, 9.99),
+        (1, 55.0, 10.0),
+        (2, 55.0, 10.01),
+        (3, 55.01, 10.0),
+        (4, 54.99, 10.0),
+    ]
+    .into_iter()
+    .map(|(id, lat, lon)| (NodeId(id), Coord { lat, lon }))
+    .collect();
+    let mut graph: HashMap<NodeId, Vec<Edge>> = HashMap::new();
+    for arm in 0..5 {
+        graph.entry(NodeId(arm)).or_default();
+    }
+    for arm in [0, 2, 3, 4] {
+        graph
+            .get_mut(&NodeId(1))
+            .unwrap()
+            .push(Edge::new(NodeId(arm), 100.0));
+        graph
+            .get_mut(&NodeId(arm))
+            .unwrap()
+            .push(Edge::new(NodeId(1), 100.0));
+    }
+    let costs = TurnCosts::default();
+    let (turn_graph, segments) = Graph::turn_expanded_graph(&graph, &coords, costs);
+    assert_eq!(8, segments.len());
+    assert_eq!(8, turn_graph.len());
+
+    let segment = |from: i64, to: i64| {
+        let index = segments
+            .iter()
+            .position(|s| *s == (NodeId(from), NodeId(to)));
+        NodeId(index.unwrap() as i64)
+    };
+    let turn = |from: NodeId, to: NodeId| {
+        let edge = turn_graph[&from].iter().find(|edge| edge.node == to);
+        edge.unwrap().cost
+    };
+    let from_west = segment(0, 1);
+    assert_eq!(4, turn_graph[&from_west].len());
+    assert!((turn(from_west, segment(1, 2)) - 100.0).abs() < 0.1);
+    assert!((turn(from_west, segment(1, 3)) - 120.0).abs() < 0.1);
+    assert!((turn(from_west, segment(1, 4)) - 120.0).abs() < 0.1);
+    assert_eq!(600.0, turn(from_west, segment(1, 0)));
+    // At a dead end the only way on is back
+    let east = segment(1, 2);
+    let edges: Vec<(NodeId, f32)> = turn_graph[&east]
+        .iter()
+        .map(|edge| (edge.node, edge.cost))
+        .collect();
+    assert_eq!(vec![(segment(2, 1), 600.0)], edges);
+
+    assert!((costs.penalty(350.0, 10.0) - 20.0 * 20.0 / 90.0).abs() < 1e-3);
+}
+
+#[test]
+fn turn_graph_without_costs_keeps_distances() {
+    let full_graph = crate::preprocessor::preprocessor::grid_full_graph();
+    let graph = full_graph.graph();
+    let free = TurnCosts {
+        right_angle: 0.0,
+        u_turn: 0.0,
+    };
+    let (turn_graph, segments) = Graph::turn_expanded_graph(&graph, &full_graph.coords(), free);
+    let edge_count: usize = graph.values().map(Vec::len).sum();
+    assert_eq!(edge_count, segments.len());
+
+    // Arriving at a junction by the best edge into it costs as much as the node-based route
+    for (id, (_, from)) in segments.iter().enumerate() {
+        let node_distances = Graph::dijkstra_all(&graph, *from);
+        let turn_distances = Graph::dijkstra_all(&turn_graph, NodeId(id as i64));
+        for junction in graph.keys() {
+            if junction == from {
+                continue;
+            }
+            let best = segments
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, to))| to == junction)
+                .map(|(index, _)| turn_distances[index])
+                .fold(f32::MAX, f32::min);
+            let expected = node_distances[junction.0 as usize];
+            assert!((best - expected).abs() < 1e-2 || best == expected);
+        }
+    }
+}
+
+#[test]
+fn turn_graph_routes_with_landmarks() {
+    use crate::preprocessor::preprocessor::preprocess_grid;
+    use crate::preprocessor::routing::{Algorithm, Router};
+    let mut preprocessor = Preprocessor::new();
+    let grid = preprocess_grid(&mut preprocessor);
+    let costs = TurnCosts::default();
+    let full_graph = preprocessor.build_turn_graph(&grid.graph(), &grid.projected_points(), costs);
+    assert_eq!("distance+turns", full_graph.header.metric);
+    assert!(!full_graph.landmarks.is_empty());
+
+    let router = Router::new(&full_graph);
+    let last = NodeId(full_graph.nodes.len() as i64 - 1);
+    let dijkstra = router.route_between_nodes(NodeId(0), last, Algorithm::Dijkstra);
+    let landmarks = router.route_between_nodes(NodeId(0), last, Algorithm::Landmarks);
+    let (dijkstra, landmarks) = (dijkstra.unwrap(), landmarks.unwrap());
+    assert!((dijkstra.cost - landmarks.cost).abs() < 1e-2);
+    let snapped = router.route_between_coords(
+        Coord {
+            lat: 55.0004,
+            lon: 10.0001,
+        },
+        Coord {
+            lat: 55.006,
+            lon: 10.0089,
+        },
+        Algorithm::Landmarks,
+    );
+    assert!(snapped.is_some());
+}
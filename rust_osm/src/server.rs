@@ -0,0 +1,360 @@
+use crate::preprocessor::graph_file::GraphHeader;
+use crate::preprocessor::osrm::{parse_coordinates, Geometries};
+use crate::preprocessor::overlay::Overlay;
+use crate::preprocessor::preprocessor::FullGraph;
+use crate::preprocessor::routing::{Algorithm, Route, Router};
+use crate::Coord;
+
+use osmpbfreader::NodeId;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/*
+   HTTP JSON interface to a loaded graph, started with `rust_osm serve <graph>`.
+
+   GET /metadata                          the graph header
+   GET /nearest?lat=..&lon=..             the closest node
+   GET /route?from=lat,lon&to=lat,lon     route between coordinates, snapped onto the roads
+   GET /route?from_node=..&to_node=..     route between node ids
+   Routes take an optional algorithm=dijkstra|astar|landmarks, dijkstra by default, and answer
+   with the distance in meters, the number of nodes visited, the node ids passed and the path
+   as [lon, lat] coordinates like GeoJSON. Errors are {"error": ...} with a 4xx status.
+
+   GET /route/v1/<profile>/<lon>,<lat>;<lon>,<lat>...   answers like OSRM, see osrm.rs
+   The profile is ignored, and geometries=polyline|polyline6|geojson and overview=false
+   are supported.
+*/
+pub struct Server {
+    router: Router,
+    header: GraphHeader,
+}
+
+type Response = (u16, Value);
+
+fn error(status: u16, message: impl std::fmt::Display) -> Response {
+    (status, json!({ "error": message.to_string() }))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn parse<T: std::str::FromStr>(query: &HashMap<String, String>, name: &str) -> Result<T, Response> {
+    let value = query
+        .get(name)
+        .ok_or_else(|| error(400, format!("missing parameter {}", name)))?;
+    value
+        .trim()
+        .parse()
+        .map_err(|_| error(400, format!("invalid {}: {}", name, value)))
+}
+
+fn parse_coord(query: &HashMap<String, String>, name: &str) -> Result<Coord, Response> {
+    let value: String = parse(query, name)?;
+    let invalid = || error(400, format!("{} must be lat,lon", name));
+    let (lat, lon) = value.split_once(',').ok_or_else(invalid)?;
+    match (lat.trim().parse::<f64>(), lon.trim().parse::<f64>()) {
+        (Ok(lat), Ok(lon)) if lat.is_finite() && lon.is_finite() => Ok(Coord { lat, lon }),
+        _ => Err(invalid()),
+    }
+}
+
+impl Server {
+    pub fn new(full_graph: &FullGraph) -> Server {
+        Server {
+            router: Router::new(full_graph),
+            header: full_graph.header.clone(),
+        }
+    }
+
+    /// Answers routes with the costs of the overlay, see Router::set_overlay
+    pub fn set_overlay(&mut self, overlay: Option<Overlay>) {
+        self.router.set_overlay(overlay);
+    }
+
+    /// Answers a GET request for a url like /route?from=55.6,12.5&to=55.7,12.6
+    pub fn handle(&self, url: &str) -> Response {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let query: HashMap<String, String> = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (percent_decode(key), percent_decode(value)))
+            .collect();
+        let result = match path {
+            "/metadata" => Ok((200, json!(self.header))),
+            "/nearest" => self.nearest(&query),
+            "/route" => self.route(&query),
+            _ if path.starts_with("/route/v1/") => Ok(self.osrm_route(path, &query)),
+            _ => Err(error(404, format!("unknown endpoint {}", path))),
+        };
+        result.unwrap_or_else(|response| response)
+    }
+
+    fn nearest(&self, query: &HashMap<String, String>) -> Result<Response, Response> {
+        let coord = Coord {
+            lat: parse(query, "lat")?,
+            lon: parse(query, "lon")?,
+        };
+        if !coord.lat.is_finite() || !coord.lon.is_finite() {
+            return Err(error(400, "lat and lon must be finite"));
+        }
+        let node = self
+            .router
+            .nearest_node(coord)
+            .ok_or_else(|| error(404, "the graph has no nodes"))?;
+        let position = self.router.coords[&node];
+        Ok((
+            200,
+            json!({
+                "node": node.0,
+                "lat": position.lat,
+                "lon": position.lon,
+                "distance": coord.distance_to(position),
+            }),
+        ))
+    }
+
+    fn route(&self, query: &HashMap<String, String>) -> Result<Response, Response> {
+        let algorithm = match query.get("algorithm") {
+            Some(name) => name.parse().map_err(|e| error(400, e))?,
+            None => Algorithm::Dijkstra,
+        };
+        let no_route = || error(404, "no route between the given points");
+        let (route, coords): (Route, Vec<Coord>) = if query.contains_key("from_node") {
+            let start = NodeId(parse(query, "from_node")?);
+            let end = NodeId(parse(query, "to_node")?);
+            for node in [start, end] {
+                if !self.router.graph.contains_key(&node) {
+                    return Err(error(404, format!("unknown node {}", node.0)));
+                }
+            }
+            let route = self
+                .router
+                .route_between_nodes(start, end, algorithm)
+                .ok_or_else(no_route)?;
+            let coords = route.nodes.iter().map(|n| self.router.coords[n]).collect();
+            (route, coords)
+        } else {
+            let start = parse_coord(query, "from")?;
+            let end = parse_coord(query, "to")?;
+            let snapped = self
+                .router
+                .route_between_coords(start, end, algorithm)
+                .ok_or_else(no_route)?;
+            (snapped.route, snapped.coords)
+        };
+        let coordinates: Vec<[f64; 2]> = coords.iter().map(|c| [c.lon, c.lat]).collect();
+        Ok((
+            200,
+            json!({
+                "distance": route.cost,
+                "visited": route.visited,
+                "nodes": route.nodes.iter().map(|node| node.0).collect::<Vec<_>>(),
+                "coordinates": coordinates,
+            }),
+        ))
+    }
+
+    fn osrm_route(&self, path: &str, query: &HashMap<String, String>) -> Response {
+        let invalid = |message: String| (400, json!({ "code": "InvalidUrl", "message": message }));
+        // The profile is the first segment after /route/v1/, the coordinates the second
+        let Some((_, coordinates)) = path["/route/v1/".len()..].split_once('/') else {
+            return invalid(format!("URL string malformed: {}", path));
+        };
+        let waypoints = match parse_coordinates(&percent_decode(coordinates)) {
+            Ok(waypoints) => waypoints,
+            Err(e) => return invalid(e),
+        };
+        let geometries = match query.get("geometries").map(|g| g.parse::<Geometries>()) {
+            None => Geometries::Polyline,
+            Some(Ok(geometries)) => geometries,
+            Some(Err(e)) => return (400, json!({ "code": "InvalidOptions", "message": e })),
+        };
+        let algorithm = match query.get("algorithm").map(|a| a.parse::<Algorithm>()) {
+            None => Algorithm::Dijkstra,
+            Some(Ok(algorithm)) => algorithm,
+            Some(Err(e)) => return (400, json!({ "code": "InvalidOptions", "message": e })),
+        };
+        let mut response = self.router.osrm_route(&waypoints, algorithm, geometries);
+        if query.get("overview").map(String::as_str) == Some("false") {
+            if let Some(route) = response["routes"].get_mut(0) {
+                route.as_object_mut().unwrap().remove("geometry");
+            }
+        }
+        // Like OSRM, anything but "Ok" is a 400
+        let status = if response["code"] == "Ok" { 200 } else { 400 };
+        (status, response)
+    }
+
+    /// Serves requests on the address, like "0.0.0.0:8080", until the process is stopped
+    pub fn serve(&self, address: &str) -> std::io::Result<()> {
+        let server = tiny_http::Server::http(address).map_err(std::io::Error::other)?;
+        println!("Serving on http://{}", server.server_addr());
+        self.serve_on(&server);
+        Ok(())
+    }
+
+    /// Answers requests until the server is unblocked. A request that panics gets a 500
+    /// instead of stopping the server
+    fn serve_on(&self, server: &tiny_http::Server) {
+        let content_type =
+            tiny_http::Header::from_bytes("Content-Type", "application/json").unwrap();
+        for request in server.incoming_requests() {
+            let (status, body) = match request.method() {
+                tiny_http::Method::Get => {
+                    let handle = std::panic::AssertUnwindSafe(|| self.handle(request.url()));
+                    std::panic::catch_unwind(handle)
+                        .unwrap_or_else(|_| error(500, "the request could not be answered"))
+                }
+                _ => error(405, "only GET is supported"),
+            };
+            let response = tiny_http::Response::from_string(body.to_string())
+                .with_status_code(status)
+                .with_header(content_type.clone());
+            if let Err(e) = request.respond(response) {
+                println!("Could not answer request: {}", e);
+            }
+        }
+    }
+}
+
+// TESTS
+#[cfg(test)]
+fn grid_server() -> Server {
+    Server::new(&crate::preprocessor::preprocessor::grid_full_graph())
+}
+
+#[cfg(test)]
+fn get(address: std::net::SocketAddr, url: &str) -> (u16, Value) {
+    use std::io::{Read, Write};
+    let mut stream = std::net::TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        url
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[test]
+fn answers_over_http() {
+    let server = grid_server();
+    let http = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let address = http.server_addr().to_ip().unwrap();
+    std::thread::scope(|scope| {
+        let http = std::sync::Arc::new(http);
+        let serving = http.clone();
+        let server = &server;
+        // Stops when the test unblocks the server below
+        scope.spawn(move || server.serve_on(&serving));
+
+        let (status, metadata) = get(address, "/metadata");
+        assert_eq!(200, status);
+        assert_eq!("car", metadata["profile"]);
+
+        let (status, route) = get(
+            address,
+            "/route?from=55.0004%2C10.0001&to=55.006,10.0089&algorithm=astar",
+        );
+        assert_eq!(200, status);
+        assert!(route["distance"].as_f64().unwrap() > 0.0);
+        assert!(route["visited"].as_u64().unwrap() > 0);
+        let coordinates = route["coordinates"].as_array().unwrap();
+        assert_eq!(
+            route["nodes"].as_array().unwrap().len() + 2,
+            coordinates.len()
+        );
+
+        let (status, _) = get(address, "/nowhere");
+        assert_eq!(404, status);
+        http.unblock();
+    });
+}
+
+#[test]
+fn routes_between_nodes_and_finds_nearest() {
+    let server = grid_server();
+    let (status, nearest) = server.handle("/nearest?lat=55.002&lon=10.003");
+    assert_eq!(200, status);
+    let node = nearest["node"].as_i64().unwrap();
+    // Intersection 111 of the grid survives minimization as a junction
+    assert!((55.002 - nearest["lat"].as_f64().unwrap()).abs() < 1e-6);
+    assert!((10.003 - nearest["lon"].as_f64().unwrap()).abs() < 1e-6);
+
+    let (status, route) = server.handle(&format!(
+        "/route?from_node={}&to_node={}&algorithm=landmarks",
+        node, node
+    ));
+    assert_eq!(200, status);
+    assert_eq!(0.0, route["distance"]);
+    assert_eq!(json!([node]), route["nodes"]);
+
+    let (status, route) = server.handle("/route?from_node=0&to_node=1");
+    assert_eq!(200, status);
+    let dijkstra = route["distance"].as_f64().unwrap();
+    let (_, route) = server.handle("/route?from_node=0&to_node=1&algorithm=landmarks");
+    assert!((dijkstra - route["distance"].as_f64().unwrap()).abs() < 1e-2);
+}
+
+#[test]
+fn answers_osrm_route_requests() {
+    use crate::preprocessor::osrm::decode_polyline;
+    let server = grid_server();
+    let (status, response) =
+        server.handle("/route/v1/driving/10.0001,55.0004;10.0089,55.006?geometries=polyline6");
+    assert_eq!(200, status);
+    assert_eq!("Ok", response["code"]);
+    let geometry = response["routes"][0]["geometry"].as_str().unwrap();
+    assert!(decode_polyline(geometry, 6).unwrap().len() >= 2);
+
+    let (status, response) =
+        server.handle("/route/v1/driving/10.0001,55.0004%3B10.0089,55.006?overview=false");
+    assert_eq!(200, status);
+    assert!(response["routes"][0].get("geometry").is_none());
+
+    let (status, response) = server.handle("/route/v1/driving/10.0001,55.0004");
+    assert_eq!(400, status);
+    assert_eq!("InvalidQuery", response["code"]);
+    assert_eq!(400, server.handle("/route/v1/driving").0);
+}
+
+#[test]
+fn rejects_bad_requests() {
+    let server = grid_server();
+    assert_eq!(400, server.handle("/nearest?lat=55").0);
+    assert_eq!(400, server.handle("/route?from=55&to=55,10").0);
+    assert_eq!(
+        400,
+        server.handle("/route?from=55,10&to=55,10&algorithm=bfs").0
+    );
+    assert_eq!(404, server.handle("/route?from_node=0&to_node=9999").0);
+    assert_eq!(400, server.handle("/nearest?lat=NaN&lon=10").0);
+    assert_eq!(400, server.handle("/route?from=55,inf&to=55,10").0);
+    assert_eq!(400, server.handle("/route?from=55,10&to=NaN,10").0);
+    let (status, body) = server.handle("/route?from_node=x&to_node=1");
+    assert_eq!(400, status);
+    assert_eq!("invalid from_node: x", body["error"]);
+}
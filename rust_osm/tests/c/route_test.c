@@ -0,0 +1,54 @@
+/* Routes on the graph given as the first argument through the C API, see tests/c_api.rs */
+#include <math.h>
+#include <stdio.h>
+#include <string.h>
+
+#include "rust_osm.h"
+
+#define CHECK(condition)                                                  \
+    if (!(condition)) {                                                   \
+        fprintf(stderr, "%s:%d: %s failed\n", __FILE__, __LINE__, #condition); \
+        return 1;                                                         \
+    }
+
+int main(int argc, char **argv) {
+    CHECK(argc == 2);
+    CHECK(osm_graph_load("/nonexistent/map.graph") == NULL);
+    CHECK(strstr(osm_last_error(), "/nonexistent/map.graph") != NULL);
+
+    OsmGraph *graph = osm_graph_load(argv[1]);
+    CHECK(graph != NULL);
+    CHECK(osm_graph_node_count(graph) > 0);
+
+    int64_t start = osm_nearest_node(graph, 55.0, 10.0);
+    int64_t end = osm_nearest_node(graph, 55.006, 10.009);
+    CHECK(start >= 0 && end >= 0 && start != end);
+
+    float distances[3];
+    int algorithms[3] = {OSM_DIJKSTRA, OSM_ASTAR, OSM_LANDMARKS};
+    for (int i = 0; i < 3; i++) {
+        OsmRoute *route = osm_route_between_nodes(graph, start, end, algorithms[i]);
+        CHECK(route != NULL);
+        CHECK(route->nodes[0] == start && route->nodes[route->node_count - 1] == end);
+        CHECK(route->coord_count == route->node_count);
+        CHECK(route->visited > 0);
+        distances[i] = route->distance;
+        osm_route_free(route);
+    }
+    CHECK(fabsf(distances[0] - distances[1]) < 0.01f);
+    CHECK(fabsf(distances[0] - distances[2]) < 0.01f);
+
+    OsmRoute *route = osm_route_between_coords(graph, 55.0004, 10.0001, 55.006, 10.0089,
+                                               OSM_ASTAR);
+    CHECK(route != NULL);
+    CHECK(route->coord_count == route->node_count + 2);
+    CHECK(fabs(route->coords[0] - 55.0004) < 0.001);
+    osm_route_free(route);
+
+    CHECK(osm_route_between_nodes(graph, start, end, 42) == NULL);
+    CHECK(strcmp(osm_last_error(), "unknown algorithm 42") == 0);
+
+    osm_graph_free(graph);
+    printf("ok\n");
+    return 0;
+}
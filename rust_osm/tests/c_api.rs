@@ -0,0 +1,51 @@
+use rust_osm::preprocessor::preprocessor::Preprocessor;
+
+use std::path::Path;
+use std::process::Command;
+
+// Compiles tests/c/route_test.c against the cdylib cargo builds next to the test binary
+// and runs it on the grid test graph
+#[cfg(target_os = "linux")]
+#[test]
+fn c_program_routes_through_the_library() {
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.get_roads_and_nodes("src/test_data/grid.osm");
+    let (graph, bi_graph, landmarks) = preprocessor.build_graph();
+    let projected_points = preprocessor.project_nodes_to_2d();
+    let full_graph = preprocessor.build_full_graph(&graph, &bi_graph, landmarks, &projected_points);
+    let dir = tempfile::tempdir().unwrap();
+    let graph_path = dir.path().join("grid.graph");
+    full_graph
+        .write(&mut std::fs::File::create(&graph_path).unwrap())
+        .unwrap();
+
+    let exe = std::env::current_exe().unwrap();
+    let lib_dir = exe.parent().unwrap();
+    assert!(
+        lib_dir.join("librust_osm.so").exists(),
+        "librust_osm.so was not built in {}",
+        lib_dir.display()
+    );
+    let program = dir.path().join("route_test");
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let status = Command::new(std::env::var("CC").unwrap_or("cc".to_owned()))
+        .arg(manifest_dir.join("tests/c/route_test.c"))
+        .arg("-I")
+        .arg(manifest_dir.join("include"))
+        .arg("-L")
+        .arg(lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .args(["-lrust_osm", "-lm", "-Wall", "-Werror", "-o"])
+        .arg(&program)
+        .status()
+        .expect("a C compiler is needed for this test");
+    assert!(status.success());
+
+    let output = Command::new(&program).arg(&graph_path).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!("ok\n", String::from_utf8_lossy(&output.stdout));
+}